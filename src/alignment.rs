@@ -0,0 +1,101 @@
+extern crate rayon;
+
+use std::f32;
+use rayon::prelude::*;
+
+
+// Emit/Shift layout of the last axis of the transition log-probs.
+const EMIT: usize = 0;
+const SHIFT: usize = 1;
+const TRANSITION_SIZE: usize = 2;
+
+pub fn viterbi_alignment(h: &[f32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, max_u: usize, t_history: &mut [i32], duration: &mut [i32], log_probs: &mut [f32]) {
+    assert_eq!(h.len(), batch_size * max_u * max_t * TRANSITION_SIZE);
+    assert_eq!(input_length.len(), batch_size);
+    assert_eq!(output_length.len(), batch_size);
+    assert_eq!(t_history.len(), batch_size * max_u);
+    assert_eq!(duration.len(), batch_size * max_t);
+    assert_eq!(log_probs.len(), batch_size);
+    // (B, U, T, 2)
+    h.par_chunks(max_u * max_t * TRANSITION_SIZE)
+        .zip(input_length.par_chunks(1))
+        .zip(output_length.par_chunks(1))
+        // (B, U)
+        .zip(t_history.par_chunks_mut(max_u))
+        // (B, T)
+        .zip(duration.par_chunks_mut(max_t))
+        .zip(log_probs.par_chunks_mut(1))
+        .for_each(|(((((h, input_length), output_length), t_history), duration), log_prob)| {
+            let input_length = input_length[0] as usize;
+            let output_length = output_length[0] as usize;
+            // Padding region is filled with -1 for t and 0 for durations.
+            t_history.iter_mut().for_each(|v| *v = -1);
+            duration.iter_mut().for_each(|v| *v = 0);
+            // Items without any alignment are reported with -inf instead.
+            let alignment = if output_length <= max_u {
+                viterbi_alignment_kernel(h, input_length, output_length, max_t)
+            } else {
+                None
+            };
+            match alignment {
+                Some((ts, ds, score)) => {
+                    t_history[..output_length].copy_from_slice(ts.as_slice());
+                    duration[..input_length].copy_from_slice(ds.as_slice());
+                    log_prob[0] = score;
+                }
+                None => log_prob[0] = f32::NEG_INFINITY,
+            }
+        });
+}
+
+// Best monotonic alignment over the (U, T) lattice given (U, T, 2) transition log-probs.
+// Frame u is aligned to t_u with t_0 = 0. As in SsntTtsCpu::beam_search_kernel_internal, the first Emit at
+// t = input_length - 1 finishes, so the last input position is aligned to the last frame only.
+// Returns None if no such alignment exists.
+pub fn viterbi_alignment_kernel(h: &[f32], input_length: usize, output_length: usize, max_t: usize) -> Option<(Vec<i32>, Vec<i32>, f32)> {
+    if input_length == 0 || input_length > max_t || output_length == 0 || h.len() < output_length * max_t * TRANSITION_SIZE {
+        return None;
+    }
+    let last_t = input_length - 1;
+    let log_prob_at = |u: usize, t: usize, transition: usize| -> f32 {
+        h[(u * max_t + t) * TRANSITION_SIZE + transition]
+    };
+
+    // (U, T)
+    let mut score: Vec<f32> = vec![f32::NEG_INFINITY; output_length * input_length];
+    let mut shifted: Vec<bool> = vec![false; output_length * input_length];
+    score[0] = 0.0;
+    for u in 1..output_length {
+        for t in 0..input_length {
+            // Emit at the last position finishes, so it can only be entered by Shift.
+            let emit = if t < last_t {
+                score[(u - 1) * input_length + t] + log_prob_at(u - 1, t, EMIT)
+            } else {
+                f32::NEG_INFINITY
+            };
+            let shift = if t > 0 {
+                score[(u - 1) * input_length + t - 1] + log_prob_at(u - 1, t - 1, SHIFT)
+            } else {
+                f32::NEG_INFINITY
+            };
+            score[u * input_length + t] = emit.max(shift);
+            shifted[u * input_length + t] = shift > emit;
+        }
+    }
+    let final_score = score[(output_length - 1) * input_length + last_t] + log_prob_at(output_length - 1, last_t, EMIT);
+    if final_score == f32::NEG_INFINITY {
+        return None;
+    }
+
+    let mut t_history: Vec<i32> = vec![0; output_length];
+    let mut duration: Vec<i32> = vec![0; input_length];
+    let mut t = last_t;
+    for u in (0..output_length).rev() {
+        t_history[u] = t as i32;
+        duration[t] += 1;
+        if u > 0 && shifted[u * input_length + t] {
+            t -= 1;
+        }
+    }
+    Some((t_history, duration, final_score))
+}
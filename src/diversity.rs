@@ -0,0 +1,91 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use crate::pruning::PruningPolicy;
+
+
+// Diverse beam search: beams are split into groups, and each group is penalized for repeating predictions chosen by earlier groups at the same step.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct DiversityPolicy {
+    pub num_groups: usize,
+    // Score subtracted per earlier selection of the same prediction.
+    pub penalty: f32,
+}
+
+impl Default for DiversityPolicy {
+    fn default() -> DiversityPolicy {
+        DiversityPolicy {
+            num_groups: 1,
+            penalty: 0.0,
+        }
+    }
+}
+
+impl DiversityPolicy {
+    pub fn new(num_groups: usize, penalty: f32) -> DiversityPolicy {
+        assert!(num_groups > 0, "num_groups must be positive");
+        DiversityPolicy {
+            num_groups,
+            penalty,
+        }
+    }
+
+    // num_groups as passed through the C interface. Returns None unless it is between 1 and beam_width.
+    pub fn from_attr(num_groups: i32, beam_width: i32, penalty: f32) -> Option<DiversityPolicy> {
+        if num_groups >= 1 && num_groups <= beam_width {
+            Some(DiversityPolicy::new(num_groups as usize, penalty))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.num_groups > 1
+    }
+
+    // Hypotheses from beams of different groups are never duplicates of each other, since each group runs its own search.
+    pub fn same_group(&self, w: usize, other: usize, beam_width: usize) -> bool {
+        self.group_of(w, beam_width) == self.group_of(other, beam_width)
+    }
+
+    // Beams are assigned to groups in contiguous blocks.
+    fn group_of(&self, w: usize, beam_width: usize) -> usize {
+        (w * self.num_groups / beam_width).min(self.num_groups - 1)
+    }
+
+    fn group_range(&self, g: usize, beam_width: usize) -> (usize, usize) {
+        (g * beam_width / self.num_groups, (g + 1) * beam_width / self.num_groups)
+    }
+
+    // Selects max_beam_width results group by group from results sorted by score.
+    // Each group extends its own parent beams, is pruned on its own, and fills its slots with padding copies when it has too few candidates.
+    pub fn select<T, FB, FP, FS, FD>(&self, results: Vec<T>, beam_width: usize, max_beam_width: usize, pruning: &PruningPolicy, parent_branch: FB, prediction: FP, score: FS, padding: FD) -> Vec<T>
+        where T: Clone, FB: Fn(&T) -> usize, FP: Fn(&T) -> i32, FS: Fn(&T) -> f32, FD: Fn(&T) -> T {
+        assert!(self.num_groups <= max_beam_width, "num_groups: {} exceeds beam width: {}", self.num_groups, max_beam_width);
+        let mut counts: HashMap<i32, usize> = HashMap::new();
+        let mut selected: Vec<T> = Vec::with_capacity(max_beam_width);
+        for g in 0..self.num_groups {
+            let mut candidates: Vec<(f32, &T)> = results.iter()
+                .filter(|result| self.group_of(parent_branch(result), beam_width) == g)
+                .map(|result| {
+                    let count = counts.get(&prediction(result)).cloned().unwrap_or(0);
+                    (score(result) - self.penalty * count as f32, result)
+                }).collect();
+            candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal).reverse());
+            pruning.prune(&mut candidates, |c| c.0);
+            let (start, end) = self.group_range(g, max_beam_width);
+            candidates.truncate(end - start);
+            candidates.iter().for_each(|(_, result)| {
+                *counts.entry(prediction(result)).or_insert(0) += 1;
+            });
+            // A group without live parents leaves its slots to the padding of the caller.
+            let n_candidates = candidates.len();
+            if n_candidates > 0 {
+                for i in 0..(end - start) {
+                    let candidate = candidates[i % n_candidates].1;
+                    selected.push(if i < n_candidates { candidate.clone() } else { padding(candidate) });
+                }
+            }
+        }
+        selected
+    }
+}
@@ -0,0 +1,195 @@
+extern crate rayon;
+
+use std::f32;
+use rayon::prelude::*;
+use crate::util::log_add_exp;
+
+
+// Emit/Shift layout of the last axis of the transition log-probs.
+const EMIT: usize = 0;
+const SHIFT: usize = 1;
+const TRANSITION_SIZE: usize = 2;
+
+pub struct ForwardBackwardTable {
+    // (U, T)
+    pub alpha: Vec<f32>,
+    // (U, T)
+    pub beta: Vec<f32>,
+    pub log_likelihood: f32,
+    pub input_length: usize,
+    pub output_length: usize,
+}
+
+pub fn ssnt_loss(h: &[f32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, max_u: usize, loss: &mut [f32], grad: &mut [f32]) {
+    assert_eq!(h.len(), batch_size * max_u * max_t * TRANSITION_SIZE);
+    assert_eq!(input_length.len(), batch_size);
+    assert_eq!(output_length.len(), batch_size);
+    assert_eq!(loss.len(), batch_size);
+    assert_eq!(grad.len(), h.len());
+    // (B, U, T, 2)
+    h.par_chunks(max_u * max_t * TRANSITION_SIZE)
+        .zip(input_length.par_chunks(1))
+        .zip(output_length.par_chunks(1))
+        .zip(loss.par_chunks_mut(1))
+        // (B, U, T, 2)
+        .zip(grad.par_chunks_mut(max_u * max_t * TRANSITION_SIZE))
+        .for_each(|((((h, input_length), output_length), loss), grad)| {
+            // Items with lengths outside the lattice have no alignment, like those that cannot be aligned.
+            if !is_valid_length(input_length[0], output_length[0], max_t, max_u) {
+                loss[0] = f32::INFINITY;
+                grad.iter_mut().for_each(|v| *v = 0.0);
+                return;
+            }
+            let table = forward_backward_kernel(h, input_length[0] as usize, output_length[0] as usize, max_t);
+            loss[0] = -table.log_likelihood;
+            grad.copy_from_slice(ssnt_loss_grad_kernel(h, &table, max_t).as_slice());
+        });
+}
+
+pub fn alignment_posterior(h: &[f32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, max_u: usize, occupancy: &mut [f32], expected_duration: &mut [f32]) {
+    assert_eq!(h.len(), batch_size * max_u * max_t * TRANSITION_SIZE);
+    assert_eq!(input_length.len(), batch_size);
+    assert_eq!(output_length.len(), batch_size);
+    assert_eq!(occupancy.len(), batch_size * max_u * max_t);
+    assert_eq!(expected_duration.len(), batch_size * max_t);
+    // (B, U, T, 2)
+    h.par_chunks(max_u * max_t * TRANSITION_SIZE)
+        .zip(input_length.par_chunks(1))
+        .zip(output_length.par_chunks(1))
+        // (B, U, T)
+        .zip(occupancy.par_chunks_mut(max_u * max_t))
+        // (B, T)
+        .zip(expected_duration.par_chunks_mut(max_t))
+        .for_each(|((((h, input_length), output_length), occupancy), expected_duration)| {
+            // Padding region, and every item with lengths outside the lattice, is filled with zeros.
+            occupancy.iter_mut().for_each(|v| *v = 0.0);
+            expected_duration.iter_mut().for_each(|v| *v = 0.0);
+            if !is_valid_length(input_length[0], output_length[0], max_t, max_u) {
+                return;
+            }
+            let table = forward_backward_kernel(h, input_length[0] as usize, output_length[0] as usize, max_t);
+            let (gamma, duration) = alignment_posterior_kernel(&table);
+            gamma.chunks(table.input_length)
+                .zip(occupancy.chunks_mut(max_t))
+                .for_each(|(gamma, occupancy)| {
+                    occupancy[..table.input_length].copy_from_slice(gamma);
+                });
+            expected_duration[..table.input_length].copy_from_slice(duration.as_slice());
+        });
+}
+
+fn is_valid_length(input_length: i32, output_length: i32, max_t: usize, max_u: usize) -> bool {
+    input_length > 0 && input_length as usize <= max_t && output_length > 0 && output_length as usize <= max_u
+}
+
+// Forward and backward variables over the (U, T) lattice with the same transitions as SsntTtsCpu.
+// alpha(u, t) is the log-prob of reaching input position t at frame u, and beta(u, t) is the log-prob of
+// completing the sequence from there, including the transition taken at (u, t).
+// As in SsntTtsCpu::beam_search_kernel_internal, the first Emit at t = input_length - 1 finishes, so the last input
+// position is entered by Shift at the last frame only, and Shift is prohibited at that position.
+pub fn forward_backward_kernel(h: &[f32], input_length: usize, output_length: usize, max_t: usize) -> ForwardBackwardTable {
+    assert!(input_length > 0 && input_length <= max_t);
+    assert!(output_length > 0);
+    let log_prob_at = |u: usize, t: usize, transition: usize| -> f32 {
+        h[(u * max_t + t) * TRANSITION_SIZE + transition]
+    };
+    let last_t = input_length - 1;
+    let last_u = output_length - 1;
+
+    let mut alpha: Vec<f32> = vec![f32::NEG_INFINITY; output_length * input_length];
+    alpha[0] = 0.0;
+    for u in 1..output_length {
+        for t in 0..input_length {
+            let emit = if t < last_t {
+                alpha[(u - 1) * input_length + t] + log_prob_at(u - 1, t, EMIT)
+            } else {
+                f32::NEG_INFINITY
+            };
+            let shift = if t > 0 {
+                alpha[(u - 1) * input_length + t - 1] + log_prob_at(u - 1, t - 1, SHIFT)
+            } else {
+                f32::NEG_INFINITY
+            };
+            alpha[u * input_length + t] = log_add_exp(emit, shift);
+        }
+    }
+
+    let mut beta: Vec<f32> = vec![f32::NEG_INFINITY; output_length * input_length];
+    beta[last_u * input_length + last_t] = log_prob_at(last_u, last_t, EMIT);
+    for u in (0..last_u).rev() {
+        for t in 0..input_length {
+            let emit = if t < last_t {
+                log_prob_at(u, t, EMIT) + beta[(u + 1) * input_length + t]
+            } else {
+                f32::NEG_INFINITY
+            };
+            let shift = if t < last_t {
+                log_prob_at(u, t, SHIFT) + beta[(u + 1) * input_length + t + 1]
+            } else {
+                f32::NEG_INFINITY
+            };
+            beta[u * input_length + t] = log_add_exp(emit, shift);
+        }
+    }
+
+    let log_likelihood = beta[0];
+    ForwardBackwardTable {
+        alpha,
+        beta,
+        log_likelihood,
+        input_length,
+        output_length,
+    }
+}
+
+// Gradient of the negative log-likelihood w.r.t. the (U, T, 2) log-probs, i.e. minus the transition posteriors.
+// Entries outside the valid lattice, or of an utterance without any alignment, are zero.
+pub fn ssnt_loss_grad_kernel(h: &[f32], table: &ForwardBackwardTable, max_t: usize) -> Vec<f32> {
+    let mut grad: Vec<f32> = vec![0.0; h.len()];
+    if !table.log_likelihood.is_finite() {
+        return grad;
+    }
+    let input_length = table.input_length;
+    let last_t = input_length - 1;
+    let last_u = table.output_length - 1;
+    for u in 0..table.output_length {
+        for t in 0..input_length {
+            let alpha = table.alpha[u * input_length + t];
+            if alpha == f32::NEG_INFINITY {
+                continue;
+            }
+            let index = (u * max_t + t) * TRANSITION_SIZE;
+            let next_emit = if u < last_u && t < last_t {
+                table.beta[(u + 1) * input_length + t]
+            } else if u == last_u && t == last_t {
+                0.0
+            } else {
+                f32::NEG_INFINITY
+            };
+            grad[index + EMIT] = -(alpha + h[index + EMIT] + next_emit - table.log_likelihood).exp();
+            if u < last_u && t < last_t {
+                let next_shift = table.beta[(u + 1) * input_length + t + 1];
+                grad[index + SHIFT] = -(alpha + h[index + SHIFT] + next_shift - table.log_likelihood).exp();
+            }
+        }
+    }
+    grad
+}
+
+// Occupancy gamma(u, t), the posterior of frame u being aligned to input position t, as a (U, T) matrix,
+// and the expected number of frames per input position. Both are zero if no alignment exists.
+pub fn alignment_posterior_kernel(table: &ForwardBackwardTable) -> (Vec<f32>, Vec<f32>) {
+    let input_length = table.input_length;
+    let mut expected_duration: Vec<f32> = vec![0.0; input_length];
+    if !table.log_likelihood.is_finite() {
+        return (vec![0.0; table.output_length * input_length], expected_duration);
+    }
+    let gamma: Vec<f32> = table.alpha.iter()
+        .zip(table.beta.iter())
+        .map(|(alpha, beta)| (alpha + beta - table.log_likelihood).exp())
+        .collect();
+    gamma.chunks(input_length).for_each(|row| {
+        row.iter().zip(expected_duration.iter_mut()).for_each(|(g, d)| *d += g);
+    });
+    (gamma, expected_duration)
+}
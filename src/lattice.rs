@@ -0,0 +1,167 @@
+use std::cmp::Ordering;
+use std::f32;
+
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LatticeNode {
+    // Node the hypothesis was expanded from. None for hypotheses of the first step.
+    pub parent: Option<usize>,
+    pub step: usize,
+    pub prediction: i32,
+    pub log_prob: f32,
+    // log_prob as ranked by the scoring policy of the decoder.
+    pub score: f32,
+    // Input position and output step the prediction is made at.
+    pub t: usize,
+    pub u: usize,
+    // Only tracked by the v2 decoder. 0 otherwise.
+    pub total_duration: i32,
+    pub is_finished: bool,
+}
+
+impl LatticeNode {
+    // parent and step are filled in when the node is recorded.
+    pub fn new(prediction: i32, log_prob: f32, score: f32, t: usize, u: usize, total_duration: i32, is_finished: bool) -> LatticeNode {
+        LatticeNode {
+            parent: None,
+            step: 0,
+            prediction,
+            log_prob,
+            score,
+            t,
+            u,
+            total_duration,
+            is_finished,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LatticePath {
+    pub nodes: Vec<usize>,
+    pub prediction: Vec<i32>,
+    pub t_history: Vec<i32>,
+    pub total_duration: i32,
+    pub log_prob: f32,
+    pub score: f32,
+}
+
+// Search graph of a beam search. Every expanded hypothesis is a node, including the ones that did not survive selection.
+// Decoders record into lattices owned by the caller. They are only available from Rust, not through the C API or the ops.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Lattice {
+    pub nodes: Vec<LatticeNode>,
+    // Node each beam holds after the last recorded step.
+    beams: Vec<Option<usize>>,
+    n_steps: usize,
+}
+
+impl Lattice {
+    pub fn new() -> Lattice {
+        Lattice::default()
+    }
+
+    pub fn n_steps(&self) -> usize {
+        self.n_steps
+    }
+
+    // Records one decoding step.
+    // Candidates are paired with the beam they were expanded from, and `selected` is the candidate each beam continues with.
+    // Beams that had already finished keep their node instead of adding a copy of it.
+    pub fn push_step(&mut self, candidates: Vec<(usize, LatticeNode)>, selected: &[usize]) {
+        let step = self.n_steps;
+        let ids: Vec<Option<usize>> = candidates.into_iter().map(|(w, node)| {
+            let parent = self.beams.get(w).cloned().unwrap_or(None);
+            match parent {
+                Some(p) if self.nodes[p].is_finished => Some(p),
+                _ => {
+                    self.nodes.push(LatticeNode { parent, step, ..node });
+                    Some(self.nodes.len() - 1)
+                }
+            }
+        }).collect();
+        self.beams = selected.iter().map(|i| ids[*i]).collect();
+        self.n_steps += 1;
+    }
+
+    // Best score reachable from each node, counting only finished nodes if any.
+    fn best_completion(&self) -> Vec<f32> {
+        let finished_only = self.nodes.iter().any(|node| node.is_finished);
+        let mut best: Vec<f32> = self.nodes.iter()
+            .map(|node| if node.is_finished || !finished_only { node.score } else { f32::NEG_INFINITY })
+            .collect();
+        // Children are always recorded after their parents.
+        for i in (0..self.nodes.len()).rev() {
+            if let Some(p) = self.nodes[i].parent {
+                best[p] = best[p].max(best[i]);
+            }
+        }
+        best
+    }
+
+    // Removes nodes whose best path scores more than `beam` below the best path of the lattice.
+    pub fn prune(&mut self, beam: f32) {
+        let best = self.best_completion();
+        let threshold = best.iter().cloned().fold(f32::NEG_INFINITY, f32::max) - beam;
+        let mut ids: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut nodes: Vec<LatticeNode> = Vec::with_capacity(self.nodes.len());
+        for (i, node) in self.nodes.iter().enumerate() {
+            // A kept node always has a kept parent, since the parent scores at least as well.
+            if best[i] > f32::NEG_INFINITY && best[i] >= threshold {
+                ids[i] = Some(nodes.len());
+                nodes.push(LatticeNode { parent: node.parent.and_then(|p| ids[p]), ..*node });
+            }
+        }
+        self.beams = self.beams.iter().map(|id| id.and_then(|i| ids[i])).collect();
+        self.nodes = nodes;
+    }
+
+    fn path(&self, end: usize) -> LatticePath {
+        let mut nodes: Vec<usize> = vec![end];
+        while let Some(p) = self.nodes[*nodes.last().unwrap()].parent {
+            nodes.push(p);
+        }
+        nodes.reverse();
+        let last = &self.nodes[end];
+        LatticePath {
+            prediction: nodes.iter().map(|i| self.nodes[*i].prediction).collect(),
+            t_history: nodes.iter().map(|i| self.nodes[*i].t as i32).collect(),
+            nodes,
+            total_duration: last.total_duration,
+            log_prob: last.log_prob,
+            score: last.score,
+        }
+    }
+
+    pub fn best_path(&self) -> Option<LatticePath> {
+        self.n_best(1).pop()
+    }
+
+    // Top-N finished paths, best first. Only the best of identical prediction sequences is kept.
+    pub fn n_best(&self, n_best: usize) -> Vec<LatticePath> {
+        let mut ends: Vec<usize> = (0..self.nodes.len()).filter(|i| self.nodes[*i].is_finished).collect();
+        ends.sort_by(|a, b| self.nodes[*a].score.partial_cmp(&self.nodes[*b].score).unwrap_or(Ordering::Equal).reverse());
+        let mut paths: Vec<LatticePath> = Vec::with_capacity(n_best);
+        for end in ends.into_iter() {
+            if paths.len() == n_best {
+                break;
+            }
+            let path = self.path(end);
+            if !paths.iter().any(|p| p.prediction == path.prediction) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
+}
+
+// One lattice per batch item to record into, or none for every item.
+pub fn lattices_per_batch(lattices: Option<&mut [Lattice]>, batch_size: usize) -> Vec<Option<&mut Lattice>> {
+    match lattices {
+        Some(lattices) => {
+            assert_eq!(lattices.len(), batch_size);
+            lattices.iter_mut().map(Some).collect()
+        }
+        None => (0..batch_size).map(|_| None).collect(),
+    }
+}
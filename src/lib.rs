@@ -4,10 +4,29 @@ pub mod util;
 pub mod v2;
 pub mod v2_util;
 pub mod tone_latent;
+pub mod tone_duration;
 pub mod edit_distance;
+pub mod alignment;
+pub mod forward_backward;
+pub mod v2_duration;
+pub mod scoring;
+pub mod merge;
+pub mod diversity;
+pub mod sampling;
+pub mod pruning;
+pub mod n_best;
+pub mod lattice;
+pub mod ngram;
+pub mod rescoring;
 
 use std::cmp::Ordering;
 use rayon::prelude::*;
+use util::{extract_best_beam_branch_kernel, fill_beams};
+use scoring::ScoringPolicy;
+use merge::{MergeMode, merge_hypotheses};
+use diversity::DiversityPolicy;
+use pruning::PruningPolicy;
+use lattice::{Lattice, LatticeNode, lattices_per_batch};
 
 #[derive(PartialEq)]
 enum Transition {
@@ -75,9 +94,16 @@ pub struct DecodeResult {
     next_u: usize,
     is_finished: bool,
     parent_branch: usize,
+    // Duplicate that only fills the beam up to max_beam_width.
+    pub is_padding: bool,
 }
 
 impl DecodeResult {
+    // Number of steps taken, counting the finishing step.
+    fn length(&self) -> usize {
+        if self.is_finished { self.next_u + 1 } else { self.next_u }
+    }
+
     fn eq_ignore_parent(&self, other: &DecodeResult) -> bool {
         self.prediction == other.prediction &&
             self.log_prob == other.log_prob &&
@@ -85,6 +111,40 @@ impl DecodeResult {
             self.next_u == other.next_u &&
             self.is_finished == other.is_finished
     }
+
+    // Same transition from the same beam, regardless of the log_prob after merging.
+    fn is_same_transition(&self, other: &DecodeResult) -> bool {
+        self.parent_branch == other.parent_branch &&
+            self.prediction == other.prediction &&
+            self.next_t == other.next_t &&
+            self.next_u == other.next_u &&
+            self.is_finished == other.is_finished
+    }
+}
+
+
+/// Supplies Emit/Shift log-probs to the full-utterance decoder.
+pub trait TransitionModel {
+    // Returns (W, 2) log-probs for every beam at output step `u`.
+    // `beam_branch` is the parent of each beam at the previous step so that autoregressive states can be reordered.
+    fn transition_log_prob(&mut self, beam_branch: &[usize], t: &[usize], u: usize) -> Vec<f32>;
+}
+
+impl<F> TransitionModel for F where F: FnMut(&[usize], &[usize], usize) -> Vec<f32> {
+    fn transition_log_prob(&mut self, beam_branch: &[usize], t: &[usize], u: usize) -> Vec<f32> {
+        self(beam_branch, t, u)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hypothesis {
+    pub prediction: Vec<i32>,
+    // Input position each output frame is aligned to.
+    pub t_history: Vec<i32>,
+    pub log_prob: f32,
+    // log_prob as ranked by the scoring policy.
+    pub score: f32,
+    pub is_finished: bool,
 }
 
 
@@ -93,32 +153,114 @@ pub struct SsntTtsCpu {
     input_length: usize,
     max_u: usize,
     transition_size: usize,
+    scoring: ScoringPolicy,
+    merge_mode: MergeMode,
+    diversity: DiversityPolicy,
+    pruning: PruningPolicy,
 }
 
 impl SsntTtsCpu {
-    pub fn new(batch_size: i32, input_length: usize, max_u: usize) -> SsntTtsCpu {
+    pub fn new(batch_size: i32, input_length: usize, max_u: usize, scoring: ScoringPolicy, merge_mode: MergeMode, diversity: DiversityPolicy, pruning: PruningPolicy) -> SsntTtsCpu {
         let transition_size = 2;
         SsntTtsCpu {
             batch_size,
             input_length,
             max_u,
             transition_size,
+            scoring,
+            merge_mode,
+            diversity,
+            pruning,
+        }
+    }
+
+    // Coverage is the fraction of the input the hypothesis has reached.
+    fn rank_score(&self, result: &DecodeResult) -> f32 {
+        let coverage = (result.next_t + 1) as f32 / self.input_length as f32;
+        self.scoring.score(result.log_prob, result.length(), coverage)
+    }
+
+    // Every hypothesis reachable from the current beams, best first.
+    fn expand(&self, h: &BeamSearchDecodingTable, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
+        let mut results: Vec<DecodeResult> = (0..h.beam_width)
+            .into_par_iter()
+            .flat_map(|w| {
+                let t = start_t[w];
+                let u = u[w];
+                let log_prob_history = h.log_prob_history[w];
+                let is_finished = h.is_finished[w];
+                self.beam_search_kernel_internal(h, w, t, u, log_prob_history, is_finished)
+            }).collect();
+
+        // Here the sorting does not consider prefixes. This is because we are interested in intermediate features which is path dependent.
+        results.sort_by(|a, b| self.rank_score(a).partial_cmp(&self.rank_score(b)).unwrap_or(Ordering::Equal).reverse());
+        results.dedup_by(|a, b| a.eq_ignore_parent(b) && self.diversity.same_group(a.parent_branch, b.parent_branch, h.beam_width));
+        results
+    }
+
+    // Chooses the next max_beam_width beams among the expanded hypotheses.
+    fn select(&self, h: &BeamSearchDecodingTable, mut results: Vec<DecodeResult>) -> Vec<DecodeResult> {
+        // Optionally merge paths reaching the same lattice state, which makes intermediate features path independent.
+        if self.merge_mode != MergeMode::NoMerge {
+            results = merge_hypotheses(results, self.merge_mode,
+                                       |r| (r.next_t, r.next_u, r.is_finished),
+                                       |r| r.log_prob,
+                                       |r, log_prob| DecodeResult { log_prob, ..r });
+            results.sort_by(|a, b| self.rank_score(a).partial_cmp(&self.rank_score(b)).unwrap_or(Ordering::Equal).reverse());
+        }
+        if self.diversity.is_enabled() {
+            results = self.diversity.select(results, h.beam_width, h.max_beam_width, &self.pruning,
+                                            |r| r.parent_branch,
+                                            |r| r.prediction,
+                                            |r| self.rank_score(r),
+                                            |r| DecodeResult { is_padding: true, ..*r });
+        } else {
+            self.pruning.prune(&mut results, |r| self.rank_score(r));
+        }
+        fill_beams(results, None, h.max_beam_width, |r| r.is_padding, |r| DecodeResult { is_padding: true, ..*r })
+    }
+
+    // beam_search_kernel that also records the step into the lattice if one is given.
+    fn beam_search_step(&self, h: &BeamSearchDecodingTable, start_t: &[usize], u: &[usize], lattice: Option<&mut Lattice>) -> Vec<DecodeResult> {
+        match lattice {
+            None => self.beam_search_kernel(h, start_t, u),
+            Some(lattice) => {
+                let expanded = self.expand(h, start_t, u);
+                let results = self.select(h, expanded.clone());
+                let candidates: Vec<(usize, LatticeNode)> = expanded.iter().map(|r| {
+                    let w = r.parent_branch;
+                    (w, LatticeNode::new(r.prediction, r.log_prob, self.rank_score(r), start_t[w], u[w], 0, r.is_finished))
+                }).collect();
+                // Merged hypotheses only differ from their expanded representative in log_prob.
+                let selected: Vec<usize> = results.iter().map(|r| {
+                    expanded.iter().position(|e| e.is_same_transition(r) && e.log_prob == r.log_prob)
+                        .or_else(|| expanded.iter().position(|e| e.is_same_transition(r)))
+                        .expect("selected hypothesis must be expanded")
+                }).collect();
+                lattice.push_step(candidates, selected.as_slice());
+                results
+            }
         }
     }
 }
 
 pub trait SsntTts {
-    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32]) -> ();
+    // Each step is appended to the lattice of its batch item if lattices are given.
+    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32], num_valid: &mut [i32], lattices: Option<&mut [Lattice]>) -> ();
 
     fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult>;
 
     fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32, is_finished: bool) -> Vec<DecodeResult>;
+
+    // The search graph is appended to the lattice if one is given, so it should start out empty.
+    fn decode<M: TransitionModel>(&self, model: &mut M, beam_width: i32, lattice: Option<&mut Lattice>) -> Vec<Hypothesis>;
 }
 
 
 impl SsntTts for SsntTtsCpu {
 
-    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32]) -> () {
+    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32], num_valid: &mut [i32], lattices: Option<&mut [Lattice]>) -> () {
+        let mut lattices = lattices_per_batch(lattices, num_valid.len());
         h.par_chunks(beam_width as usize * self.transition_size)
             .zip(log_prob_history.par_chunks(beam_width as usize))
             .zip(is_finished.par_chunks(beam_width as usize))
@@ -130,11 +272,13 @@ impl SsntTts for SsntTtsCpu {
             .zip(next_u.par_chunks_mut(max_beam_width as usize))
             .zip(beam_branch.par_chunks_mut(max_beam_width as usize))
             .zip(next_is_finished.par_chunks_mut(max_beam_width as usize))
-            .for_each(|((((((((((h, log_prob_history), is_finished), t), u), prediction), log_probs), next_t), next_u), w), next_is_finished)| {
+            .zip(num_valid.par_chunks_mut(1))
+            .zip(lattices.par_iter_mut())
+            .for_each(|((((((((((((h, log_prob_history), is_finished), t), u), prediction), log_probs), next_t), next_u), w), next_is_finished), num_valid), lattice)| {
                 let table = BeamSearchDecodingTable::new(h, log_prob_history, is_finished, self.input_length, beam_width as usize, max_beam_width as usize);
                 let t: Vec<usize> = t.iter().map(|v| *v as usize).collect();
                 let u: Vec<usize> = u.iter().map(|v| *v as usize).collect();
-                let results = self.beam_search_kernel(&table, t.as_slice(), u.as_slice());
+                let results = self.beam_search_step(&table, t.as_slice(), u.as_slice(), lattice.as_deref_mut());
                 results.iter().enumerate().for_each(|(i, result)| {
                     prediction[i] = result.prediction;
                     log_probs[i] = result.log_prob;
@@ -143,30 +287,13 @@ impl SsntTts for SsntTtsCpu {
                     w[i] = result.parent_branch as i32;
                     next_is_finished[i] = result.is_finished;
                 });
+                num_valid[0] = results.iter().filter(|result| !result.is_padding).count() as i32;
             });
     }
 
     fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
-        let mut results: Vec<DecodeResult> = (0..h.beam_width)
-            .into_par_iter()
-            .flat_map(|w| {
-                let t = start_t[w];
-                let u = u[w];
-                let log_prob_history = h.log_prob_history[w];
-                let is_finished = h.is_finished[w];
-                self.beam_search_kernel_internal(h, w, t, u, log_prob_history, is_finished)
-            }).collect();
-
-        // Here the sorting does not consider prefixes. This is because we are interested in intermediate features which is path dependent.
-        results.sort_by(|a, b| a.log_prob.partial_cmp(&b.log_prob).unwrap_or(Ordering::Equal).reverse());
-        results.dedup_by(|a, b| a.eq_ignore_parent(b));
-        if results.len() < h.max_beam_width {
-            for i in 0..(h.max_beam_width - results.len()) {
-                results.push(results[i].clone());
-            }
-        }
-        results.truncate(h.max_beam_width);
-        results
+        let expanded = self.expand(h, start_t, u);
+        self.select(h, expanded)
     }
 
     fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32, is_finished: bool) -> Vec<DecodeResult> {
@@ -180,6 +307,7 @@ impl SsntTts for SsntTtsCpu {
                     next_u: u,
                     is_finished: true,
                     parent_branch: w,
+                    is_padding: false,
                 }]
             }
             Some(results) => {
@@ -192,6 +320,7 @@ impl SsntTts for SsntTtsCpu {
                             next_u: u,
                             is_finished: true,
                             parent_branch: w,
+                            is_padding: false,
                         }
                     } else if prediction == Transition::Shift && t == self.input_length - 1 {
                         // Shift transition is prohibited.
@@ -202,6 +331,7 @@ impl SsntTts for SsntTtsCpu {
                             next_u: u,
                             is_finished: true,
                             parent_branch: w,
+                            is_padding: false,
                         }
                     } else if prediction == Transition::Shift {
                         // Shift transition. Proceed to t + 1.
@@ -212,6 +342,7 @@ impl SsntTts for SsntTtsCpu {
                             next_u: u + 1,
                             is_finished: false,
                             parent_branch: w,
+                            is_padding: false,
                         }
                     } else {
                         // Emit transition. Keep the same t for next step.
@@ -222,10 +353,75 @@ impl SsntTts for SsntTtsCpu {
                             next_u: u + 1,
                             is_finished: false,
                             parent_branch: w,
+                            is_padding: false,
                         }
                     }
                 }).collect()
             }
         }
     }
-}
\ No newline at end of file
+    fn decode<M: TransitionModel>(&self, model: &mut M, beam_width: i32, mut lattice: Option<&mut Lattice>) -> Vec<Hypothesis> {
+        let beam_width = beam_width as usize;
+        let mut log_prob_history: Vec<f32> = vec![0.0; beam_width];
+        let mut is_finished: Vec<bool> = vec![false; beam_width];
+        let mut t: Vec<usize> = vec![0; beam_width];
+        let mut u: Vec<usize> = vec![0; beam_width];
+        let mut parent_branch: Vec<usize> = (0..beam_width).collect();
+        let mut is_padding: Vec<bool> = vec![false; beam_width];
+        // (U, W)
+        let mut prediction_history: Vec<i32> = Vec::with_capacity(self.max_u * beam_width);
+        let mut beam_branch_history: Vec<i32> = Vec::with_capacity(self.max_u * beam_width);
+        let mut t_history: Vec<i32> = Vec::with_capacity(self.max_u * beam_width);
+        let mut is_finished_history: Vec<bool> = Vec::with_capacity(self.max_u * beam_width);
+
+        for step in 0..self.max_u {
+            if is_finished.iter().all(|f| *f) {
+                break;
+            }
+            let h = model.transition_log_prob(parent_branch.as_slice(), t.as_slice(), step);
+            let table = BeamSearchDecodingTable::new(h.as_slice(), log_prob_history.as_slice(), is_finished.as_slice(), self.input_length, beam_width, beam_width);
+            let results = self.beam_search_step(&table, t.as_slice(), u.as_slice(), lattice.as_deref_mut());
+            // Each frame is aligned to the input position it was emitted from, not the one it moves to.
+            let current_t: Vec<usize> = results.iter().map(|result| t[result.parent_branch]).collect();
+            results.iter().zip(current_t.iter()).enumerate().for_each(|(i, (result, current_t))| {
+                prediction_history.push(result.prediction);
+                beam_branch_history.push(result.parent_branch as i32);
+                t_history.push(*current_t as i32);
+                is_finished_history.push(result.is_finished);
+                log_prob_history[i] = result.log_prob;
+                is_finished[i] = result.is_finished;
+                t[i] = result.next_t;
+                u[i] = result.next_u;
+                parent_branch[i] = result.parent_branch;
+                is_padding[i] = result.is_padding;
+            });
+        }
+
+        let n_steps = prediction_history.len() / beam_width;
+        // Padding duplicates of the last step are not returned.
+        let mut hypotheses: Vec<Hypothesis> = (0..beam_width).filter(|w| !is_padding[*w]).map(|w| {
+            let (branch, ts) = extract_best_beam_branch_kernel(w as i32, beam_branch_history.as_slice(), t_history.as_slice(), beam_width as i32, n_steps as i32);
+            // Padding steps after the finishing step are dropped.
+            let length = branch.iter().enumerate()
+                .position(|(s, b)| is_finished_history[s * beam_width + *b as usize])
+                .map_or(n_steps, |s| s + 1);
+            let prediction: Vec<i32> = branch.iter().enumerate().take(length)
+                .map(|(s, b)| prediction_history[s * beam_width + *b as usize])
+                .collect();
+            let coverage = (ts.get(length.max(1) - 1).map_or(0, |t| *t) + 1) as f32 / self.input_length as f32;
+            Hypothesis {
+                prediction,
+                t_history: ts[..length].to_vec(),
+                log_prob: log_prob_history[w],
+                score: self.scoring.score(log_prob_history[w], length, coverage),
+                is_finished: is_finished[w],
+            }
+        }).collect();
+        // Finished hypotheses come first so that the best one is a complete utterance whenever one exists.
+        hypotheses.sort_by(|a, b| {
+            b.is_finished.cmp(&a.is_finished)
+                .then(a.score.partial_cmp(&b.score).unwrap_or(Ordering::Equal).reverse())
+        });
+        hypotheses
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use crate::util::log_add_exp;
+
+
+// How hypotheses reaching the same decoder state through different paths are combined.
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub enum MergeMode {
+    // Keep every path. Needed when intermediate features depend on the path.
+    #[default]
+    NoMerge,
+    // Keep the best path only.
+    Max,
+    // Keep the best path with the summed probability of all paths.
+    LogSumExp,
+}
+
+impl MergeMode {
+    // 0: NoMerge, 1: Max, 2: LogSumExp, as passed through the C interface. Returns None for any other id.
+    pub fn from_id(id: i32) -> Option<MergeMode> {
+        match id {
+            0 => Some(MergeMode::NoMerge),
+            1 => Some(MergeMode::Max),
+            2 => Some(MergeMode::LogSumExp),
+            _ => None,
+        }
+    }
+}
+
+// Merges hypotheses with the same key, keeping the best one of each group as representative in order of first appearance.
+pub fn merge_hypotheses<T, K, FK, FP, FS>(results: Vec<T>, mode: MergeMode, key: FK, log_prob: FP, with_log_prob: FS) -> Vec<T>
+    where T: Copy, K: Eq + Hash, FK: Fn(&T) -> K, FP: Fn(&T) -> f32, FS: Fn(T, f32) -> T {
+    if mode == MergeMode::NoMerge {
+        return results;
+    }
+    let mut groups: HashMap<K, usize> = HashMap::new();
+    // (representative, merged log_prob)
+    let mut merged: Vec<(T, f32)> = Vec::with_capacity(results.len());
+    results.into_iter().for_each(|result| {
+        let score = log_prob(&result);
+        match groups.get(&key(&result)) {
+            Some(&index) => {
+                let (representative, total) = merged[index];
+                let total = match mode {
+                    MergeMode::LogSumExp => log_add_exp(total, score),
+                    _ => total.max(score),
+                };
+                let representative = if score > log_prob(&representative) { result } else { representative };
+                merged[index] = (representative, total);
+            }
+            None => {
+                groups.insert(key(&result), merged.len());
+                merged.push((result, score));
+            }
+        }
+    });
+    merged.into_iter().map(|(representative, total)| with_log_prob(representative, total)).collect()
+}
@@ -0,0 +1,110 @@
+extern crate rayon;
+
+use std::cmp::Ordering;
+use std::f32;
+use rayon::prelude::*;
+use crate::util::extract_best_beam_branch_kernel;
+use crate::scoring::ScoringPolicy;
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct NBestHypothesis {
+    // Beam the hypothesis ends in at the last step.
+    pub final_branch: usize,
+    pub prediction: Vec<i32>,
+    pub t_history: Vec<i32>,
+    // Only tracked by the v2 decoder. 0 otherwise.
+    pub total_duration: i32,
+    pub log_prob: f32,
+    pub score: f32,
+}
+
+// Histories are (B, S, W) as recorded at each step of the step-wise decoders, and final values are (B, W).
+// Outputs are (B, N, S) padded with -1 and (B, N) padded with -inf scores, 0 lengths and 0 durations.
+pub fn n_best(prediction: &[i32], beam_branch: &[i32], t_history: &[i32], is_finished: &[bool], log_probs: &[f32], total_duration: Option<&[i32]>, batch_size: usize, beam_width: usize, n_steps: usize, n_best: usize, scoring: &ScoringPolicy,
+              best_prediction: &mut [i32], best_t_history: &mut [i32], best_total_duration: &mut [i32], best_log_probs: &mut [f32], best_scores: &mut [f32], best_length: &mut [i32], num_valid: &mut [i32]) {
+    let history_size = n_steps * beam_width;
+    assert_eq!(prediction.len(), batch_size * history_size);
+    assert_eq!(beam_branch.len(), batch_size * history_size);
+    assert_eq!(t_history.len(), batch_size * history_size);
+    assert_eq!(is_finished.len(), batch_size * history_size);
+    assert_eq!(log_probs.len(), batch_size * beam_width);
+    assert!(total_duration.is_none_or(|d| d.len() == batch_size * beam_width));
+    assert_eq!(best_prediction.len(), batch_size * n_best * n_steps);
+    assert_eq!(best_t_history.len(), batch_size * n_best * n_steps);
+    assert_eq!(best_total_duration.len(), batch_size * n_best);
+    assert_eq!(best_log_probs.len(), batch_size * n_best);
+    assert_eq!(best_scores.len(), batch_size * n_best);
+    assert_eq!(best_length.len(), batch_size * n_best);
+    assert_eq!(num_valid.len(), batch_size);
+    best_prediction.par_chunks_mut(n_best * n_steps)
+        .zip(best_t_history.par_chunks_mut(n_best * n_steps))
+        .zip(best_total_duration.par_chunks_mut(n_best))
+        .zip(best_log_probs.par_chunks_mut(n_best))
+        .zip(best_scores.par_chunks_mut(n_best))
+        .zip(best_length.par_chunks_mut(n_best))
+        .zip(num_valid.par_chunks_mut(1))
+        .enumerate()
+        .for_each(|(b, ((((((best_prediction, best_t_history), best_total_duration), best_log_probs), best_scores), best_length), num_valid))| {
+            let history = b * history_size..(b + 1) * history_size;
+            let finals = b * beam_width..(b + 1) * beam_width;
+            let hypotheses = n_best_kernel(&prediction[history.clone()], &beam_branch[history.clone()], &t_history[history.clone()], &is_finished[history],
+                                           &log_probs[finals.clone()], total_duration.map(|d| &d[finals]), beam_width, n_steps, n_best, scoring);
+            best_prediction.iter_mut().for_each(|v| *v = -1);
+            best_t_history.iter_mut().for_each(|v| *v = -1);
+            best_total_duration.iter_mut().for_each(|v| *v = 0);
+            best_log_probs.iter_mut().for_each(|v| *v = f32::NEG_INFINITY);
+            best_scores.iter_mut().for_each(|v| *v = f32::NEG_INFINITY);
+            best_length.iter_mut().for_each(|v| *v = 0);
+            hypotheses.iter().enumerate().for_each(|(n, hypothesis)| {
+                let length = hypothesis.prediction.len();
+                best_prediction[n * n_steps..n * n_steps + length].copy_from_slice(hypothesis.prediction.as_slice());
+                best_t_history[n * n_steps..n * n_steps + length].copy_from_slice(hypothesis.t_history.as_slice());
+                best_total_duration[n] = hypothesis.total_duration;
+                best_log_probs[n] = hypothesis.log_prob;
+                best_scores[n] = hypothesis.score;
+                best_length[n] = length as i32;
+            });
+            num_valid[0] = hypotheses.len() as i32;
+        });
+}
+
+// Top-N finished hypotheses of a single batch item, best first.
+// Every hypothesis ends at its finishing step, and only the best of identical prediction sequences is kept.
+pub fn n_best_kernel(prediction: &[i32], beam_branch: &[i32], t_history: &[i32], is_finished: &[bool], log_probs: &[f32], total_duration: Option<&[i32]>, beam_width: usize, n_steps: usize, n_best: usize, scoring: &ScoringPolicy) -> Vec<NBestHypothesis> {
+    if n_steps == 0 {
+        return vec![];
+    }
+    let last_step = (n_steps - 1) * beam_width;
+    let mut hypotheses: Vec<NBestHypothesis> = (0..beam_width)
+        .filter(|w| is_finished[last_step + *w] && log_probs[*w] > f32::NEG_INFINITY)
+        .map(|w| {
+            let (branch, ts) = extract_best_beam_branch_kernel(w as i32, beam_branch, t_history, beam_width as i32, n_steps as i32);
+            let length = branch.iter().enumerate()
+                .position(|(s, b)| is_finished[s * beam_width + *b as usize])
+                .map_or(n_steps, |s| s + 1);
+            let prediction: Vec<i32> = branch.iter().enumerate().take(length)
+                .map(|(s, b)| prediction[s * beam_width + *b as usize])
+                .collect();
+            // Finished hypotheses cover the whole input.
+            NBestHypothesis {
+                final_branch: w,
+                prediction,
+                t_history: ts[..length].to_vec(),
+                total_duration: total_duration.map_or(0, |d| d[w]),
+                log_prob: log_probs[w],
+                score: scoring.score(log_probs[w], length, 1.0),
+            }
+        }).collect();
+    hypotheses.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap_or(Ordering::Equal).reverse());
+    let mut unique: Vec<NBestHypothesis> = Vec::with_capacity(n_best);
+    for hypothesis in hypotheses.into_iter() {
+        if unique.len() == n_best {
+            break;
+        }
+        if !unique.iter().any(|h| h.prediction == hypothesis.prediction) {
+            unique.push(hypothesis);
+        }
+    }
+    unique
+}
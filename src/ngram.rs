@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::f32;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+
+// Reserved ids of the ARPA sentence markers and unknown token. Regular tokens are class ids.
+pub const BOS_ID: i32 = -1;
+pub const EOS_ID: i32 = -2;
+pub const UNK_ID: i32 = -3;
+
+#[derive(Debug)]
+pub enum ArpaError {
+    Io(io::Error),
+    // The text has no \data\ section.
+    MissingHeader,
+    InvalidLine { line_number: usize, line: String },
+}
+
+impl fmt::Display for ArpaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArpaError::Io(e) => write!(f, "Failed to read ARPA file: {}", e),
+            ArpaError::MissingHeader => write!(f, "ARPA text does not contain a \\data\\ section."),
+            ArpaError::InvalidLine { line_number, line } => write!(f, "Invalid ARPA line {}: {}", line_number, line),
+        }
+    }
+}
+
+impl std::error::Error for ArpaError {}
+
+impl From<io::Error> for ArpaError {
+    fn from(e: io::Error) -> ArpaError {
+        ArpaError::Io(e)
+    }
+}
+
+// Back-off n-gram model over class ids, e.g. tone classes or duration classes.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NGramModel {
+    order: usize,
+    // Natural-log probability and back-off weight of every listed n-gram.
+    entries: HashMap<Vec<i32>, (f32, f32)>,
+}
+
+impl NGramModel {
+    pub fn load_arpa<P: AsRef<Path>>(path: P) -> Result<NGramModel, ArpaError> {
+        let text = fs::read_to_string(path)?;
+        NGramModel::from_arpa(text.as_str())
+    }
+
+    // ARPA probabilities are log10 and are converted to natural log like the decoder scores.
+    pub fn from_arpa(text: &str) -> Result<NGramModel, ArpaError> {
+        let mut order = 0;
+        let mut section: Option<usize> = None;
+        let mut has_header = false;
+        let mut entries: HashMap<Vec<i32>, (f32, f32)> = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let invalid = || ArpaError::InvalidLine { line_number: i + 1, line: line.to_string() };
+            if line.is_empty() {
+                continue;
+            }
+            if line == "\\data\\" {
+                has_header = true;
+                continue;
+            }
+            if line == "\\end\\" {
+                break;
+            }
+            if line.starts_with('\\') && line.ends_with("-grams:") {
+                let n: usize = line[1..line.len() - "-grams:".len()].parse().map_err(|_| invalid())?;
+                section = Some(n);
+                order = order.max(n);
+                continue;
+            }
+            match section {
+                // Counts of the \data\ section are not needed.
+                None => {
+                    if has_header && !line.starts_with("ngram ") {
+                        return Err(invalid());
+                    }
+                }
+                Some(n) => {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    if fields.len() != n + 1 && fields.len() != n + 2 {
+                        return Err(invalid());
+                    }
+                    let log_prob: f32 = fields[0].parse().map_err(|_| invalid())?;
+                    let tokens: Vec<i32> = fields[1..n + 1].iter()
+                        .map(|token| NGramModel::token_id(token))
+                        .collect::<Option<Vec<i32>>>()
+                        .ok_or_else(invalid)?;
+                    let backoff: f32 = match fields.get(n + 1) {
+                        Some(field) => field.parse().map_err(|_| invalid())?,
+                        None => 0.0,
+                    };
+                    entries.insert(tokens, (log_prob * f32::consts::LN_10, backoff * f32::consts::LN_10));
+                }
+            }
+        }
+        if !has_header {
+            return Err(ArpaError::MissingHeader);
+        }
+        Ok(NGramModel {
+            order,
+            entries,
+        })
+    }
+
+    fn token_id(token: &str) -> Option<i32> {
+        match token {
+            "<s>" => Some(BOS_ID),
+            "</s>" => Some(EOS_ID),
+            "<unk>" => Some(UNK_ID),
+            _ => token.parse().ok(),
+        }
+    }
+
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    // log P(token | context) with back-off, where context lists the preceding tokens oldest first.
+    // Tokens outside the vocabulary are scored as <unk>, or -inf if the model has none.
+    pub fn log_prob(&self, context: &[i32], token: i32) -> f32 {
+        let start = context.len().saturating_sub(self.order.saturating_sub(1));
+        let context = &context[start..];
+        let mut ngram: Vec<i32> = context.to_vec();
+        ngram.push(token);
+        if let Some((log_prob, _)) = self.entries.get(&ngram) {
+            return *log_prob;
+        }
+        if context.is_empty() {
+            return if token == UNK_ID {
+                f32::NEG_INFINITY
+            } else {
+                self.log_prob(context, UNK_ID)
+            };
+        }
+        let backoff = self.entries.get(context).map_or(0.0, |(_, backoff)| *backoff);
+        backoff + self.log_prob(&context[1..], token)
+    }
+
+    // Log probability of a complete sequence between <s> and </s>.
+    pub fn sequence_log_prob(&self, tokens: &[i32]) -> f32 {
+        let mut context: Vec<i32> = vec![BOS_ID];
+        let mut log_prob = 0.0;
+        for token in tokens.iter().chain(std::iter::once(&EOS_ID)) {
+            log_prob += self.log_prob(context.as_slice(), *token);
+            context.push(*token);
+        }
+        log_prob
+    }
+}
+
+// Shallow fusion of an n-gram model into beam search, which adds the weighted LM log prob of every expansion to its score.
+#[derive(Debug, Clone)]
+pub struct LmFusion {
+    pub lm: Arc<NGramModel>,
+    pub weight: f32,
+}
+
+impl LmFusion {
+    pub fn new(lm: Arc<NGramModel>, weight: f32) -> LmFusion {
+        LmFusion {
+            lm,
+            weight,
+        }
+    }
+
+    // Number of preceding tokens each beam has to carry. Contexts start filled with BOS_ID.
+    pub fn context_size(&self) -> usize {
+        self.lm.order().saturating_sub(1)
+    }
+
+    pub fn log_prob(&self, context: &[i32], token: i32) -> f32 {
+        // Avoids 0 * -inf for tokens the model cannot produce.
+        if self.weight == 0.0 {
+            return 0.0;
+        }
+        self.weight * self.lm.log_prob(context, token)
+    }
+
+    // Drops the oldest token and appends `token`.
+    pub fn next_context(context: &[i32], token: i32, next_context: &mut [i32]) {
+        if context.is_empty() {
+            return;
+        }
+        next_context[..context.len() - 1].copy_from_slice(&context[1..]);
+        next_context[context.len() - 1] = token;
+    }
+}
@@ -0,0 +1,44 @@
+use std::f32;
+
+
+// Score-relative and histogram pruning of the hypotheses kept at each step.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct PruningPolicy {
+    // Hypotheses scoring more than beam_threshold below the best are dropped. Infinity disables it.
+    pub beam_threshold: f32,
+    // At most max_active hypotheses are kept. 0 disables it.
+    pub max_active: usize,
+}
+
+impl Default for PruningPolicy {
+    fn default() -> PruningPolicy {
+        PruningPolicy {
+            beam_threshold: f32::INFINITY,
+            max_active: 0,
+        }
+    }
+}
+
+impl PruningPolicy {
+    // A negative beam_threshold disables score-relative pruning.
+    pub fn new(beam_threshold: f32, max_active: usize) -> PruningPolicy {
+        PruningPolicy {
+            beam_threshold: if beam_threshold < 0.0 { f32::INFINITY } else { beam_threshold },
+            max_active,
+        }
+    }
+
+    // Results are expected in the order they are kept, so max_active drops from the end.
+    // The best hypothesis always survives.
+    pub fn prune<T, FS>(&self, results: &mut Vec<T>, score: FS) where FS: Fn(&T) -> f32 {
+        if self.beam_threshold.is_finite() {
+            let best = results.iter().map(&score).fold(f32::NEG_INFINITY, f32::max);
+            if best.is_finite() {
+                results.retain(|r| score(r) >= best - self.beam_threshold);
+            }
+        }
+        if self.max_active > 0 {
+            results.truncate(self.max_active);
+        }
+    }
+}
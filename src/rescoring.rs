@@ -0,0 +1,65 @@
+use std::cmp::Ordering;
+use crate::ngram::{NGramModel, BOS_ID, EOS_ID};
+use crate::lattice::{Lattice, LatticePath};
+use crate::n_best::NBestHypothesis;
+
+
+// Adds lm_weight times the LM log probability of each complete prediction sequence to its score, and re-sorts best first.
+pub fn rescore_hypotheses<T, FP, FS, FU>(hypotheses: Vec<T>, lm: &NGramModel, lm_weight: f32, prediction: FP, score: FS, with_score: FU) -> Vec<T>
+    where FP: Fn(&T) -> &[i32], FS: Fn(&T) -> f32, FU: Fn(T, f32) -> T {
+    let mut rescored: Vec<(f32, T)> = hypotheses.into_iter().map(|h| {
+        let score = score(&h) + lm_weight * lm.sequence_log_prob(prediction(&h));
+        (score, with_score(h, score))
+    }).collect();
+    rescored.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal).reverse());
+    rescored.into_iter().map(|(_, h)| h).collect()
+}
+
+pub fn rescore_n_best(hypotheses: Vec<NBestHypothesis>, lm: &NGramModel, lm_weight: f32) -> Vec<NBestHypothesis> {
+    rescore_hypotheses(hypotheses, lm, lm_weight,
+                       |h| h.prediction.as_slice(),
+                       |h| h.score,
+                       |h, score| NBestHypothesis { score, ..h })
+}
+
+pub fn rescore_paths(paths: Vec<LatticePath>, lm: &NGramModel, lm_weight: f32) -> Vec<LatticePath> {
+    rescore_hypotheses(paths, lm, lm_weight,
+                       |p| p.prediction.as_slice(),
+                       |p| p.score,
+                       |p, score| LatticePath { score, ..p })
+}
+
+// Adds the weighted LM log probability of the path leading to every node to its score, so that pruning, best_path and n_best use the combined score.
+// Finished nodes also pay for the end of sentence.
+pub fn rescore_lattice(lattice: &mut Lattice, lm: &NGramModel, lm_weight: f32) {
+    let context_size = lm.order().saturating_sub(1);
+    let mut lm_log_probs: Vec<f32> = Vec::with_capacity(lattice.nodes.len());
+    // Parents are always recorded before their children.
+    for i in 0..lattice.nodes.len() {
+        let node = lattice.nodes[i];
+        let mut context: Vec<i32> = Vec::with_capacity(context_size);
+        let mut ancestor = node.parent;
+        while context.len() < context_size {
+            match ancestor {
+                Some(a) => {
+                    context.push(lattice.nodes[a].prediction);
+                    ancestor = lattice.nodes[a].parent;
+                }
+                None => {
+                    context.push(BOS_ID);
+                    break;
+                }
+            }
+        }
+        context.reverse();
+        let mut log_prob = node.parent.map_or(0.0, |p| lm_log_probs[p]) + lm.log_prob(context.as_slice(), node.prediction);
+        if node.is_finished {
+            context.push(node.prediction);
+            log_prob += lm.log_prob(context.as_slice(), EOS_ID);
+        }
+        lm_log_probs.push(log_prob);
+    }
+    lattice.nodes.iter_mut().zip(lm_log_probs.iter()).for_each(|(node, log_prob)| {
+        node.score += lm_weight * log_prob;
+    });
+}
@@ -0,0 +1,74 @@
+use std::cmp::Ordering;
+use std::f32;
+use crate::util::{Rng, log_sum_exp};
+
+
+// Filters applied to the candidate distribution before drawing a class.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct SamplingPolicy {
+    // Non-positive temperature picks the most likely candidate.
+    pub temperature: f32,
+    // Keeps the k most likely candidates. 0 disables it.
+    pub top_k: usize,
+    // Keeps the smallest set of candidates whose probability reaches top_p. 1.0 disables it.
+    pub top_p: f32,
+}
+
+impl Default for SamplingPolicy {
+    fn default() -> SamplingPolicy {
+        SamplingPolicy {
+            temperature: 1.0,
+            top_k: 0,
+            top_p: 1.0,
+        }
+    }
+}
+
+impl SamplingPolicy {
+    pub fn new(temperature: f32, top_k: usize, top_p: f32) -> SamplingPolicy {
+        SamplingPolicy {
+            temperature,
+            top_k,
+            top_p,
+        }
+    }
+
+    // Tempered log-weights with filtered candidates set to -inf.
+    pub fn filter(&self, log_probs: &[f32]) -> Vec<f32> {
+        let mut order: Vec<usize> = (0..log_probs.len()).filter(|i| log_probs[*i] > f32::NEG_INFINITY).collect();
+        order.sort_by(|a, b| log_probs[*a].partial_cmp(&log_probs[*b]).unwrap_or(Ordering::Equal).reverse());
+        let mut weights: Vec<f32> = vec![f32::NEG_INFINITY; log_probs.len()];
+        if order.is_empty() {
+            return weights;
+        }
+        if self.temperature <= 0.0 {
+            weights[order[0]] = 0.0;
+            return weights;
+        }
+        if self.top_k > 0 {
+            order.truncate(self.top_k);
+        }
+        let tempered: Vec<f32> = order.iter().map(|i| log_probs[*i] / self.temperature).collect();
+        let normalizer = log_sum_exp(tempered.as_slice());
+        let mut cumulative = 0.0;
+        for (i, w) in order.iter().zip(tempered.iter()) {
+            weights[*i] = *w;
+            cumulative += (w - normalizer).exp();
+            if cumulative >= self.top_p {
+                break;
+            }
+        }
+        weights
+    }
+
+    pub fn sample(&self, rng: &mut Rng, log_probs: &[f32]) -> Option<usize> {
+        rng.categorical(self.filter(log_probs).as_slice())
+    }
+}
+
+// Independent stream for each batch item, beam and step so that one seed reproduces a whole decode.
+// Every index goes through its own SplitMix64 step, so neither large nor permuted indexes give related streams.
+pub fn beam_rng(seed: u64, b: usize, w: usize, u: usize) -> Rng {
+    let state = [b, w, u].iter().fold(seed, |state, index| Rng::new(state ^ *index as u64).next_u64());
+    Rng::new(state)
+}
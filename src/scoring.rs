@@ -0,0 +1,45 @@
+// Ranking of beam hypotheses. The accumulated log_prob is left untouched and only the order of hypotheses changes.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ScoringPolicy {
+    // Exponent of the GNMT length penalty ((5 + length) / 6)^alpha. 0 disables length normalization.
+    pub length_alpha: f32,
+    // Added per decoded step.
+    pub insertion_bonus: f32,
+    // Weight of log(min(coverage, 1)), where coverage is the consumed fraction of the input or target. 0 disables it.
+    pub coverage_beta: f32,
+}
+
+impl Default for ScoringPolicy {
+    fn default() -> ScoringPolicy {
+        ScoringPolicy {
+            length_alpha: 0.0,
+            insertion_bonus: 0.0,
+            coverage_beta: 0.0,
+        }
+    }
+}
+
+impl ScoringPolicy {
+    pub fn new(length_alpha: f32, insertion_bonus: f32, coverage_beta: f32) -> ScoringPolicy {
+        ScoringPolicy {
+            length_alpha,
+            insertion_bonus,
+            coverage_beta,
+        }
+    }
+
+    pub fn length_penalty(&self, length: usize) -> f32 {
+        ((5 + length) as f32 / 6.0).powf(self.length_alpha)
+    }
+
+    pub fn coverage_penalty(&self, coverage: f32) -> f32 {
+        if self.coverage_beta == 0.0 {
+            return 0.0;
+        }
+        self.coverage_beta * coverage.clamp(f32::MIN_POSITIVE, 1.0).ln()
+    }
+
+    pub fn score(&self, log_prob: f32, length: usize, coverage: f32) -> f32 {
+        log_prob / self.length_penalty(length) + self.insertion_bonus * length as f32 + self.coverage_penalty(coverage)
+    }
+}
@@ -0,0 +1,381 @@
+extern crate rayon;
+
+use std::cmp::Ordering;
+use rayon::prelude::*;
+use crate::scoring::ScoringPolicy;
+use crate::diversity::DiversityPolicy;
+use crate::pruning::PruningPolicy;
+use crate::v2::{self, DurationConstraints, SsntTtsV2Cpu};
+use crate::tone_latent;
+use crate::util::{BatchView, fill_beams, log_sum_exp};
+
+
+pub struct BeamSearchDecodingTable<'a> {
+    // (W, C, D) joint log-probs of a tone class and a duration class.
+    input: &'a [f32],
+    // (W, D) duration log-probs with the tones marginalized out.
+    duration_marginal: Vec<f32>,
+    // (W)
+    log_prob_history: &'a [f32],
+    // (W)
+    total_duration: &'a [i32],
+    // The tables of the separate decoders over zero log-probs. They decide which classes are admissible at a position,
+    // and the duration table also holds the rate bias of the ranking.
+    tone: tone_latent::BeamSearchDecodingTable<'a>,
+    duration: v2::BeamSearchDecodingTable<'a>,
+    tone_class_size: usize,
+    duration_class_size: usize,
+    input_length: usize,
+    output_length: usize,
+    beam_width: usize,
+    max_beam_width: usize,
+}
+
+impl<'a> BeamSearchDecodingTable<'a> {
+    pub fn new(input: &'a [f32],
+               log_prob_history: &'a [f32],
+               total_duration: &'a [i32],
+               tone: tone_latent::BeamSearchDecodingTable<'a>,
+               duration: v2::BeamSearchDecodingTable<'a>,
+               tone_class_size: usize,
+               duration_class_size: usize,
+               input_length: usize,
+               output_length: usize,
+               beam_width: usize,
+               max_beam_width: usize) -> BeamSearchDecodingTable<'a> {
+        assert_eq!(input.len(), beam_width * tone_class_size * duration_class_size, "input: {}, beam_width: {}, tone_class_size: {}, duration_class_size: {}", input.len(), beam_width, tone_class_size, duration_class_size);
+        assert_eq!(log_prob_history.len(), beam_width);
+        assert_eq!(total_duration.len(), beam_width);
+        let duration_marginal: Vec<f32> = input.chunks(tone_class_size * duration_class_size).flat_map(|branch| {
+            (0..duration_class_size).map(move |d| {
+                let column: Vec<f32> = branch.chunks(duration_class_size).map(|row| row[d]).collect();
+                log_sum_exp(column.as_slice())
+            })
+        }).collect();
+        BeamSearchDecodingTable {
+            input,
+            duration_marginal,
+            log_prob_history,
+            total_duration,
+            tone,
+            duration,
+            tone_class_size,
+            duration_class_size,
+            input_length,
+            output_length,
+            beam_width,
+            max_beam_width,
+        }
+    }
+
+    // An empty tone outside of the tone classes scores the duration alone, marginalizing the tones.
+    fn log_prob_at(&self, w: usize, tone_class: i32, duration_class: i32) -> f32 {
+        if tone_class >= 0 && (tone_class as usize) < self.tone_class_size {
+            self.input[(w * self.tone_class_size + tone_class as usize) * self.duration_class_size + duration_class as usize]
+        } else {
+            self.duration_marginal[w * self.duration_class_size + duration_class as usize]
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct DecodeResult {
+    tone: i32,
+    duration: i32,
+    log_prob: f32,
+    next_t: usize,
+    next_u: usize,
+    is_finished: bool,
+    parent_branch: usize,
+    // Duplicate that only fills the beam up to max_beam_width.
+    pub is_padding: bool,
+    total_duration: i32,
+}
+
+impl DecodeResult {
+    // Number of steps taken, counting the finishing step.
+    fn length(&self) -> usize {
+        if self.is_finished { self.next_u + 1 } else { self.next_u }
+    }
+
+    fn eq_ignore_parent(&self, other: &DecodeResult) -> bool {
+        self.tone == other.tone &&
+            self.duration == other.duration &&
+            self.log_prob == other.log_prob &&
+            self.next_t == other.next_t &&
+            self.next_u == other.next_u &&
+            self.is_finished == other.is_finished &&
+            self.total_duration == other.total_duration
+    }
+}
+
+// Class spaces of the joint decoder and the options it shares with SsntTtsV2Cpu.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ToneDurationOptions {
+    pub tone_class_size: usize,
+    pub empty_tone_id: i32,
+    pub duration_class_size: usize,
+    pub zero_duration_id: i32,
+    pub allow_skip: bool,
+    pub test_mode: bool,
+    // Strength of the per-frame bias toward shorter classes for faster speaking rates and longer ones for slower rates.
+    pub rate_bias: f32,
+}
+
+// Decodes a tone class and a duration class per input token in one search over their product space,
+// with the tone semantics of ToneLatentCpu and the length constraints of SsntTtsV2Cpu.
+// Hypothesis merging, sampling, lattices, tone transitions, LM fusion and free-length decoding of the separate decoders are not supported.
+pub struct ToneDurationCpu {
+    batch_size: i32,
+    tone_class_size: usize,
+    empty_tone_id: i32,
+    duration_class_size: usize,
+    zero_duration_id: i32,
+    allow_skip: bool,
+    test_mode: bool,
+    constraints: DurationConstraints,
+    rate_bias: f32,
+    scoring: ScoringPolicy,
+    diversity: DiversityPolicy,
+    pruning: PruningPolicy,
+}
+
+impl ToneDurationCpu {
+    pub fn new(batch_size: i32, options: ToneDurationOptions, constraints: DurationConstraints, scoring: ScoringPolicy, diversity: DiversityPolicy, pruning: PruningPolicy) -> ToneDurationCpu {
+        ToneDurationCpu {
+            batch_size,
+            tone_class_size: options.tone_class_size,
+            empty_tone_id: options.empty_tone_id,
+            duration_class_size: options.duration_class_size,
+            zero_duration_id: options.zero_duration_id,
+            allow_skip: options.allow_skip,
+            test_mode: options.test_mode,
+            constraints,
+            rate_bias: options.rate_bias,
+            scoring,
+            diversity,
+            pruning,
+        }
+    }
+
+    // Finished beam that is not a valid hypothesis.
+    fn dead_end(&self, h: &BeamSearchDecodingTable, w: usize, t: usize, u: usize) -> DecodeResult {
+        DecodeResult {
+            tone: self.empty_tone_id,
+            duration: self.zero_duration_id,
+            log_prob: f32::NEG_INFINITY,
+            next_t: t,
+            next_u: u,
+            is_finished: true,
+            parent_branch: w,
+            is_padding: true,
+            total_duration: h.total_duration[w],
+        }
+    }
+
+    // Index of the pair in the (C, D) product space.
+    fn joint_class(&self, result: &DecodeResult) -> i32 {
+        result.tone * self.duration_class_size as i32 + result.duration
+    }
+
+    // Coverage is the fraction of the target length covered so far, or of the input if the target is unknown.
+    fn rank_score(&self, h: &BeamSearchDecodingTable, result: &DecodeResult) -> f32 {
+        let coverage = if h.output_length > 0 {
+            result.total_duration as f32 / h.output_length as f32
+        } else {
+            result.next_t as f32 / h.input_length as f32
+        };
+        self.scoring.score(result.log_prob, result.length(), coverage) + h.duration.rank_bias(result.total_duration, result.is_finished)
+    }
+
+    // Every hypothesis reachable from the current beams, best first.
+    fn expand(&self, h: &BeamSearchDecodingTable, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
+        let mut results: Vec<DecodeResult> = (0..h.beam_width)
+            .into_par_iter()
+            .flat_map(|w| {
+                let t = start_t[w];
+                let u = u[w];
+                let log_prob_history = h.log_prob_history[w];
+                self.beam_search_kernel_internal(h, w, t, u, log_prob_history)
+            }).collect();
+
+        results.sort_by(|a, b| self.rank_score(h, a).partial_cmp(&self.rank_score(h, b)).unwrap_or(Ordering::Equal).reverse());
+        results.dedup_by(|a, b| a.eq_ignore_parent(b) && self.diversity.same_group(a.parent_branch, b.parent_branch, h.beam_width));
+        results
+    }
+
+    // Chooses the next max_beam_width beams among the expanded hypotheses.
+    fn select(&self, h: &BeamSearchDecodingTable, mut results: Vec<DecodeResult>) -> Vec<DecodeResult> {
+        // Add a diagonal duration candidate to avoid empty search
+        let diagonal_result: Option<DecodeResult> = if !self.test_mode && self.constraints.use_diagonal {
+            results.iter().find(|result| !result.is_padding && h.duration.is_on_diagonal(result.next_t, result.total_duration)).cloned()
+        } else {
+            None
+        };
+        if self.diversity.is_enabled() {
+            results = self.diversity.select(results, h.beam_width, h.max_beam_width, &self.pruning,
+                                            |r| r.parent_branch,
+                                            |r| self.joint_class(r),
+                                            |r| self.rank_score(h, r),
+                                            |r| DecodeResult { is_padding: true, ..*r });
+        } else {
+            self.pruning.prune(&mut results, |r| self.rank_score(h, r));
+        }
+        fill_beams(results, diagonal_result, h.max_beam_width, |r| r.is_padding, |r| DecodeResult { is_padding: true, ..*r })
+    }
+}
+
+pub trait ToneDuration {
+    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], max_t: &[i32], max_u: &[i32], speaking_rate: &[f32], min_duration: Option<&[i32]>, max_duration: Option<&[i32]>, allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, batch_size: i32, beam_width: i32, max_beam_width: i32, tone_prediction: &mut [i32], duration_prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32], num_valid: &mut [i32]) -> ();
+
+    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult>;
+
+    fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32) -> Vec<DecodeResult>;
+}
+
+
+impl ToneDuration for ToneDurationCpu {
+    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], input_length: &[i32], output_length: &[i32], speaking_rate: &[f32], min_duration: Option<&[i32]>, max_duration: Option<&[i32]>, allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, batch_size: i32, beam_width: i32, max_beam_width: i32, tone_prediction: &mut [i32], duration_prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32], num_valid: &mut [i32]) -> () {
+        assert_eq!(tone_prediction.len(), (batch_size * max_beam_width) as usize);
+        assert_eq!(duration_prediction.len(), (batch_size * max_beam_width) as usize);
+        assert_eq!(log_probs.len(), (batch_size * beam_width) as usize);
+        assert_eq!(next_is_finished.len(), (batch_size * beam_width) as usize);
+        assert_eq!(next_total_duration.len(), (batch_size * beam_width) as usize);
+        assert_eq!(beam_branch.len(), (batch_size * beam_width) as usize);
+        assert_eq!(speaking_rate.len(), batch_size as usize);
+        // (B, T)
+        let min_duration = min_duration.map(|d| BatchView::new(batch_size as usize, d));
+        let max_duration = max_duration.map(|d| BatchView::new(batch_size as usize, d));
+        let tonal = tonal.map(|tonal| BatchView::new(batch_size as usize, tonal));
+        // (B, T, C)
+        let allowed_tones = allowed_tones.map(|mask| BatchView::new(batch_size as usize, mask));
+        let beam_width = beam_width as usize;
+        let max_beam_width = max_beam_width as usize;
+        let zero_tone_log_probs: Vec<f32> = vec![0.0; beam_width * self.tone_class_size];
+        let zero_duration_log_probs: Vec<f32> = vec![0.0; beam_width * self.duration_class_size];
+        h.par_chunks(beam_width * self.tone_class_size * self.duration_class_size)
+            .zip(log_prob_history.par_chunks(beam_width))
+            .zip(is_finished.par_chunks(beam_width))
+            .zip(total_duration.par_chunks(beam_width))
+            .zip(t.par_chunks(beam_width))
+            .zip(u.par_chunks(beam_width))
+            .zip(input_length.par_chunks(1))
+            .zip(output_length.par_chunks(1))
+            .zip(speaking_rate.par_chunks(1))
+            .zip(tone_prediction.par_chunks_mut(max_beam_width))
+            .zip(duration_prediction.par_chunks_mut(max_beam_width))
+            .zip(log_probs.par_chunks_mut(max_beam_width))
+            .zip(next_t.par_chunks_mut(max_beam_width))
+            .zip(next_u.par_chunks_mut(max_beam_width))
+            .zip(beam_branch.par_chunks_mut(max_beam_width))
+            .zip(next_is_finished.par_chunks_mut(max_beam_width))
+            .zip(next_total_duration.par_chunks_mut(max_beam_width))
+            .zip(num_valid.par_chunks_mut(1))
+            .enumerate()
+            .for_each(|(b, (((((((((((((((((h, log_prob_history), is_finished), total_duration), t), u), input_length), output_length), speaking_rate), tone_prediction), duration_prediction), log_probs), next_t), next_u), beam_branch), next_is_finished), next_total_duration), num_valid))| {
+                // The target length and the diagonal follow the speaking rate.
+                let output_length = SsntTtsV2Cpu::scaled_output_length(output_length[0], speaking_rate[0]);
+                let duration_bias = -self.rate_bias * speaking_rate[0].ln();
+                let tone = tone_latent::BeamSearchDecodingTable::new(zero_tone_log_probs.as_slice(),
+                                                                     log_prob_history,
+                                                                     is_finished,
+                                                                     &[],
+                                                                     0,
+                                                                     allowed_tones.as_ref().map(|mask| mask.batch(b)),
+                                                                     tonal.as_ref().map(|tonal| tonal.batch(b)),
+                                                                     None,
+                                                                     self.tone_class_size,
+                                                                     input_length[0] as usize,
+                                                                     beam_width,
+                                                                     max_beam_width,
+                                                                     self.empty_tone_id);
+                let duration = v2::BeamSearchDecodingTable::new(zero_duration_log_probs.as_slice(),
+                                                                log_prob_history,
+                                                                is_finished,
+                                                                total_duration,
+                                                                duration_table,
+                                                                self.duration_class_size,
+                                                                input_length[0] as usize,
+                                                                output_length,
+                                                                beam_width,
+                                                                max_beam_width,
+                                                                self.zero_duration_id,
+                                                                self.constraints,
+                                                                duration_bias,
+                                                                min_duration.as_ref().map(|d| d.batch(b)),
+                                                                max_duration.as_ref().map(|d| d.batch(b)),
+                                                                None);
+                let table = BeamSearchDecodingTable::new(h,
+                                                         log_prob_history,
+                                                         total_duration,
+                                                         tone,
+                                                         duration,
+                                                         self.tone_class_size,
+                                                         self.duration_class_size,
+                                                         input_length[0] as usize,
+                                                         output_length,
+                                                         beam_width,
+                                                         max_beam_width);
+                let t: Vec<usize> = t.iter().map(|v| *v as usize).collect();
+                let u: Vec<usize> = u.iter().map(|v| *v as usize).collect();
+                let results = self.beam_search_kernel(&table, t.as_slice(), u.as_slice());
+                results.iter().enumerate().for_each(|(i, result)| {
+                    tone_prediction[i] = result.tone;
+                    duration_prediction[i] = result.duration;
+                    log_probs[i] = result.log_prob;
+                    next_t[i] = result.next_t as i32;
+                    next_u[i] = result.next_u as i32;
+                    beam_branch[i] = result.parent_branch as i32;
+                    next_is_finished[i] = result.is_finished;
+                    next_total_duration[i] = result.total_duration;
+                });
+                num_valid[0] = results.iter().filter(|result| !result.is_padding).count() as i32;
+            });
+    }
+
+    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
+        let expanded = self.expand(h, start_t, u);
+        self.select(h, expanded)
+    }
+
+    fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32) -> Vec<DecodeResult> {
+        // Both tables finish a beam at the same position. One that admits no class at all, e.g. no duration compatible with the output length, ends the beam too.
+        let tones = h.tone.decode_beam_at(w, t).filter(|tones| !tones.is_empty());
+        let durations = h.duration.decode_beam_at(w, t, self.allow_skip, self.test_mode, false).filter(|durations| !durations.is_empty());
+        match (tones, durations) {
+            (Some(tones), Some(durations)) => {
+                tones.iter().flat_map(|tone| {
+                    durations.iter().map(move |duration| {
+                        DecodeResult {
+                            tone: tone.tone_class,
+                            duration: duration.duration_class,
+                            log_prob: log_prob_history + h.log_prob_at(w, tone.tone_class, duration.duration_class) + tone.log_prob + duration.log_prob,
+                            next_t: if duration.is_finished { t } else { t + 1 },
+                            next_u: if duration.is_finished { u } else { u + 1 },
+                            is_finished: duration.is_finished,
+                            parent_branch: w,
+                            is_padding: false,
+                            total_duration: duration.total_duration,
+                        }
+                    })
+                }).collect()
+            }
+            // A table that ends the beam while the other continues leaves no consistent pair, so the beam is a dead end.
+            (Some(_), None) | (None, Some(_)) => vec![self.dead_end(h, w, t, u)],
+            // End of input. Return values to fill padding region.
+            (None, None) => {
+                vec![DecodeResult {
+                    tone: self.empty_tone_id,
+                    duration: self.zero_duration_id,
+                    log_prob: log_prob_history,
+                    next_t: t,
+                    next_u: u,
+                    is_finished: true,
+                    parent_branch: w,
+                    is_padding: false,
+                    total_duration: h.total_duration[w],
+                }]
+            }
+        }
+    }
+}
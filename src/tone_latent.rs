@@ -2,30 +2,49 @@ extern crate rayon;
 
 use std::cmp::Ordering;
 use rayon::prelude::*;
+use crate::scoring::ScoringPolicy;
+use crate::diversity::DiversityPolicy;
+use crate::pruning::PruningPolicy;
+use crate::sampling::{SamplingPolicy, beam_rng};
+use crate::lattice::{Lattice, LatticeNode, lattices_per_batch};
+use crate::ngram::{LmFusion, EOS_ID};
+use crate::util::{BatchView, fill_beams, log_sum_exp};
 
 
-struct BatchView<'a, T> {
-    batch_offset: usize,
-    data: &'a [T],
+// Row of a (T, C) tone mask at position t. None if the position is beyond the mask or has no class set.
+fn tone_mask_at(mask: Option<&[bool]>, t: usize, tone_class_size: usize) -> Option<&[bool]> {
+    mask.filter(|mask| (t + 1) * tone_class_size <= mask.len())
+        .map(|mask| &mask[t * tone_class_size..(t + 1) * tone_class_size])
+        .filter(|row| row.iter().any(|v| *v))
 }
 
-impl<'a, T> BatchView<'a, T> {
-    fn new(batch_size: usize, data: &'a [T]) -> BatchView<'a, T> {
-        BatchView {
-            batch_offset: data.len() / batch_size,
-            data,
-        }
-    }
+// Allowed tones of a position once the tonal mask is applied. The tonal mask takes precedence:
+// a row that only allows the empty tone at a tonal position is ignored like a row without any class set,
+// and rows of non-tonal positions are not used at all since those only take the empty tone.
+fn admitted_tones_at(allowed_tones: Option<&[bool]>, is_tonal: Option<bool>, empty_tone_id: i32, t: usize, tone_class_size: usize) -> Option<&[bool]> {
+    tone_mask_at(allowed_tones, t, tone_class_size)
+        .filter(|allowed| is_tonal != Some(true) || allowed.iter().enumerate().any(|(i, a)| *a && i as i32 != empty_tone_id))
+}
 
-    fn batch(&self, index: usize) -> &[T] {
-        self.data[index * self.batch_offset..(index + 1) * self.batch_offset].as_ref()
+// Whether tone class c passes the masks of a position. Non-tonal positions only take empty_tone_id, and tonal ones never do.
+fn admits_tone(admitted_tones: Option<&[bool]>, is_tonal: Option<bool>, empty_tone_id: i32, c: usize) -> bool {
+    let is_empty = c as i32 == empty_tone_id;
+    match is_tonal {
+        Some(false) => is_empty,
+        Some(true) if is_empty => false,
+        _ => admitted_tones.is_none_or(|admitted| admitted[c]),
     }
 }
 
-struct DecodingTable {
-    log_prob: f32,
-    tone_class: i32,
-    is_finished: bool,
+fn is_tonal_at(tonal: Option<&[bool]>, t: usize) -> Option<bool> {
+    tonal.and_then(|tonal| tonal.get(t).cloned())
+}
+
+// Candidate class of one beam that passes the constraints of the position.
+pub struct DecodingTable {
+    pub log_prob: f32,
+    pub tone_class: i32,
+    pub is_finished: bool,
 }
 
 pub struct BeamSearchDecodingTable<'a> {
@@ -35,6 +54,15 @@ pub struct BeamSearchDecodingTable<'a> {
     log_prob_history: &'a [f32],
     // (W)
     is_finished: &'a [bool],
+    // (W, K) preceding tones of each beam for the fused LM.
+    lm_context: &'a [i32],
+    lm_context_size: usize,
+    // (T, C) tone classes allowed at each input position. The tonal mask takes precedence where the two conflict.
+    allowed_tones: Option<&'a [bool]>,
+    // (T) whether each input position carries a tone. Non-tonal positions only take empty_tone_id, and tonal ones never do.
+    tonal: Option<&'a [bool]>,
+    // (W) last tone of each beam, negative before the first one.
+    previous_tone: Option<&'a [i32]>,
     tone_class_size: usize,
     input_length: usize,
     beam_width: usize,
@@ -46,6 +74,11 @@ impl<'a> BeamSearchDecodingTable<'a> {
     pub fn new(input: &'a [f32],
                log_prob_history: &'a [f32],
                is_finished: &'a [bool],
+               lm_context: &'a [i32],
+               lm_context_size: usize,
+               allowed_tones: Option<&'a [bool]>,
+               tonal: Option<&'a [bool]>,
+               previous_tone: Option<&'a [i32]>,
                tone_class_size: usize,
                input_length: usize,
                beam_width: usize,
@@ -54,10 +87,17 @@ impl<'a> BeamSearchDecodingTable<'a> {
         assert_eq!(input.len(), beam_width * tone_class_size, "input: {}, beam_width: {}, tone_class_size: {}", input.len(), beam_width, tone_class_size);
         assert_eq!(log_prob_history.len(), beam_width);
         assert_eq!(is_finished.len(), beam_width);
+        assert_eq!(lm_context.len(), beam_width * lm_context_size);
+        assert!(previous_tone.is_none_or(|p| p.len() == beam_width));
         BeamSearchDecodingTable {
             input,
             log_prob_history,
             is_finished,
+            lm_context,
+            lm_context_size,
+            allowed_tones,
+            tonal,
+            previous_tone,
             tone_class_size,
             input_length,
             beam_width,
@@ -72,11 +112,30 @@ impl<'a> BeamSearchDecodingTable<'a> {
         &self.input[start..start + size]
     }
 
+    fn lm_context(&self, w: usize) -> &'a [i32] {
+        let size = self.lm_context_size;
+        &self.lm_context[w * size..(w + 1) * size]
+    }
+
+    fn previous_tone(&self, w: usize) -> Option<usize> {
+        self.previous_tone.map(|p| p[w]).filter(|p| *p >= 0).map(|p| p as usize)
+    }
+
     fn is_defined_at(&self, t: usize) -> bool {
         t < self.input_length
     }
 
-    fn decode_beam_at(&self, w: usize, t: usize) -> Option<Vec<DecodingTable>> {
+    // A position without any allowed class is left unconstrained rather than making every beam a dead end.
+    fn allowed_tones_at(&self, t: usize) -> Option<&'a [bool]> {
+        admitted_tones_at(self.allowed_tones, self.is_tonal_at(t), self.empty_tone_id, t, self.tone_class_size)
+    }
+
+    fn is_tonal_at(&self, t: usize) -> Option<bool> {
+        is_tonal_at(self.tonal, t)
+    }
+
+    // The beam finishes with the tone of the last input position.
+    pub fn decode_beam_at(&self, w: usize, t: usize) -> Option<Vec<DecodingTable>> {
         if !self.is_defined_at(t) {
             return None;
         }
@@ -84,11 +143,26 @@ impl<'a> BeamSearchDecodingTable<'a> {
             return None;
         }
         let branch: &[f32] = self.beam_branch(w);
+        let is_final = t + 1 == self.input_length;
+        let is_tonal = self.is_tonal_at(t);
+        if is_tonal == Some(false) {
+            // An empty tone outside of the tone classes has no probability of its own.
+            let log_prob = branch.get(self.empty_tone_id as usize).cloned().unwrap_or(0.0);
+            return Some(vec![DecodingTable {
+                log_prob,
+                tone_class: self.empty_tone_id,
+                is_finished: is_final,
+            }]);
+        }
+        let allowed_tones = self.allowed_tones_at(t);
         let input_copy: Vec<DecodingTable> = branch.iter().enumerate().filter_map(|(i, v)| {
+            if !admits_tone(allowed_tones, is_tonal, self.empty_tone_id, i) {
+                return None;
+            }
             Some(DecodingTable {
                 log_prob: *v,
                 tone_class: i as i32,
-                is_finished: false,
+                is_finished: is_final,
             })
         }).collect();
         Some(input_copy)
@@ -103,9 +177,16 @@ pub struct DecodeResult {
     next_u: usize,
     is_finished: bool,
     parent_branch: usize,
+    // Duplicate that only fills the beam up to max_beam_width.
+    pub is_padding: bool,
 }
 
 impl DecodeResult {
+    // Number of steps taken, counting the finishing step.
+    fn length(&self) -> usize {
+        if self.is_finished { self.next_u + 1 } else { self.next_u }
+    }
+
     fn eq_ignore_parent(&self, other: &DecodeResult) -> bool {
         self.prediction == other.prediction &&
             self.log_prob == other.log_prob &&
@@ -119,33 +200,133 @@ pub struct ToneLatentCpu {
     batch_size: i32,
     tone_class_size: usize,
     empty_tone_id: i32,
+    scoring: ScoringPolicy,
+    diversity: DiversityPolicy,
+    pruning: PruningPolicy,
+    fusion: Option<LmFusion>,
+    // (C, C) log-probs of a tone given the previous one, row by previous tone.
+    transition: Option<Vec<f32>>,
 }
 
 impl ToneLatentCpu {
-    pub fn new(batch_size: i32, tone_class_size: usize, empty_tone_id: i32) -> ToneLatentCpu {
+    pub fn new(batch_size: i32, tone_class_size: usize, empty_tone_id: i32, scoring: ScoringPolicy, diversity: DiversityPolicy, pruning: PruningPolicy, fusion: Option<LmFusion>, transition: Option<Vec<f32>>) -> ToneLatentCpu {
+        assert!(transition.as_ref().is_none_or(|m| m.len() == tone_class_size * tone_class_size));
         ToneLatentCpu {
             batch_size,
             tone_class_size,
             empty_tone_id,
+            scoring,
+            diversity,
+            pruning,
+            fusion,
+            transition,
         }
     }
-}
 
-pub trait ToneLatent {
-    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], max_t: &[i32], batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32]) -> ();
+    // Number of preceding tones each beam carries for the fused LM. 0 without an LM.
+    pub fn lm_context_size(&self) -> usize {
+        self.fusion.as_ref().map_or(0, |fusion| fusion.context_size())
+    }
 
-    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult>;
+    // A beam finishing with the token also pays for the end of sentence after it.
+    fn lm_log_prob(&self, h: &BeamSearchDecodingTable, w: usize, token: i32, is_finished: bool) -> f32 {
+        self.fusion.as_ref().map_or(0.0, |fusion| {
+            let context = h.lm_context(w);
+            let log_prob = fusion.log_prob(context, token);
+            if !is_finished {
+                return log_prob;
+            }
+            let mut next_context: Vec<i32> = vec![0; context.len()];
+            LmFusion::next_context(context, token, next_context.as_mut_slice());
+            log_prob + fusion.log_prob(next_context.as_slice(), EOS_ID)
+        })
+    }
 
-    fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32) -> Vec<DecodeResult>;
-}
+    // Transition from the previous tone of beam w. 0 for the first tone or without a transition matrix.
+    fn transition_log_prob(&self, h: &BeamSearchDecodingTable, w: usize, tone: i32) -> f32 {
+        match (&self.transition, h.previous_tone(w)) {
+            // The empty tone has no row or column if it is not one of the tone classes.
+            (Some(transition), Some(previous)) if previous < self.tone_class_size && (tone as usize) < self.tone_class_size => {
+                transition[previous * self.tone_class_size + tone as usize]
+            }
+            _ => 0.0,
+        }
+    }
 
+    // Coverage is the fraction of the input decoded so far.
+    fn rank_score(&self, h: &BeamSearchDecodingTable, result: &DecodeResult) -> f32 {
+        let coverage = result.next_t as f32 / h.input_length as f32;
+        self.scoring.score(result.log_prob, result.length(), coverage)
+    }
 
-impl ToneLatent for ToneLatentCpu {
-    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], input_length: &[i32], batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32]) -> () {
+    // Every hypothesis reachable from the current beams, best first.
+    fn expand(&self, h: &BeamSearchDecodingTable, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
+        let mut results: Vec<DecodeResult> = (0..h.beam_width)
+            .into_par_iter()
+            .flat_map(|w| {
+                let t = start_t[w];
+                let u = u[w];
+                let log_prob_history = h.log_prob_history[w];
+                self.beam_search_kernel_internal(h, w, t, u, log_prob_history)
+            }).collect();
+
+        // Here the sorting does not consider prefixes. This is because we are interested in intermediate features which is path dependent.
+        results.sort_by(|a, b| self.rank_score(h, a).partial_cmp(&self.rank_score(h, b)).unwrap_or(Ordering::Equal).reverse());
+        results.dedup_by(|a, b| a.eq_ignore_parent(b) && self.diversity.same_group(a.parent_branch, b.parent_branch, h.beam_width));
+        results
+    }
+
+    // Chooses the next max_beam_width beams among the expanded hypotheses.
+    fn select(&self, h: &BeamSearchDecodingTable, mut results: Vec<DecodeResult>) -> Vec<DecodeResult> {
+        if self.diversity.is_enabled() {
+            results = self.diversity.select(results, h.beam_width, h.max_beam_width, &self.pruning,
+                                            |r| r.parent_branch,
+                                            |r| r.prediction,
+                                            |r| self.rank_score(h, r),
+                                            |r| DecodeResult { is_padding: true, ..*r });
+        } else {
+            self.pruning.prune(&mut results, |r| self.rank_score(h, r));
+        }
+        fill_beams(results, None, h.max_beam_width, |r| r.is_padding, |r| DecodeResult { is_padding: true, ..*r })
+    }
+
+    // beam_search_kernel that also records the step into the lattice if one is given.
+    fn beam_search_step(&self, h: &BeamSearchDecodingTable, start_t: &[usize], u: &[usize], lattice: Option<&mut Lattice>) -> Vec<DecodeResult> {
+        match lattice {
+            None => self.beam_search_kernel(h, start_t, u),
+            Some(lattice) => {
+                let expanded = self.expand(h, start_t, u);
+                let results = self.select(h, expanded.clone());
+                let candidates: Vec<(usize, LatticeNode)> = expanded.iter().map(|r| {
+                    let w = r.parent_branch;
+                    (w, LatticeNode::new(r.prediction, r.log_prob, self.rank_score(h, r), start_t[w], u[w], 0, r.is_finished))
+                }).collect();
+                let selected: Vec<usize> = results.iter().map(|r| {
+                    expanded.iter().position(|e| e.eq_ignore_parent(r) && e.parent_branch == r.parent_branch)
+                        .expect("selected hypothesis must be expanded")
+                }).collect();
+                lattice.push_step(candidates, selected.as_slice());
+                results
+            }
+        }
+    }
+
+    // Runs one decoding step per batch item with the table built from the batched inputs.
+    fn decode_step<F>(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], lm_context: &[i32], input_length: &[i32], allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, previous_tone: Option<&[i32]>, batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32], next_lm_context: &mut [i32], num_valid: &mut [i32], lattices: Option<&mut [Lattice]>, kernel: F) where F: Fn(usize, &BeamSearchDecodingTable, &[usize], &[usize], Option<&mut Lattice>) -> Vec<DecodeResult> + Sync {
         assert_eq!(prediction.len(), (batch_size * max_beam_width) as usize);
         assert_eq!(log_probs.len(), (batch_size * beam_width) as usize);
         assert_eq!(next_is_finished.len(), (batch_size * beam_width) as usize);
         assert_eq!(beam_branch.len(), (batch_size * beam_width) as usize);
+        let lm_context_size = self.lm_context_size();
+        assert_eq!(lm_context.len(), batch_size as usize * beam_width as usize * lm_context_size);
+        assert_eq!(next_lm_context.len(), batch_size as usize * max_beam_width as usize * lm_context_size);
+        // (B, T, C)
+        let allowed_tones = allowed_tones.map(|mask| BatchView::new(batch_size as usize, mask));
+        // (B, T)
+        let tonal = tonal.map(|tonal| BatchView::new(batch_size as usize, tonal));
+        // (B, W)
+        let previous_tone = previous_tone.map(|p| BatchView::new(batch_size as usize, p));
+        let mut lattices = lattices_per_batch(lattices, batch_size as usize);
         h.par_chunks(beam_width as usize * self.tone_class_size)
             .zip(log_prob_history.par_chunks(beam_width as usize))
             .zip(is_finished.par_chunks(beam_width as usize))
@@ -158,10 +339,19 @@ impl ToneLatent for ToneLatentCpu {
             .zip(next_u.par_chunks_mut(max_beam_width as usize))
             .zip(beam_branch.par_chunks_mut(max_beam_width as usize))
             .zip(next_is_finished.par_chunks_mut(max_beam_width as usize))
-            .for_each(|(((((((((((h, log_prob_history), is_finished), t), u), input_length), prediction), log_probs), next_t), next_u), beam_branch), next_is_finished)| {
+            .zip(num_valid.par_chunks_mut(1))
+            .zip(lattices.par_iter_mut())
+            .enumerate()
+            .for_each(|(b, (((((((((((((h, log_prob_history), is_finished), t), u), input_length), prediction), log_probs), next_t), next_u), beam_branch), next_is_finished), num_valid), lattice))| {
+                let lm_context_len = beam_width as usize * lm_context_size;
                 let table = BeamSearchDecodingTable::new(h,
                                                          log_prob_history,
                                                          is_finished,
+                                                         &lm_context[b * lm_context_len..(b + 1) * lm_context_len],
+                                                         lm_context_size,
+                                                         allowed_tones.as_ref().map(|mask| mask.batch(b)),
+                                                         tonal.as_ref().map(|tonal| tonal.batch(b)),
+                                                         previous_tone.as_ref().map(|p| p.batch(b)),
                                                          self.tone_class_size,
                                                          input_length[0] as usize,
                                                          beam_width as usize,
@@ -169,7 +359,7 @@ impl ToneLatent for ToneLatentCpu {
                                                          self.empty_tone_id);
                 let t: Vec<usize> = t.iter().map(|v| *v as usize).collect();
                 let u: Vec<usize> = u.iter().map(|v| *v as usize).collect();
-                let results = self.beam_search_kernel(&table, t.as_slice(), u.as_slice());
+                let results = kernel(b, &table, t.as_slice(), u.as_slice(), lattice.as_deref_mut());
                 results.iter().enumerate().for_each(|(i, result)| {
                     prediction[i] = result.prediction;
                     log_probs[i] = result.log_prob;
@@ -178,58 +368,241 @@ impl ToneLatent for ToneLatentCpu {
                     beam_branch[i] = result.parent_branch as i32;
                     next_is_finished[i] = result.is_finished;
                 });
+                num_valid[0] = results.iter().filter(|result| !result.is_padding).count() as i32;
             });
+        // Each beam takes over the LM context of its parent, extended with the new tone unless the parent had already finished.
+        if lm_context_size > 0 {
+            for b in 0..batch_size as usize {
+                for i in 0..max_beam_width as usize {
+                    let parent = b * beam_width as usize + beam_branch[b * max_beam_width as usize + i] as usize;
+                    let context = &lm_context[parent * lm_context_size..(parent + 1) * lm_context_size];
+                    let start = (b * max_beam_width as usize + i) * lm_context_size;
+                    let next_context = &mut next_lm_context[start..start + lm_context_size];
+                    if is_finished[parent] {
+                        next_context.copy_from_slice(context);
+                    } else {
+                        LmFusion::next_context(context, prediction[b * max_beam_width as usize + i], next_context);
+                    }
+                }
+            }
+        }
     }
+}
 
-    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
-        let mut results: Vec<DecodeResult> = (0..h.beam_width)
-            .into_par_iter()
-            .flat_map(|w| {
-                let t = start_t[w];
-                let u = u[w];
-                let log_prob_history = h.log_prob_history[w];
-                self.beam_search_kernel_internal(h, w, t, u, log_prob_history)
-            }).collect();
+pub trait ToneLatent {
+    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], lm_context: &[i32], max_t: &[i32], allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, previous_tone: Option<&[i32]>, batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32], next_lm_context: &mut [i32], num_valid: &mut [i32], lattices: Option<&mut [Lattice]>) -> ();
 
-        // Here the sorting does not consider prefixes. This is because we are interested in intermediate features which is path dependent.
-        results.sort_by(|a, b| a.log_prob.partial_cmp(&b.log_prob).unwrap_or(Ordering::Equal).reverse());
-        results.dedup_by(|a, b| a.eq_ignore_parent(b));
+    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult>;
 
-        let n_results: usize = results.len();
-        if n_results < h.max_beam_width {
-            for i in 0..(h.max_beam_width - n_results) {
-                results.push(results[i % n_results].clone());
-            }
-        }
-        results.truncate(h.max_beam_width);
-        results
+    fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32) -> Vec<DecodeResult>;
+
+    fn sample_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], lm_context: &[i32], max_t: &[i32], allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, previous_tone: Option<&[i32]>, batch_size: i32, beam_width: i32, policy: SamplingPolicy, seed: u64, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32], next_lm_context: &mut [i32]) -> ();
+
+    fn sample_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize], policy: SamplingPolicy, seed: u64, b: usize) -> Vec<DecodeResult>;
+}
+
+
+impl ToneLatent for ToneLatentCpu {
+    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], lm_context: &[i32], input_length: &[i32], allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, previous_tone: Option<&[i32]>, batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32], next_lm_context: &mut [i32], num_valid: &mut [i32], lattices: Option<&mut [Lattice]>) -> () {
+        self.decode_step(h, log_prob_history, is_finished, t, u, lm_context, input_length, allowed_tones, tonal, previous_tone, batch_size, beam_width, max_beam_width, prediction, log_probs, next_t, next_u, next_is_finished, beam_branch, next_lm_context, num_valid, lattices, |_, table, t, u, lattice| self.beam_search_step(table, t, u, lattice));
+    }
+
+    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
+        let expanded = self.expand(h, start_t, u);
+        self.select(h, expanded)
     }
 
     fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32) -> Vec<DecodeResult> {
         match h.decode_beam_at(w, t) {
             // End of input. Return values to fill padding region.
             None => {
+                // A beam finishing here also pays the fused LM for the end of sentence.
+                let log_prob = if h.is_finished[w] { log_prob_history } else { log_prob_history + self.lm_log_prob(h, w, EOS_ID, false) };
                 vec![DecodeResult {
                     prediction: self.empty_tone_id,
-                    log_prob: log_prob_history,
+                    log_prob,
                     next_t: t,
                     next_u: u,
                     is_finished: true,
                     parent_branch: w,
+                    is_padding: false,
                 }]
             }
             Some(results) => {
                 results.into_iter().map(|v| {
                     DecodeResult {
                         prediction: v.tone_class,
-                        log_prob: log_prob_history + v.log_prob,
+                        log_prob: log_prob_history + v.log_prob + self.transition_log_prob(h, w, v.tone_class) + self.lm_log_prob(h, w, v.tone_class, v.is_finished),
                         next_t: if v.is_finished { t } else { t + 1 },
                         next_u: if v.is_finished { u } else { u + 1 },
                         is_finished: v.is_finished,
                         parent_branch: w,
+                        is_padding: false,
                     }
                 }).collect()
             }
         }
     }
-}
\ No newline at end of file
+
+    fn sample_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], t: &[i32], u: &[i32], lm_context: &[i32], input_length: &[i32], allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, previous_tone: Option<&[i32]>, batch_size: i32, beam_width: i32, policy: SamplingPolicy, seed: u64, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], beam_branch: &mut [i32], next_lm_context: &mut [i32]) -> () {
+        // Every sampled beam is a real hypothesis.
+        let mut num_valid: Vec<i32> = vec![0; batch_size as usize];
+        self.decode_step(h, log_prob_history, is_finished, t, u, lm_context, input_length, allowed_tones, tonal, previous_tone, batch_size, beam_width, beam_width, prediction, log_probs, next_t, next_u, next_is_finished, beam_branch, next_lm_context, num_valid.as_mut_slice(), None,
+                         |b, table, t, u, _| self.sample_kernel(table, t, u, policy, seed, b));
+    }
+
+    // Every beam draws its own next tone class, so beams stay in place.
+    fn sample_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize], policy: SamplingPolicy, seed: u64, b: usize) -> Vec<DecodeResult> {
+        (0..h.beam_width)
+            .into_par_iter()
+            .map(|w| {
+                let t = start_t[w];
+                let u = u[w];
+                let log_prob_history = h.log_prob_history[w];
+                let candidates = self.beam_search_kernel_internal(h, w, t, u, log_prob_history);
+                let log_probs: Vec<f32> = candidates.iter().map(|result| result.log_prob - log_prob_history).collect();
+                match policy.sample(&mut beam_rng(seed, b, w, u), log_probs.as_slice()) {
+                    Some(i) => candidates[i],
+                    // A beam without any candidate is dropped by finishing it with zero probability.
+                    None => DecodeResult {
+                        prediction: self.empty_tone_id,
+                        log_prob: f32::NEG_INFINITY,
+                        next_t: t,
+                        next_u: u,
+                        is_finished: true,
+                        parent_branch: w,
+                        is_padding: false,
+                    },
+                }
+            }).collect()
+    }
+}
+
+// Marginal log-likelihood of the tone sequence and posterior tone distributions per position, summing over every tone class
+// that is not clamped by the observed mask. Positions are independent given the (B, T, C) log-probs.
+// observed_tones is an optional (B, T, C) mask where a position with any class set only admits those classes, and a position without is unobserved.
+// tonal is an optional (B, T) mask that restricts positions like it does in beam search.
+pub fn tone_marginal(h: &[f32], input_length: &[i32], observed_tones: Option<&[bool]>, tonal: Option<&[bool]>, empty_tone_id: i32, batch_size: usize, max_t: usize, tone_class_size: usize, log_likelihood: &mut [f32], posterior: &mut [f32]) {
+    assert_eq!(h.len(), batch_size * max_t * tone_class_size);
+    assert_eq!(input_length.len(), batch_size);
+    assert_eq!(log_likelihood.len(), batch_size);
+    assert_eq!(posterior.len(), h.len());
+    // (B, T, C)
+    let observed_tones = observed_tones.map(|mask| BatchView::new(batch_size, mask));
+    // (B, T)
+    let tonal = tonal.map(|tonal| BatchView::new(batch_size, tonal));
+    h.par_chunks(max_t * tone_class_size)
+        .zip(input_length.par_chunks(1))
+        .zip(log_likelihood.par_chunks_mut(1))
+        // (B, T, C)
+        .zip(posterior.par_chunks_mut(max_t * tone_class_size))
+        .enumerate()
+        .for_each(|(b, (((h, input_length), log_likelihood), posterior))| {
+            let input_length = input_length[0] as usize;
+            let (marginal, gamma) = tone_marginal_kernel(&h[..input_length * tone_class_size], observed_tones.as_ref().map(|mask| mask.batch(b)), tonal.as_ref().map(|tonal| tonal.batch(b)), empty_tone_id, tone_class_size);
+            log_likelihood[0] = marginal;
+            // Padding region is filled with zeros.
+            posterior.iter_mut().for_each(|v| *v = 0.0);
+            posterior[..gamma.len()].copy_from_slice(gamma.as_slice());
+        });
+}
+
+// Log-likelihood and (T, C) posteriors of a single item whose log-probs cover exactly its input positions.
+// Classes excluded by the masks have zero posterior, and so does every class of a position without any probability mass.
+// A non-tonal position with an empty tone outside of the tone classes has probability one and zero posteriors, as in beam search.
+pub fn tone_marginal_kernel(h: &[f32], observed_tones: Option<&[bool]>, tonal: Option<&[bool]>, empty_tone_id: i32, tone_class_size: usize) -> (f32, Vec<f32>) {
+    let mut log_likelihood = 0.0;
+    let mut posterior: Vec<f32> = vec![0.0; h.len()];
+    h.chunks(tone_class_size)
+        .zip(posterior.chunks_mut(tone_class_size))
+        .enumerate()
+        .for_each(|(t, (log_probs, posterior))| {
+            let is_tonal = is_tonal_at(tonal, t);
+            if is_tonal == Some(false) && empty_tone_id as usize >= tone_class_size {
+                return;
+            }
+            let observed = admitted_tones_at(observed_tones, is_tonal, empty_tone_id, t, tone_class_size);
+            let admitted: Vec<f32> = log_probs.iter().enumerate()
+                .map(|(c, v)| if admits_tone(observed, is_tonal, empty_tone_id, c) { *v } else { f32::NEG_INFINITY })
+                .collect();
+            let marginal = log_sum_exp(admitted.as_slice());
+            log_likelihood += marginal;
+            if marginal.is_finite() {
+                posterior.iter_mut().zip(admitted.iter()).for_each(|(p, v)| *p = (v - marginal).exp());
+            }
+        });
+    (log_likelihood, posterior)
+}
+
+
+// Exact best tone sequence under the per-position log-probs and an optional (C, C) transition matrix, which beam search only approximates.
+// prediction is (B, T) padded with -1, and log_prob is the score of the best sequence.
+// allowed_tones (B, T, C) and tonal (B, T) restrict positions like they do in beam search.
+pub fn tone_viterbi_decode(h: &[f32], input_length: &[i32], transition: Option<&[f32]>, allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, empty_tone_id: i32, batch_size: usize, max_t: usize, tone_class_size: usize, prediction: &mut [i32], log_prob: &mut [f32]) {
+    assert_eq!(h.len(), batch_size * max_t * tone_class_size);
+    assert_eq!(input_length.len(), batch_size);
+    assert!(transition.is_none_or(|m| m.len() == tone_class_size * tone_class_size));
+    assert_eq!(prediction.len(), batch_size * max_t);
+    assert_eq!(log_prob.len(), batch_size);
+    // (B, T, C)
+    let allowed_tones = allowed_tones.map(|mask| BatchView::new(batch_size, mask));
+    // (B, T)
+    let tonal = tonal.map(|tonal| BatchView::new(batch_size, tonal));
+    h.par_chunks(max_t * tone_class_size)
+        .zip(input_length.par_chunks(1))
+        // (B, T)
+        .zip(prediction.par_chunks_mut(max_t))
+        .zip(log_prob.par_chunks_mut(1))
+        .enumerate()
+        .for_each(|(b, (((h, input_length), prediction), log_prob))| {
+            let input_length = input_length[0] as usize;
+            let (best, best_log_prob) = tone_viterbi_kernel(&h[..input_length * tone_class_size], transition, allowed_tones.as_ref().map(|mask| mask.batch(b)), tonal.as_ref().map(|tonal| tonal.batch(b)), empty_tone_id, tone_class_size);
+            prediction.iter_mut().for_each(|p| *p = -1);
+            prediction[..best.len()].copy_from_slice(best.as_slice());
+            log_prob[0] = best_log_prob;
+        });
+}
+
+pub fn tone_viterbi_kernel(h: &[f32], transition: Option<&[f32]>, allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>, empty_tone_id: i32, tone_class_size: usize) -> (Vec<i32>, f32) {
+    let input_length = h.len() / tone_class_size;
+    if input_length == 0 {
+        return (vec![], 0.0);
+    }
+    // An empty tone outside of the tone classes is an extra state with no probability of its own and no transitions, as in beam search.
+    let empty_state = tonal.is_some() && empty_tone_id as usize >= tone_class_size;
+    let state_size = if empty_state { tone_class_size + 1 } else { tone_class_size };
+    let emission = |t: usize, c: usize| -> f32 {
+        let is_tonal = is_tonal_at(tonal, t);
+        if c == tone_class_size {
+            return if is_tonal == Some(false) { 0.0 } else { f32::NEG_INFINITY };
+        }
+        let allowed = admitted_tones_at(allowed_tones, is_tonal, empty_tone_id, t, tone_class_size);
+        if admits_tone(allowed, is_tonal, empty_tone_id, c) { h[t * tone_class_size + c] } else { f32::NEG_INFINITY }
+    };
+    let transition_at = |previous: usize, c: usize| -> f32 {
+        match transition {
+            Some(m) if previous < tone_class_size && c < tone_class_size => m[previous * tone_class_size + c],
+            _ => 0.0,
+        }
+    };
+    let mut delta: Vec<f32> = (0..state_size).map(|c| emission(0, c)).collect();
+    // (T, S) best previous state of every state.
+    let mut back_pointer: Vec<usize> = vec![0; input_length * state_size];
+    for t in 1..input_length {
+        delta = (0..state_size).map(|c| {
+            let (previous, score) = (0..state_size)
+                .map(|p| (p, delta[p] + transition_at(p, c)))
+                .fold((0, f32::NEG_INFINITY), |best, v| if v.1 > best.1 { v } else { best });
+            back_pointer[t * state_size + c] = previous;
+            score + emission(t, c)
+        }).collect();
+    }
+    let (mut state, best_log_prob) = delta.iter().cloned().enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, v| if v.1 > best.1 { v } else { best });
+    let mut prediction: Vec<i32> = vec![0; input_length];
+    for t in (0..input_length).rev() {
+        prediction[t] = if state == tone_class_size { empty_tone_id } else { state as i32 };
+        state = back_pointer[t * state_size + state];
+    }
+    (prediction, best_log_prob)
+}
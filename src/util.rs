@@ -2,6 +2,53 @@ extern crate rayon;
 
 use rayon::prelude::*;
 use std::collections::VecDeque;
+use std::f32;
+
+
+// Per-item slices of a batched input that is laid out item by item.
+pub struct BatchView<'a, T> {
+    batch_offset: usize,
+    data: &'a [T],
+}
+
+impl<'a, T> BatchView<'a, T> {
+    pub fn new(batch_size: usize, data: &'a [T]) -> BatchView<'a, T> {
+        BatchView {
+            batch_offset: data.len() / batch_size,
+            data,
+        }
+    }
+
+    pub fn batch(&self, index: usize) -> &[T] {
+        self.data[index * self.batch_offset..(index + 1) * self.batch_offset].as_ref()
+    }
+}
+
+// Fills max_beam_width beams from the selected results, keeping their order.
+// A reserved candidate, such as the diagonal one of the duration decoders, takes the last beam, and only counts as a real hypothesis if it is not kept already.
+// Real hypotheses come before the padding, which copies the results in turn. Empty results stay empty.
+pub fn fill_beams<T, FP, FD>(mut results: Vec<T>, reserved: Option<T>, max_beam_width: usize, is_padding: FP, padding: FD) -> Vec<T>
+    where T: Copy + PartialEq, FP: Fn(&T) -> bool, FD: Fn(&T) -> T {
+    if let Some(reserved) = reserved {
+        results.truncate(max_beam_width - 1);
+        let is_kept = results.iter().any(|r| !is_padding(r) && *r == reserved);
+        if is_kept {
+            results.push(padding(&reserved));
+        } else {
+            let position = results.iter().position(&is_padding).unwrap_or(results.len());
+            results.insert(position, reserved);
+        }
+    }
+    let n_results = results.len();
+    if n_results > 0 && n_results < max_beam_width {
+        for i in 0..(max_beam_width - n_results) {
+            let copy = padding(&results[i % n_results]);
+            results.push(copy);
+        }
+    }
+    results.truncate(max_beam_width);
+    results
+}
 
 pub fn extract_best_beam_branch(best_final_branch: &[i32], beam_branch: &[i32], t_history: &[i32], beam_width: i32, max_u: i32, best_beam_branch: &mut [i32], best_t_history: &mut [i32]) {
     best_final_branch.par_chunks(1)
@@ -30,4 +77,104 @@ pub fn extract_best_beam_branch_kernel(best_final_branch: i32, beam_branch: &[i3
             prev_branch
         });
     (Vec::from(branch_buf), Vec::from(t_buf))
-}
\ No newline at end of file
+}
+
+// Backtracks the best beam of each batch item up to its final step (B), which is inclusive.
+// Steps after the final one are filled with pad_value in every output, and so is the whole path when the final step is negative.
+pub fn extract_best_path(best_final_branch: &[i32], final_step: &[i32], beam_branch: &[i32], t_history: &[i32], prediction: &[i32], beam_width: i32, max_u: i32, pad_value: i32,
+                         best_beam_branch: &mut [i32], best_t_history: &mut [i32], best_prediction: &mut [i32]) {
+    let history_size = (max_u * beam_width) as usize;
+    best_final_branch.par_chunks(1)
+        .zip(final_step.par_chunks(1))
+        .zip(beam_branch.par_chunks(history_size))
+        .zip(t_history.par_chunks(history_size))
+        .zip(prediction.par_chunks(history_size))
+        .zip(best_beam_branch.par_chunks_mut(max_u as usize))
+        .zip(best_t_history.par_chunks_mut(max_u as usize))
+        .zip(best_prediction.par_chunks_mut(max_u as usize))
+        .for_each(|(((((((best_final_branch, final_step), beam_branch), t_history), prediction), best_beam_branch), best_t_history), best_prediction)| {
+            let (beam_branch, t_history, prediction) = extract_best_path_kernel(best_final_branch[0], final_step[0], beam_branch, t_history, prediction, beam_width, max_u, pad_value);
+            best_beam_branch.copy_from_slice(beam_branch.as_slice());
+            best_t_history.copy_from_slice(t_history.as_slice());
+            best_prediction.copy_from_slice(prediction.as_slice());
+        });
+}
+
+pub fn extract_best_path_kernel(best_final_branch: i32, final_step: i32, beam_branch: &[i32], t_history: &[i32], prediction: &[i32], beam_width: i32, max_u: i32, pad_value: i32) -> (Vec<i32>, Vec<i32>, Vec<i32>) {
+    let n_steps = (final_step + 1).max(0).min(max_u);
+    let history_size = (n_steps * beam_width) as usize;
+    let (mut branch, mut ts) = extract_best_beam_branch_kernel(best_final_branch, &beam_branch[..history_size], &t_history[..history_size], beam_width, n_steps);
+    let mut path_prediction: Vec<i32> = branch.iter().enumerate()
+        .map(|(s, b)| prediction[s * beam_width as usize + *b as usize])
+        .collect();
+    branch.resize(max_u as usize, pad_value);
+    ts.resize(max_u as usize, pad_value);
+    path_prediction.resize(max_u as usize, pad_value);
+    (branch, ts, path_prediction)
+}
+
+// log(exp(a) + exp(b)) that stays finite when either side is -inf.
+pub fn log_add_exp(a: f32, b: f32) -> f32 {
+    if a == f32::NEG_INFINITY {
+        return b;
+    }
+    if b == f32::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
+
+pub fn log_sum_exp(values: &[f32]) -> f32 {
+    values.iter().fold(f32::NEG_INFINITY, |acc, v| log_add_exp(acc, *v))
+}
+
+
+// Small seeded generator (SplitMix64) so that sampling is reproducible across platforms.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            state: seed,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    // Draws an index with probability proportional to exp(log_weights). Returns None if every weight is -inf.
+    pub fn categorical(&mut self, log_weights: &[f32]) -> Option<usize> {
+        let normalizer = log_sum_exp(log_weights);
+        if normalizer == f32::NEG_INFINITY {
+            return None;
+        }
+        let threshold = self.next_f32();
+        let mut cumulative = 0.0;
+        let mut last_valid = None;
+        for (i, w) in log_weights.iter().enumerate() {
+            if *w == f32::NEG_INFINITY {
+                continue;
+            }
+            cumulative += (w - normalizer).exp();
+            last_valid = Some(i);
+            if threshold < cumulative {
+                return Some(i);
+            }
+        }
+        // Rounding may leave the cumulative sum slightly below one.
+        last_valid
+    }
+}
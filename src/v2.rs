@@ -2,32 +2,91 @@ extern crate rayon;
 
 use std::cmp::Ordering;
 use rayon::prelude::*;
+use crate::scoring::ScoringPolicy;
+use crate::merge::{MergeMode, merge_hypotheses};
+use crate::diversity::DiversityPolicy;
+use crate::pruning::PruningPolicy;
+use crate::sampling::{SamplingPolicy, beam_rng};
+use crate::lattice::{Lattice, LatticeNode, lattices_per_batch};
+use crate::util::{BatchView, fill_beams};
 
 
-struct BatchView<'a, T> {
-    batch_offset: usize,
-    data: &'a [T],
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct DurationConstraints {
+    // Band of allowed total duration around the diagonal, as ratios of the output length plus absolute frames.
+    pub use_band: bool,
+    pub lower_band_ratio: f32,
+    pub upper_band_ratio: f32,
+    pub lower_band_frames: f32,
+    pub upper_band_frames: f32,
+    // Frames reserved for every remaining input token.
+    pub use_overrun: bool,
+    pub min_frames_per_token: usize,
+    // Window of total duration minus diagonal in which a hypothesis is kept as the diagonal candidate.
+    pub use_diagonal: bool,
+    pub diagonal_lower: f32,
+    pub diagonal_upper: f32,
 }
 
-impl<'a, T> BatchView<'a, T> {
-    fn new(batch_size: usize, data: &'a [T]) -> BatchView<'a, T> {
-        BatchView {
-            batch_offset: data.len() / batch_size,
-            data,
+impl Default for DurationConstraints {
+    fn default() -> DurationConstraints {
+        DurationConstraints {
+            use_band: true,
+            lower_band_ratio: 0.05,
+            upper_band_ratio: 0.1,
+            lower_band_frames: 0.0,
+            upper_band_frames: 0.0,
+            use_overrun: true,
+            min_frames_per_token: 3,
+            use_diagonal: true,
+            diagonal_lower: -20.0,
+            diagonal_upper: 0.0,
         }
     }
+}
+
+// Options of SsntTtsV2Cpu that score the output length instead of constraining it.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LengthOptions {
+    // Decodes without a known output length, scoring the total duration with an optional length prior instead.
+    pub free_length: bool,
+    pub length_prior_weight: f32,
+    // Strength of the per-frame bias toward shorter classes for faster speaking rates and longer ones for slower rates.
+    pub rate_bias: f32,
+}
 
-    fn batch(&self, index: usize) -> &[T] {
-        self.data[index * self.batch_offset..(index + 1) * self.batch_offset].as_ref()
+impl Default for LengthOptions {
+    fn default() -> LengthOptions {
+        LengthOptions {
+            free_length: false,
+            length_prior_weight: 1.0,
+            rate_bias: 0.0,
+        }
     }
 }
 
-struct DecodingTable {
-    log_prob: f32,
-    duration_class: i32,
-    duration: i32,
-    total_duration: i32,
-    is_finished: bool,
+// Gaussian prior over the total number of frames, used when the output length is not known.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LengthPrior {
+    pub mean: f32,
+    pub stddev: f32,
+    pub weight: f32,
+}
+
+impl LengthPrior {
+    pub fn log_prob(&self, total_duration: i32) -> f32 {
+        let z = (total_duration as f32 - self.mean) / self.stddev;
+        self.weight * (-0.5 * z * z - self.stddev.ln() - 0.5 * (2.0 * std::f32::consts::PI).ln())
+    }
+}
+
+// Candidate class of one beam that passes the constraints of the position.
+pub struct DecodingTable {
+    pub log_prob: f32,
+    pub duration_class: i32,
+    pub duration: i32,
+    pub total_duration: i32,
+    pub is_finished: bool,
 }
 
 pub struct BeamSearchDecodingTable<'a> {
@@ -47,6 +106,14 @@ pub struct BeamSearchDecodingTable<'a> {
     beam_width: usize,
     max_beam_width: usize,
     zero_duration_id: i32,
+    constraints: DurationConstraints,
+    // Added to the ranking score per frame of duration, but not to the log-probs.
+    duration_bias: f32,
+    // (T) per-token bounds of duration in frames. Negative values leave the side unbounded.
+    min_duration: Option<&'a [i32]>,
+    max_duration: Option<&'a [i32]>,
+    // Added to the ranking score of finished hypotheses in free-length mode, but not to the log-probs.
+    length_prior: Option<LengthPrior>,
 }
 
 impl<'a> BeamSearchDecodingTable<'a> {
@@ -60,7 +127,12 @@ impl<'a> BeamSearchDecodingTable<'a> {
                output_length: usize,
                beam_width: usize,
                max_beam_width: usize,
-               zero_duration_id: i32) -> BeamSearchDecodingTable<'a> {
+               zero_duration_id: i32,
+               constraints: DurationConstraints,
+               duration_bias: f32,
+               min_duration: Option<&'a [i32]>,
+               max_duration: Option<&'a [i32]>,
+               length_prior: Option<LengthPrior>) -> BeamSearchDecodingTable<'a> {
         assert_eq!(input.len(), beam_width * duration_class_size, "input: {}, beam_width: {}, duration_class_size: {}", input.len(), beam_width, duration_class_size);
         assert_eq!(log_prob_history.len(), beam_width);
         assert_eq!(is_finished.len(), beam_width);
@@ -78,6 +150,11 @@ impl<'a> BeamSearchDecodingTable<'a> {
             beam_width,
             max_beam_width,
             zero_duration_id,
+            constraints,
+            duration_bias,
+            min_duration,
+            max_duration,
+            length_prior,
         }
     }
 
@@ -93,11 +170,8 @@ impl<'a> BeamSearchDecodingTable<'a> {
 
     fn total_duration_bounds(&self, t: usize) -> (i32, i32) {
         let diagonal: f32 = self.output_length as f32 / self.input_length as f32 * (t + 1) as f32;
-        // ToDo: configure the range by arguments
-        // + 10% of total frames
-        let upper_range = self.output_length as f32 * 0.1;
-        // - 5% of total frames
-        let lower_range = self.output_length as f32 * 0.05;
+        let upper_range = self.output_length as f32 * self.constraints.upper_band_ratio + self.constraints.upper_band_frames;
+        let lower_range = self.output_length as f32 * self.constraints.lower_band_ratio + self.constraints.lower_band_frames;
         let lower_bound: i32 = (diagonal - lower_range).max(0.0) as i32;
         let upper_bound: i32 = (diagonal + upper_range).min(self.output_length as f32) as i32;
         (lower_bound, upper_bound)
@@ -105,18 +179,36 @@ impl<'a> BeamSearchDecodingTable<'a> {
 
     fn will_overrun(&self, t: usize) -> bool {
         let remaining_iteration = self.input_length - (t + 1);
-        // ToDo: configure by argument
-        let min_total_duration = remaining_iteration * 3;
+        let min_total_duration = remaining_iteration * self.constraints.min_frames_per_token;
         min_total_duration > self.output_length
     }
 
     fn on_diagonal(&self, result: &DecodeResult) -> bool {
-        let diagonal: f32 = self.output_length as f32 / self.input_length as f32 * result.next_t as f32;
-        let diff: f32 = result.total_duration as f32 - diagonal;
-        diff >= -20.0 && diff <= 0.0
+        self.is_on_diagonal(result.next_t, result.total_duration)
     }
 
-    fn decode_beam_at(&self, w: usize, t: usize, allow_skip: bool, test_mode: bool) -> Option<Vec<DecodingTable>> {
+    pub fn is_on_diagonal(&self, next_t: usize, total_duration: i32) -> bool {
+        let diagonal: f32 = self.output_length as f32 / self.input_length as f32 * next_t as f32;
+        let diff: f32 = total_duration as f32 - diagonal;
+        diff >= self.constraints.diagonal_lower && diff <= self.constraints.diagonal_upper
+    }
+
+    // Ranking score of a hypothesis with the given total duration on top of its model log-prob.
+    pub fn rank_bias(&self, total_duration: i32, is_finished: bool) -> f32 {
+        let length_log_prob = match self.length_prior {
+            Some(prior) if is_finished => prior.log_prob(total_duration),
+            _ => 0.0,
+        };
+        self.duration_bias * total_duration as f32 + length_log_prob
+    }
+
+    fn is_within_token_bounds(&self, t: usize, duration: i32) -> bool {
+        let above_min = self.min_duration.is_none_or(|d| d[t] < 0 || duration >= d[t]);
+        let below_max = self.max_duration.is_none_or(|d| d[t] < 0 || duration <= d[t]);
+        above_min && below_max
+    }
+
+    pub fn decode_beam_at(&self, w: usize, t: usize, allow_skip: bool, test_mode: bool, free_length: bool) -> Option<Vec<DecodingTable>> {
         if !self.is_defined_at(t) {
             return None;
         }
@@ -124,16 +216,20 @@ impl<'a> BeamSearchDecodingTable<'a> {
             return None;
         }
         let branch: &[f32] = self.beam_branch(w);
+        // Constraints derived from output_length do not apply when it is unknown.
+        let length_constrained = !test_mode && !free_length;
         let input_copy: Vec<DecodingTable> = branch.iter().enumerate().filter_map(|(i, v)| {
             let duration: i32 = self.duration_table[i];
             let total_duration: i32 = self.total_duration[w] + duration;
             let (lower_bound, upper_bound) = self.total_duration_bounds(t);
-            if !test_mode && (total_duration < lower_bound || total_duration > upper_bound as i32) {
+            // Per-token bounds are given explicitly, so they apply in test mode too.
+            if !self.is_within_token_bounds(t, duration) ||
+                (length_constrained && self.constraints.use_band && (total_duration < lower_bound || total_duration > upper_bound as i32)) {
                 None
-            } else if !test_mode && self.will_overrun(t)  {
+            } else if length_constrained && self.constraints.use_overrun && self.will_overrun(t)  {
                 None
             } else if t == self.input_length - 1 {
-                if !test_mode && total_duration != self.output_length as i32 {
+                if length_constrained && total_duration != self.output_length as i32 {
                     None
                 } else {
                     if !allow_skip && i as i32 == self.zero_duration_id {
@@ -174,10 +270,17 @@ pub struct DecodeResult {
     next_u: usize,
     is_finished: bool,
     parent_branch: usize,
+    // Duplicate that only fills the beam up to max_beam_width.
+    pub is_padding: bool,
     total_duration: i32,
 }
 
 impl DecodeResult {
+    // Number of steps taken, counting the finishing step.
+    fn length(&self) -> usize {
+        if self.is_finished { self.next_u + 1 } else { self.next_u }
+    }
+
     fn eq_ignore_parent(&self, other: &DecodeResult) -> bool {
         self.prediction == other.prediction &&
             self.log_prob == other.log_prob &&
@@ -186,6 +289,16 @@ impl DecodeResult {
             self.is_finished == other.is_finished &&
             self.total_duration == other.total_duration
     }
+
+    // Same transition from the same beam, regardless of the log_prob after merging.
+    fn is_same_transition(&self, other: &DecodeResult) -> bool {
+        self.parent_branch == other.parent_branch &&
+            self.prediction == other.prediction &&
+            self.next_t == other.next_t &&
+            self.next_u == other.next_u &&
+            self.is_finished == other.is_finished &&
+            self.total_duration == other.total_duration
+    }
 }
 
 pub struct SsntTtsV2Cpu {
@@ -194,36 +307,137 @@ pub struct SsntTtsV2Cpu {
     zero_duration_id: i32,
     allow_skip: bool,
     test_mode: bool,
+    constraints: DurationConstraints,
+    length_options: LengthOptions,
+    scoring: ScoringPolicy,
+    merge_mode: MergeMode,
+    diversity: DiversityPolicy,
+    pruning: PruningPolicy,
 }
 
 impl SsntTtsV2Cpu {
-    pub fn new(batch_size: i32, duration_class_size: usize, zero_duration_id: i32, allow_skip: bool, test_mode: bool) -> SsntTtsV2Cpu {
+    pub fn new(batch_size: i32, duration_class_size: usize, zero_duration_id: i32, allow_skip: bool, test_mode: bool, constraints: DurationConstraints, length_options: LengthOptions, scoring: ScoringPolicy, merge_mode: MergeMode, diversity: DiversityPolicy, pruning: PruningPolicy) -> SsntTtsV2Cpu {
         SsntTtsV2Cpu {
             batch_size,
             duration_class_size,
             zero_duration_id,
             allow_skip,
             test_mode,
+            constraints,
+            length_options,
+            scoring,
+            merge_mode,
+            diversity,
+            pruning,
         }
     }
-}
 
-pub trait SsntTtsV2 {
-    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], max_t: &[i32], max_u: &[i32], batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32]) -> ();
+    // Coverage is the fraction of the target length covered so far, or of the input if the target is unknown.
+    fn rank_score(&self, h: &BeamSearchDecodingTable, result: &DecodeResult) -> f32 {
+        let coverage = if h.output_length > 0 {
+            result.total_duration as f32 / h.output_length as f32
+        } else {
+            result.next_t as f32 / h.input_length as f32
+        };
+        self.scoring.score(result.log_prob, result.length(), coverage) + h.rank_bias(result.total_duration, result.is_finished)
+    }
 
-    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult>;
+    // Every hypothesis reachable from the current beams, best first.
+    fn expand(&self, h: &BeamSearchDecodingTable, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
+        let mut results: Vec<DecodeResult> = (0..h.beam_width)
+            .into_par_iter()
+            .flat_map(|w| {
+                let t = start_t[w];
+                let u = u[w];
+                let log_prob_history = h.log_prob_history[w];
+                self.beam_search_kernel_internal(h, w, t, u, log_prob_history)
+            }).collect();
+        // Without any duration compatible with the output length, every beam ends in a dead end that is not a valid hypothesis.
+        if results.is_empty() {
+            results = (0..h.beam_width).map(|w| DecodeResult {
+                prediction: self.zero_duration_id,
+                log_prob: f32::NEG_INFINITY,
+                next_t: start_t[w],
+                next_u: u[w],
+                is_finished: true,
+                parent_branch: w,
+                is_padding: true,
+                total_duration: h.total_duration[w],
+            }).collect();
+        }
 
-    fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32) -> Vec<DecodeResult>;
-}
+        // Here the sorting does not consider prefixes. This is because we are interested in intermediate features which is path dependent.
+        results.sort_by(|a, b| self.rank_score(h, a).partial_cmp(&self.rank_score(h, b)).unwrap_or(Ordering::Equal).reverse());
+        results.dedup_by(|a, b| a.eq_ignore_parent(b) && self.diversity.same_group(a.parent_branch, b.parent_branch, h.beam_width));
+        results
+    }
 
+    // Chooses the next max_beam_width beams among the expanded hypotheses.
+    fn select(&self, h: &BeamSearchDecodingTable, mut results: Vec<DecodeResult>) -> Vec<DecodeResult> {
+        // Optionally merge paths reaching the same lattice state, which makes intermediate features path independent.
+        if self.merge_mode != MergeMode::NoMerge {
+            results = merge_hypotheses(results, self.merge_mode,
+                                       |r| (r.next_t, r.next_u, r.total_duration, r.is_finished),
+                                       |r| r.log_prob,
+                                       |r, log_prob| DecodeResult { log_prob, ..r });
+            results.sort_by(|a, b| self.rank_score(h, a).partial_cmp(&self.rank_score(h, b)).unwrap_or(Ordering::Equal).reverse());
+        }
+        // Add a diagonal duration candidate to avoid empty search
+        let diagonal_result: Option<DecodeResult> = if !self.test_mode && !self.length_options.free_length && self.constraints.use_diagonal {
+            results.iter().find(|result| {
+                !result.is_padding && h.on_diagonal(result)
+            }).map(|result| result.clone())
+        } else {
+            None
+        };
 
-impl SsntTtsV2 for SsntTtsV2Cpu {
-    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], input_length: &[i32], output_length: &[i32], batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32]) -> () {
+        if self.diversity.is_enabled() {
+            results = self.diversity.select(results, h.beam_width, h.max_beam_width, &self.pruning,
+                                            |r| r.parent_branch,
+                                            |r| r.prediction,
+                                            |r| self.rank_score(h, r),
+                                            |r| DecodeResult { is_padding: true, ..*r });
+        } else {
+            self.pruning.prune(&mut results, |r| self.rank_score(h, r));
+        }
+        fill_beams(results, diagonal_result, h.max_beam_width, |r| r.is_padding, |r| DecodeResult { is_padding: true, ..*r })
+    }
+
+    // beam_search_kernel that also records the step into the lattice if one is given.
+    fn beam_search_step(&self, h: &BeamSearchDecodingTable, start_t: &[usize], u: &[usize], lattice: Option<&mut Lattice>) -> Vec<DecodeResult> {
+        match lattice {
+            None => self.beam_search_kernel(h, start_t, u),
+            Some(lattice) => {
+                let expanded = self.expand(h, start_t, u);
+                let results = self.select(h, expanded.clone());
+                let candidates: Vec<(usize, LatticeNode)> = expanded.iter().map(|r| {
+                    let w = r.parent_branch;
+                    (w, LatticeNode::new(r.prediction, r.log_prob, self.rank_score(h, r), start_t[w], u[w], r.total_duration, r.is_finished))
+                }).collect();
+                // Merged hypotheses only differ from their expanded representative in log_prob.
+                let selected: Vec<usize> = results.iter().map(|r| {
+                    expanded.iter().position(|e| e.is_same_transition(r) && e.log_prob == r.log_prob)
+                        .or_else(|| expanded.iter().position(|e| e.is_same_transition(r)))
+                        .expect("selected hypothesis must be expanded")
+                }).collect();
+                lattice.push_step(candidates, selected.as_slice());
+                results
+            }
+        }
+    }
+
+    // Runs one decoding step per batch item with the table built from the batched inputs.
+    fn decode_step<F>(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], input_length: &[i32], output_length: &[i32], speaking_rate: &[f32], min_duration: Option<&[i32]>, max_duration: Option<&[i32]>, length_prior_mean: Option<&[f32]>, length_prior_stddev: Option<&[f32]>, batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32], num_valid: &mut [i32], lattices: Option<&mut [Lattice]>, kernel: F) where F: Fn(usize, &BeamSearchDecodingTable, &[usize], &[usize], Option<&mut Lattice>) -> Vec<DecodeResult> + Sync {
         assert_eq!(prediction.len(), (batch_size * max_beam_width) as usize);
         assert_eq!(log_probs.len(), (batch_size * beam_width) as usize);
         assert_eq!(next_is_finished.len(), (batch_size * beam_width) as usize);
         assert_eq!(next_total_duration.len(), (batch_size * beam_width) as usize);
         assert_eq!(beam_branch.len(), (batch_size * beam_width) as usize);
+        assert_eq!(speaking_rate.len(), batch_size as usize);
+        // (B, T)
+        let min_duration = min_duration.map(|d| BatchView::new(batch_size as usize, d));
+        let max_duration = max_duration.map(|d| BatchView::new(batch_size as usize, d));
+        let mut lattices = lattices_per_batch(lattices, batch_size as usize);
         h.par_chunks(beam_width as usize * self.duration_class_size)
             .zip(log_prob_history.par_chunks(beam_width as usize))
             .zip(is_finished.par_chunks(beam_width as usize))
@@ -232,6 +446,7 @@ impl SsntTtsV2 for SsntTtsV2Cpu {
             .zip(u.par_chunks(beam_width as usize))
             .zip(input_length.par_chunks(1))
             .zip(output_length.par_chunks(1))
+            .zip(speaking_rate.par_chunks(1))
             .zip(prediction.par_chunks_mut(max_beam_width as usize))
             .zip(log_probs.par_chunks_mut(max_beam_width as usize))
             .zip(next_t.par_chunks_mut(max_beam_width as usize))
@@ -239,7 +454,22 @@ impl SsntTtsV2 for SsntTtsV2Cpu {
             .zip(beam_branch.par_chunks_mut(max_beam_width as usize))
             .zip(next_is_finished.par_chunks_mut(max_beam_width as usize))
             .zip(next_total_duration.par_chunks_mut(max_beam_width as usize))
-            .for_each(|((((((((((((((h, log_prob_history), is_finished), total_duration), t), u), input_length), output_length), prediction), log_probs), next_t), next_u), beam_branch), next_is_finished), next_total_duration)| {
+            .zip(num_valid.par_chunks_mut(1))
+            .zip(lattices.par_iter_mut())
+            .enumerate()
+            .for_each(|(b, (((((((((((((((((h, log_prob_history), is_finished), total_duration), t), u), input_length), output_length), speaking_rate), prediction), log_probs), next_t), next_u), beam_branch), next_is_finished), next_total_duration), num_valid), lattice))| {
+                // The target length and the diagonal follow the speaking rate.
+                let output_length = SsntTtsV2Cpu::scaled_output_length(output_length[0], speaking_rate[0]);
+                let duration_bias = -self.length_options.rate_bias * speaking_rate[0].ln();
+                // The prior is over frames at the normal rate, so it is rescaled like the output length.
+                let length_prior = match (length_prior_mean, length_prior_stddev) {
+                    (Some(mean), Some(stddev)) if self.length_options.free_length => Some(LengthPrior {
+                        mean: mean[b] / speaking_rate[0],
+                        stddev: stddev[b] / speaking_rate[0],
+                        weight: self.length_options.length_prior_weight,
+                    }),
+                    _ => None,
+                };
                 let table = BeamSearchDecodingTable::new(h,
                                                          log_prob_history,
                                                          is_finished,
@@ -247,13 +477,18 @@ impl SsntTtsV2 for SsntTtsV2Cpu {
                                                          duration_table,
                                                          self.duration_class_size,
                                                          input_length[0] as usize,
-                                                         output_length[0] as usize,
+                                                         output_length,
                                                          beam_width as usize,
                                                          max_beam_width as usize,
-                                                         self.zero_duration_id);
+                                                         self.zero_duration_id,
+                                                         self.constraints,
+                                                         duration_bias,
+                                                         min_duration.as_ref().map(|d| d.batch(b)),
+                                                         max_duration.as_ref().map(|d| d.batch(b)),
+                                                         length_prior);
                 let t: Vec<usize> = t.iter().map(|v| *v as usize).collect();
                 let u: Vec<usize> = u.iter().map(|v| *v as usize).collect();
-                let results = self.beam_search_kernel(&table, t.as_slice(), u.as_slice());
+                let results = kernel(b, &table, t.as_slice(), u.as_slice(), lattice.as_deref_mut());
                 results.iter().enumerate().for_each(|(i, result)| {
                     prediction[i] = result.prediction;
                     log_probs[i] = result.log_prob;
@@ -263,53 +498,42 @@ impl SsntTtsV2 for SsntTtsV2Cpu {
                     next_is_finished[i] = result.is_finished;
                     next_total_duration[i] = result.total_duration;
                 });
+                num_valid[0] = results.iter().filter(|result| !result.is_padding).count() as i32;
             });
     }
 
-    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
-        let mut results: Vec<DecodeResult> = (0..h.beam_width)
-            .into_par_iter()
-            .flat_map(|w| {
-                let t = start_t[w];
-                let u = u[w];
-                let log_prob_history = h.log_prob_history[w];
-                self.beam_search_kernel_internal(h, w, t, u, log_prob_history)
-            }).collect();
+    // Output length to decode for a speaking rate, e.g. 1.25 gives 0.8 times the frames.
+    // The rate must be positive, which the callers validate.
+    pub fn scaled_output_length(output_length: i32, speaking_rate: f32) -> usize {
+        (output_length as f32 / speaking_rate).round() as usize
+    }
+}
 
-        // Here the sorting does not consider prefixes. This is because we are interested in intermediate features which is path dependent.
-        results.sort_by(|a, b| a.log_prob.partial_cmp(&b.log_prob).unwrap_or(Ordering::Equal).reverse());
-        results.dedup_by(|a, b| a.eq_ignore_parent(b));
-        // Add a diagonal duration candidate to avoid empty search
-        let diagonal_result: Option<DecodeResult> = if !self.test_mode {
-            results.iter().find(|result| {
-                h.on_diagonal(result)
-            }).map(|result| result.clone())
-        } else {
-            None
-        };
+pub trait SsntTtsV2 {
+    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], max_t: &[i32], max_u: &[i32], speaking_rate: &[f32], min_duration: Option<&[i32]>, max_duration: Option<&[i32]>, length_prior_mean: Option<&[f32]>, length_prior_stddev: Option<&[f32]>, batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32], num_valid: &mut [i32], lattices: Option<&mut [Lattice]>) -> ();
 
-        let n_results: usize = results.len();
-        assert_ne!(n_results, 0, "Beam search could not find a duration sequence with compatible output length: {} for input with length: {}. Please increase duration class size and beam width.", h.output_length, h.input_length);
-        if n_results < h.max_beam_width {
-            for i in 0..(h.max_beam_width - n_results) {
-                results.push(results[i % n_results].clone());
-            }
-        }
-        match diagonal_result {
-            Some(result) => {
-                results.truncate(h.max_beam_width - 1);
-                results.push(result);
-                results
-            }
-            None => {
-                results.truncate(h.max_beam_width);
-                results
-            }
-        }
+    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult>;
+
+    fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32) -> Vec<DecodeResult>;
+
+    fn sample_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], max_t: &[i32], max_u: &[i32], speaking_rate: &[f32], min_duration: Option<&[i32]>, max_duration: Option<&[i32]>, length_prior_mean: Option<&[f32]>, length_prior_stddev: Option<&[f32]>, batch_size: i32, beam_width: i32, policy: SamplingPolicy, seed: u64, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32]) -> ();
+
+    fn sample_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize], policy: SamplingPolicy, seed: u64, b: usize) -> Vec<DecodeResult>;
+}
+
+
+impl SsntTtsV2 for SsntTtsV2Cpu {
+    fn beam_search_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], input_length: &[i32], output_length: &[i32], speaking_rate: &[f32], min_duration: Option<&[i32]>, max_duration: Option<&[i32]>, length_prior_mean: Option<&[f32]>, length_prior_stddev: Option<&[f32]>, batch_size: i32, beam_width: i32, max_beam_width: i32, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32], num_valid: &mut [i32], lattices: Option<&mut [Lattice]>) -> () {
+        self.decode_step(h, log_prob_history, is_finished, total_duration, duration_table, t, u, input_length, output_length, speaking_rate, min_duration, max_duration, length_prior_mean, length_prior_stddev, batch_size, beam_width, max_beam_width, prediction, log_probs, next_t, next_u, next_is_finished, next_total_duration, beam_branch, num_valid, lattices, |_, table, t, u, lattice| self.beam_search_step(table, t, u, lattice));
+    }
+
+    fn beam_search_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize]) -> Vec<DecodeResult> {
+        let expanded = self.expand(h, start_t, u);
+        self.select(h, expanded)
     }
 
     fn beam_search_kernel_internal<'a>(&self, h: &BeamSearchDecodingTable<'a>, w: usize, t: usize, u: usize, log_prob_history: f32) -> Vec<DecodeResult> {
-        match h.decode_beam_at(w, t, self.allow_skip, self.test_mode) {
+        match h.decode_beam_at(w, t, self.allow_skip, self.test_mode, self.length_options.free_length) {
             // End of input. Return values to fill padding region.
             None => {
                 vec![DecodeResult {
@@ -319,6 +543,7 @@ impl SsntTtsV2 for SsntTtsV2Cpu {
                     next_u: u,
                     is_finished: true,
                     parent_branch: w,
+                    is_padding: false,
                     total_duration: h.total_duration[w],
                 }]
             }
@@ -331,10 +556,48 @@ impl SsntTtsV2 for SsntTtsV2Cpu {
                         next_u: if v.is_finished { u } else { u + 1 },
                         is_finished: v.is_finished,
                         parent_branch: w,
+                        is_padding: false,
                         total_duration: v.total_duration,
                     }
                 }).collect()
             }
         }
     }
+
+    fn sample_decode(&self, h: &[f32], log_prob_history: &[f32], is_finished: &[bool], total_duration: &[i32], duration_table: &[i32], t: &[i32], u: &[i32], input_length: &[i32], output_length: &[i32], speaking_rate: &[f32], min_duration: Option<&[i32]>, max_duration: Option<&[i32]>, length_prior_mean: Option<&[f32]>, length_prior_stddev: Option<&[f32]>, batch_size: i32, beam_width: i32, policy: SamplingPolicy, seed: u64, prediction: &mut [i32], log_probs: &mut [f32], next_t: &mut [i32], next_u: &mut [i32], next_is_finished: &mut [bool], next_total_duration: &mut [i32], beam_branch: &mut [i32]) -> () {
+        // Every sampled beam is a real hypothesis.
+        let mut num_valid: Vec<i32> = vec![0; batch_size as usize];
+        self.decode_step(h, log_prob_history, is_finished, total_duration, duration_table, t, u, input_length, output_length, speaking_rate, min_duration, max_duration, length_prior_mean, length_prior_stddev, batch_size, beam_width, beam_width, prediction, log_probs, next_t, next_u, next_is_finished, next_total_duration, beam_branch, num_valid.as_mut_slice(), None,
+                         |b, table, t, u, _| self.sample_kernel(table, t, u, policy, seed, b));
+    }
+
+    // Every beam draws its own next duration class among the candidates that pass the constraints, so beams stay in place.
+    fn sample_kernel<'a>(&self, h: &BeamSearchDecodingTable<'a>, start_t: &[usize], u: &[usize], policy: SamplingPolicy, seed: u64, b: usize) -> Vec<DecodeResult> {
+        (0..h.beam_width)
+            .into_par_iter()
+            .map(|w| {
+                let t = start_t[w];
+                let u = u[w];
+                let log_prob_history = h.log_prob_history[w];
+                let candidates = self.beam_search_kernel_internal(h, w, t, u, log_prob_history);
+                // The rate bias and the length prior steer sampling as they steer the ranking.
+                let log_probs: Vec<f32> = candidates.iter().map(|result| {
+                    result.log_prob - log_prob_history + h.rank_bias(result.total_duration, result.is_finished) - h.rank_bias(h.total_duration[w], false)
+                }).collect();
+                match policy.sample(&mut beam_rng(seed, b, w, u), log_probs.as_slice()) {
+                    Some(i) => candidates[i],
+                    // A beam without any compatible duration is dropped by finishing it with zero probability.
+                    None => DecodeResult {
+                        prediction: self.zero_duration_id,
+                        log_prob: f32::NEG_INFINITY,
+                        next_t: t,
+                        next_u: u,
+                        is_finished: true,
+                        parent_branch: w,
+                        is_padding: false,
+                        total_duration: h.total_duration[w],
+                    },
+                }
+            }).collect()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,269 @@
+extern crate rayon;
+
+use std::f32;
+use std::fmt;
+use std::cmp::Ordering;
+use rayon::prelude::*;
+use crate::sampling::beam_rng;
+use crate::util::{log_add_exp, Rng};
+
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum DurationDecodeError {
+    // No duration sequence of the input length sums to the output length.
+    NoCompatibleSequence { input_length: usize, output_length: usize },
+}
+
+impl fmt::Display for DurationDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DurationDecodeError::NoCompatibleSequence { input_length, output_length } => {
+                write!(f, "No duration sequence with compatible output length: {} for input with length: {}. Please increase duration class size.", output_length, input_length)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DurationDecodeError {}
+
+// One of the k best partial sequences ending at a (t, total_duration) cell.
+#[derive(Debug, Copy, Clone)]
+struct PathEntry {
+    log_prob: f32,
+    duration_class: i32,
+    previous_total_duration: usize,
+    previous_rank: usize,
+}
+
+// Duration-sequence model over (T, D) duration-class log-probs that factorises over input tokens.
+pub struct DurationSequenceCpu {
+    duration_class_size: usize,
+    zero_duration_id: i32,
+    allow_skip: bool,
+}
+
+impl DurationSequenceCpu {
+    pub fn new(duration_class_size: usize, zero_duration_id: i32, allow_skip: bool) -> DurationSequenceCpu {
+        DurationSequenceCpu {
+            duration_class_size,
+            zero_duration_id,
+            allow_skip,
+        }
+    }
+
+    fn is_allowed(&self, duration_class: usize) -> bool {
+        self.allow_skip || duration_class as i32 != self.zero_duration_id
+    }
+}
+
+pub trait DurationSequence {
+    fn log_likelihood(&self, h: &[f32], duration_table: &[i32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, log_likelihood: &mut [f32]);
+
+    fn sample(&self, h: &[f32], duration_table: &[i32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, seed: u64, prediction: &mut [i32], log_probs: &mut [f32]);
+
+    fn forward_kernel(&self, h: &[f32], duration_table: &[i32], input_length: usize, output_length: usize) -> Vec<f32>;
+
+    fn sample_kernel(&self, h: &[f32], duration_table: &[i32], alpha: &[f32], input_length: usize, output_length: usize, rng: &mut Rng) -> Option<(Vec<i32>, f32)>;
+
+    fn n_best_decode(&self, h: &[f32], duration_table: &[i32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, n_best: usize, prediction: &mut [i32], log_probs: &mut [f32], num_valid: &mut [i32]);
+
+    fn best_path_kernel(&self, h: &[f32], duration_table: &[i32], input_length: usize, output_length: usize) -> Result<(Vec<i32>, f32), DurationDecodeError>;
+
+    fn n_best_kernel(&self, h: &[f32], duration_table: &[i32], input_length: usize, output_length: usize, n_best: usize) -> Result<Vec<(Vec<i32>, f32)>, DurationDecodeError>;
+}
+
+impl DurationSequence for DurationSequenceCpu {
+    fn log_likelihood(&self, h: &[f32], duration_table: &[i32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, log_likelihood: &mut [f32]) {
+        assert_eq!(h.len(), batch_size * max_t * self.duration_class_size);
+        assert_eq!(duration_table.len(), self.duration_class_size);
+        assert_eq!(log_likelihood.len(), batch_size);
+        // (B, T, D)
+        h.par_chunks(max_t * self.duration_class_size)
+            .zip(input_length.par_chunks(1))
+            .zip(output_length.par_chunks(1))
+            .zip(log_likelihood.par_chunks_mut(1))
+            .for_each(|(((h, input_length), output_length), log_likelihood)| {
+                let input_length = input_length[0] as usize;
+                let output_length = output_length[0] as usize;
+                let alpha = self.forward_kernel(h, duration_table, input_length, output_length);
+                log_likelihood[0] = alpha[input_length * (output_length + 1) + output_length];
+            });
+    }
+
+    fn sample(&self, h: &[f32], duration_table: &[i32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, seed: u64, prediction: &mut [i32], log_probs: &mut [f32]) {
+        assert_eq!(h.len(), batch_size * max_t * self.duration_class_size);
+        assert_eq!(duration_table.len(), self.duration_class_size);
+        assert_eq!(prediction.len(), batch_size * max_t);
+        assert_eq!(log_probs.len(), batch_size);
+        // (B, T, D)
+        h.par_chunks(max_t * self.duration_class_size)
+            .zip(input_length.par_chunks(1))
+            .zip(output_length.par_chunks(1))
+            // (B, T)
+            .zip(prediction.par_chunks_mut(max_t))
+            .zip(log_probs.par_chunks_mut(1))
+            .enumerate()
+            .for_each(|(b, ((((h, input_length), output_length), prediction), log_prob))| {
+                let input_length = input_length[0] as usize;
+                let output_length = output_length[0] as usize;
+                // Each batch item gets its own stream so that results do not depend on scheduling.
+                let mut rng = beam_rng(seed, b, 0, 0);
+                let alpha = self.forward_kernel(h, duration_table, input_length, output_length);
+                // Padding region and items without any compatible sequence are filled with zero_duration_id.
+                prediction.iter_mut().for_each(|v| *v = self.zero_duration_id);
+                match self.sample_kernel(h, duration_table, alpha.as_slice(), input_length, output_length, &mut rng) {
+                    Some((sampled, score)) => {
+                        prediction[..input_length].copy_from_slice(sampled.as_slice());
+                        log_prob[0] = score;
+                    }
+                    None => {
+                        log_prob[0] = f32::NEG_INFINITY;
+                    }
+                }
+            });
+    }
+
+    // alpha(t, d) is the log-prob that the first t tokens have total duration d, as a (T + 1, L + 1) table.
+    fn forward_kernel(&self, h: &[f32], duration_table: &[i32], input_length: usize, output_length: usize) -> Vec<f32> {
+        let width = output_length + 1;
+        let mut alpha: Vec<f32> = vec![f32::NEG_INFINITY; (input_length + 1) * width];
+        alpha[0] = 0.0;
+        for t in 0..input_length {
+            let branch = &h[t * self.duration_class_size..(t + 1) * self.duration_class_size];
+            for d in 0..width {
+                let previous = alpha[t * width + d];
+                if previous == f32::NEG_INFINITY {
+                    continue;
+                }
+                branch.iter().enumerate()
+                    .filter(|(i, _)| self.is_allowed(*i))
+                    .for_each(|(i, v)| {
+                        let total_duration = d + duration_table[i] as usize;
+                        if total_duration < width {
+                            let index = (t + 1) * width + total_duration;
+                            alpha[index] = log_add_exp(alpha[index], previous + v);
+                        }
+                    });
+            }
+        }
+        alpha
+    }
+
+    // Draws a duration-class sequence from the distribution restricted to sequences of total duration output_length,
+    // backwards from the last token. Returns the sequence with its log-prob, or None if no sequence is compatible.
+    fn sample_kernel(&self, h: &[f32], duration_table: &[i32], alpha: &[f32], input_length: usize, output_length: usize, rng: &mut Rng) -> Option<(Vec<i32>, f32)> {
+        let width = output_length + 1;
+        if alpha[input_length * width + output_length] == f32::NEG_INFINITY {
+            return None;
+        }
+        let mut prediction: Vec<i32> = vec![self.zero_duration_id; input_length];
+        let mut log_prob = 0.0;
+        let mut remaining = output_length;
+        for t in (0..input_length).rev() {
+            let branch = &h[t * self.duration_class_size..(t + 1) * self.duration_class_size];
+            let log_weights: Vec<f32> = branch.iter().enumerate().map(|(i, v)| {
+                let duration = duration_table[i] as usize;
+                if !self.is_allowed(i) || duration > remaining {
+                    f32::NEG_INFINITY
+                } else {
+                    v + alpha[t * width + remaining - duration]
+                }
+            }).collect();
+            let i = rng.categorical(log_weights.as_slice())?;
+            prediction[t] = i as i32;
+            log_prob += branch[i];
+            remaining -= duration_table[i] as usize;
+        }
+        Some((prediction, log_prob))
+    }
+
+    fn n_best_decode(&self, h: &[f32], duration_table: &[i32], input_length: &[i32], output_length: &[i32], batch_size: usize, max_t: usize, n_best: usize, prediction: &mut [i32], log_probs: &mut [f32], num_valid: &mut [i32]) {
+        assert_eq!(h.len(), batch_size * max_t * self.duration_class_size);
+        assert_eq!(duration_table.len(), self.duration_class_size);
+        assert_eq!(prediction.len(), batch_size * n_best * max_t);
+        assert_eq!(log_probs.len(), batch_size * n_best);
+        assert_eq!(num_valid.len(), batch_size);
+        // (B, T, D)
+        h.par_chunks(max_t * self.duration_class_size)
+            .zip(input_length.par_chunks(1))
+            .zip(output_length.par_chunks(1))
+            // (B, N, T)
+            .zip(prediction.par_chunks_mut(n_best * max_t))
+            // (B, N)
+            .zip(log_probs.par_chunks_mut(n_best))
+            .zip(num_valid.par_chunks_mut(1))
+            .for_each(|(((((h, input_length), output_length), prediction), log_probs), num_valid)| {
+                let input_length = input_length[0] as usize;
+                // Missing hypotheses are filled with zero_duration_id and -inf.
+                prediction.iter_mut().for_each(|v| *v = self.zero_duration_id);
+                log_probs.iter_mut().for_each(|v| *v = f32::NEG_INFINITY);
+                let paths = self.n_best_kernel(h, duration_table, input_length, output_length[0] as usize, n_best).unwrap_or_default();
+                num_valid[0] = paths.len() as i32;
+                paths.iter()
+                    .zip(prediction.chunks_mut(max_t))
+                    .zip(log_probs.iter_mut())
+                    .for_each(|(((path, score), prediction), log_prob)| {
+                        prediction[..input_length].copy_from_slice(path.as_slice());
+                        *log_prob = *score;
+                    });
+            });
+    }
+
+    // The best duration sequence whose total duration equals output_length. Exact because the model factorises over tokens.
+    fn best_path_kernel(&self, h: &[f32], duration_table: &[i32], input_length: usize, output_length: usize) -> Result<(Vec<i32>, f32), DurationDecodeError> {
+        let mut paths = self.n_best_kernel(h, duration_table, input_length, output_length, 1)?;
+        Ok(paths.remove(0))
+    }
+
+    // The n best distinct duration sequences with total duration output_length, by k-best dynamic programming over (t, total_duration).
+    fn n_best_kernel(&self, h: &[f32], duration_table: &[i32], input_length: usize, output_length: usize, n_best: usize) -> Result<Vec<(Vec<i32>, f32)>, DurationDecodeError> {
+        assert!(n_best > 0);
+        let width = output_length + 1;
+        // (T + 1, L + 1) cells holding up to n_best entries sorted by log_prob.
+        let mut table: Vec<Vec<PathEntry>> = vec![Vec::new(); (input_length + 1) * width];
+        table[0].push(PathEntry {
+            log_prob: 0.0,
+            duration_class: self.zero_duration_id,
+            previous_total_duration: 0,
+            previous_rank: 0,
+        });
+        for t in 0..input_length {
+            let branch = &h[t * self.duration_class_size..(t + 1) * self.duration_class_size];
+            for total_duration in 0..width {
+                let mut candidates: Vec<PathEntry> = branch.iter().enumerate()
+                    .filter(|(i, _)| self.is_allowed(*i) && duration_table[*i] as usize <= total_duration)
+                    .flat_map(|(i, v)| {
+                        let previous_total_duration = total_duration - duration_table[i] as usize;
+                        table[t * width + previous_total_duration].iter().enumerate().map(move |(rank, entry)| {
+                            PathEntry {
+                                log_prob: entry.log_prob + v,
+                                duration_class: i as i32,
+                                previous_total_duration,
+                                previous_rank: rank,
+                            }
+                        })
+                    }).collect();
+                candidates.sort_by(|a, b| a.log_prob.partial_cmp(&b.log_prob).unwrap_or(Ordering::Equal).reverse());
+                candidates.truncate(n_best);
+                table[(t + 1) * width + total_duration] = candidates;
+            }
+        }
+
+        let finals = &table[input_length * width + output_length];
+        if finals.is_empty() {
+            return Err(DurationDecodeError::NoCompatibleSequence { input_length, output_length });
+        }
+        Ok((0..finals.len()).map(|rank| {
+            let mut prediction: Vec<i32> = vec![self.zero_duration_id; input_length];
+            let mut total_duration = output_length;
+            let mut current_rank = rank;
+            for t in (0..input_length).rev() {
+                let entry = table[(t + 1) * width + total_duration][current_rank];
+                prediction[t] = entry.duration_class;
+                total_duration = entry.previous_total_duration;
+                current_rank = entry.previous_rank;
+            }
+            (prediction, finals[rank].log_prob)
+        }).collect())
+    }
+}
@@ -21,6 +21,7 @@ include_dirs = tf_includes
 
 lib_srcs = ['src/ssnt_tts_beam_search_decode_op.cc',
             'src/ssnt_extract_best_beam_branch_op.cc',
+            'src/ssnt_extract_best_path_op.cc',
             'src/ssnt_tts_v2_beam_search_decode_op.cc',
             'src/ssnt_order_beam_branch_op.cc',
             'src/upsample_source_indexes_op.cc',
@@ -0,0 +1,124 @@
+#include "tensorflow/core/framework/op.h"
+#include "tensorflow/core/framework/op_kernel.h"
+#include "tensorflow/core/framework/allocator.h"
+
+
+extern "C" void ssnt_extract_best_path(const int *best_final_branch,
+                                       const int *final_step,
+                                       const int *beam_branch,
+                                       const int *t_history,
+                                       const int *prediction,
+                                       int batch_size,
+                                       int beam_width,
+                                       int max_u,
+                                       int pad_value,
+                                       int *best_beam_branch,
+                                       int *best_t_history,
+                                       int *best_prediction);
+
+REGISTER_OP("SSNTExtractBestPath")
+        .Input("best_final_branch: int32")
+        .Input("final_step: int32")
+        .Input("beam_branch: int32")
+        .Input("t_history: int32")
+        .Input("prediction: int32")
+        .Attr("beam_width: int")
+        .Attr("pad_value: int")
+        .Output("best_beam_branch: int32")
+        .Output("best_t_history: int32")
+        .Output("best_prediction: int32");
+
+namespace tf = tensorflow;
+
+namespace ssnt {
+
+    class SSNTExtractBestPathOpCPU : public tf::OpKernel {
+    public:
+        explicit SSNTExtractBestPathOpCPU(tf::OpKernelConstruction *ctx) : tf::OpKernel(ctx) {
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("beam_width", &beam_width_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("pad_value", &pad_value_));
+        }
+
+        void Compute(tf::OpKernelContext *ctx) override {
+            // Grab the input tensors
+            const tf::Tensor *best_final_branch;
+            const tf::Tensor *final_step;
+            const tf::Tensor *beam_branch;
+            const tf::Tensor *t_history;
+            const tf::Tensor *prediction;
+            OP_REQUIRES_OK(ctx, ctx->input("best_final_branch", &best_final_branch));
+            OP_REQUIRES_OK(ctx, ctx->input("final_step", &final_step));
+            OP_REQUIRES_OK(ctx, ctx->input("beam_branch", &beam_branch));
+            OP_REQUIRES_OK(ctx, ctx->input("t_history", &t_history));
+            OP_REQUIRES_OK(ctx, ctx->input("prediction", &prediction));
+
+            OP_REQUIRES(ctx, best_final_branch->shape().dims() == 1,
+                        tf::errors::InvalidArgument("best_final_branch is not a 1D-Tensor"));
+            OP_REQUIRES(ctx, final_step->shape().dims() == 1,
+                        tf::errors::InvalidArgument("final_step is not a 1D-Tensor"));
+            OP_REQUIRES(ctx, beam_branch->shape().dims() == 3,
+                        tf::errors::InvalidArgument("beam_branch is not a 3D-Tensor"));
+            OP_REQUIRES(ctx, t_history->shape() == beam_branch->shape(),
+                        tf::errors::InvalidArgument("t_history has invalid shape"));
+            OP_REQUIRES(ctx, prediction->shape() == beam_branch->shape(),
+                        tf::errors::InvalidArgument("prediction has invalid shape"));
+            OP_REQUIRES(ctx, beam_branch->shape().dim_size(2) == beam_width_,
+                        tf::errors::InvalidArgument("Incompatible beam widths"));
+
+            // (B, U, W)
+            const auto &beam_branch_shape = beam_branch->shape();
+            const auto batch_size = beam_branch_shape.dim_size(0);
+            const auto max_u = beam_branch_shape.dim_size(1);
+
+            OP_REQUIRES(ctx, best_final_branch->shape().dim_size(0) == batch_size &&
+                             final_step->shape().dim_size(0) == batch_size,
+                        tf::errors::InvalidArgument("Incompatible batch sizes"));
+
+            auto best_final_branch_t = best_final_branch->vec<int32_t>();
+            auto final_step_t = final_step->vec<int32_t>();
+            auto beam_branch_t = beam_branch->tensor<int32_t, 3>();
+            auto t_history_t = t_history->tensor<int32_t, 3>();
+            auto prediction_t = prediction->tensor<int32_t, 3>();
+
+            for (int b = 0; b < batch_size; ++b) {
+                OP_REQUIRES(ctx, best_final_branch_t(b) >= 0 && best_final_branch_t(b) < beam_width_,
+                            tf::errors::InvalidArgument("best_final_branch is out of the beam width"));
+                OP_REQUIRES(ctx, final_step_t(b) <= max_u - 1,
+                            tf::errors::InvalidArgument("final_step is beyond the last step"));
+            }
+
+            tf::Tensor *best_beam_branch = nullptr;
+            OP_REQUIRES_OK(ctx, ctx->allocate_output("best_beam_branch", tf::TensorShape({batch_size, max_u}), &best_beam_branch));
+            auto best_beam_branch_t = best_beam_branch->matrix<int32_t>();
+
+            tf::Tensor *best_t_history = nullptr;
+            OP_REQUIRES_OK(ctx, ctx->allocate_output("best_t_history", tf::TensorShape({batch_size, max_u}), &best_t_history));
+            auto best_t_history_t = best_t_history->matrix<int32_t>();
+
+            tf::Tensor *best_prediction = nullptr;
+            OP_REQUIRES_OK(ctx, ctx->allocate_output("best_prediction", tf::TensorShape({batch_size, max_u}), &best_prediction));
+            auto best_prediction_t = best_prediction->matrix<int32_t>();
+
+            ssnt_extract_best_path(best_final_branch_t.data(),
+                                   final_step_t.data(),
+                                   beam_branch_t.data(),
+                                   t_history_t.data(),
+                                   prediction_t.data(),
+                                   batch_size,
+                                   beam_width_,
+                                   max_u,
+                                   pad_value_,
+                                   best_beam_branch_t.data(),
+                                   best_t_history_t.data(),
+                                   best_prediction_t.data());
+        }
+
+    private:
+        int beam_width_;
+        int pad_value_;
+    };
+
+    REGISTER_KERNEL_BUILDER(Name("SSNTExtractBestPath").Device(::tensorflow::DEVICE_CPU),
+                            SSNTExtractBestPathOpCPU);
+
+}
@@ -4,8 +4,12 @@
 
 extern "C" void ssnt_tts_beam_search_decode(const float *h, const float *log_prob_history, const bool *is_finished,
                                             const int *t, const int *u,
-                                            int max_t, int beam_width, int *prediction, float *log_prob, int *next_t,
-                                            int *next_u, bool *next_is_finished, int *beam_branch);
+                                            int max_t, int beam_width,
+                                            float length_alpha, float insertion_bonus, float coverage_beta,
+                                            int merge_mode, int num_groups, float diversity_penalty,
+                                            float beam_threshold, int max_active,
+                                            int *prediction, float *log_prob, int *next_t,
+                                            int *next_u, bool *next_is_finished, int *beam_branch, int *num_valid);
 
 
 REGISTER_OP("SSNTBeamSearchDecode")
@@ -16,12 +20,21 @@ REGISTER_OP("SSNTBeamSearchDecode")
     .Input("u: int32")
     .Input("max_t: int32")
     .Attr("beam_width: int")
+    .Attr("length_alpha: float = 0.0")
+    .Attr("insertion_bonus: float = 0.0")
+    .Attr("coverage_beta: float = 0.0")
+    .Attr("merge_mode: int = 0")
+    .Attr("num_groups: int = 1")
+    .Attr("diversity_penalty: float = 0.0")
+    .Attr("beam_threshold: float = -1.0")
+    .Attr("max_active: int = 0")
     .Output("prediction: int32")
     .Output("log_prob: float32")
     .Output("next_t: int32")
     .Output("next_u: int32")
     .Output("next_is_finished: bool")
-    .Output("beam_branch: int32");
+    .Output("beam_branch: int32")
+    .Output("num_valid: int32");
 
 namespace tf = tensorflow;
 
@@ -31,6 +44,19 @@ namespace ssnt {
     public:
         explicit SSNTBeamSearchDecodeOpCPU(tf::OpKernelConstruction *ctx) : tf::OpKernel(ctx) {
             OP_REQUIRES_OK(ctx, ctx->GetAttr("beam_width", &beam_width_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("length_alpha", &length_alpha_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("insertion_bonus", &insertion_bonus_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("coverage_beta", &coverage_beta_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("merge_mode", &merge_mode_));
+            // 0: NoMerge, 1: Max, 2: LogSumExp
+            OP_REQUIRES(ctx, merge_mode_ >= 0 && merge_mode_ <= 2,
+                        tf::errors::InvalidArgument("Unknown merge_mode: ", merge_mode_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("num_groups", &num_groups_));
+            OP_REQUIRES(ctx, num_groups_ >= 1 && num_groups_ <= beam_width_,
+                        tf::errors::InvalidArgument("num_groups must be between 1 and beam_width: ", num_groups_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("diversity_penalty", &diversity_penalty_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("beam_threshold", &beam_threshold_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("max_active", &max_active_));
         }
 
         void Compute(tf::OpKernelContext *ctx) override {
@@ -113,6 +139,10 @@ namespace ssnt {
                                                      &beam_branch));
             auto beam_branch_t = beam_branch->vec<int32_t>();
 
+            tf::Tensor *num_valid = nullptr;
+            OP_REQUIRES_OK(ctx, ctx->allocate_output("num_valid", tf::TensorShape({}), &num_valid));
+            auto num_valid_t = num_valid->scalar<int32_t>();
+
             ssnt_tts_beam_search_decode(h_t.data(),
                                         log_prob_history_t.data(),
                                         is_finished_t.data(),
@@ -120,16 +150,33 @@ namespace ssnt {
                                         u_t.data(),
                                         max_t_t(),
                                         beam_width_,
+                                        length_alpha_,
+                                        insertion_bonus_,
+                                        coverage_beta_,
+                                        merge_mode_,
+                                        num_groups_,
+                                        diversity_penalty_,
+                                        beam_threshold_,
+                                        max_active_,
                                         prediction_t.data(),
                                         log_prob_t.data(),
                                         next_t_t.data(),
                                         next_u_t.data(),
                                         next_is_finished_t.data(),
-                                        beam_branch_t.data());
+                                        beam_branch_t.data(),
+                                        num_valid_t.data());
         }
 
     private:
         int beam_width_;
+        float length_alpha_;
+        float insertion_bonus_;
+        float coverage_beta_;
+        int merge_mode_;
+        int num_groups_;
+        float diversity_penalty_;
+        float beam_threshold_;
+        int max_active_;
 
         void set_zero(tf::Tensor *t) {
             t->flat<float>().setZero();
@@ -11,19 +11,47 @@ extern "C" void ssnt_tts_v2_beam_search_decode(const float *h,
                                                const int *u,
                                                const int *input_length,
                                                const int *output_length,
+                                               const float *speaking_rate,
+                                               const int *min_duration,
+                                               const int *max_duration,
+                                               int max_t,
+                                               const float *length_prior_mean,
+                                               const float *length_prior_stddev,
                                                int batch_size,
                                                int beam_width,
                                                int duration_class_size,
                                                int zero_duration_id,
                                                bool allow_skip,
                                                bool test_mode,
+                                               bool free_length,
+                                               float length_prior_weight,
+                                               bool use_band,
+                                               float lower_band_ratio,
+                                               float upper_band_ratio,
+                                               float lower_band_frames,
+                                               float upper_band_frames,
+                                               bool use_overrun,
+                                               int min_frames_per_token,
+                                               bool use_diagonal,
+                                               float diagonal_lower,
+                                               float diagonal_upper,
+                                               float rate_bias,
+                                               float length_alpha,
+                                               float insertion_bonus,
+                                               float coverage_beta,
+                                               int merge_mode,
+                                               int num_groups,
+                                               float diversity_penalty,
+                                               float beam_threshold,
+                                               int max_active,
                                                int *prediction,
                                                float *log_prob,
                                                int *next_t,
                                                int *next_u,
                                                bool *next_is_finished,
                                                int *next_total_duration,
-                                               int *beam_branch);
+                                               int *beam_branch,
+                                               int *num_valid);
 
 
 REGISTER_OP("SSNTV2BeamSearchDecode")
@@ -36,18 +64,45 @@ REGISTER_OP("SSNTV2BeamSearchDecode")
         .Input("u: int32")
         .Input("input_length: int32")
         .Input("output_length: int32")
+        .Input("speaking_rate: float32")
+        .Input("min_duration: int32")
+        .Input("max_duration: int32")
+        .Input("length_prior_mean: float32")
+        .Input("length_prior_stddev: float32")
         .Attr("beam_width: int")
         .Attr("duration_class_size: int")
         .Attr("zero_duration_id: int")
         .Attr("allow_skip: bool")
         .Attr("test_mode: bool")
+        .Attr("free_length: bool = false")
+        .Attr("length_prior_weight: float = 1.0")
+        .Attr("use_band: bool = true")
+        .Attr("lower_band_ratio: float = 0.05")
+        .Attr("upper_band_ratio: float = 0.1")
+        .Attr("lower_band_frames: float = 0.0")
+        .Attr("upper_band_frames: float = 0.0")
+        .Attr("use_overrun: bool = true")
+        .Attr("min_frames_per_token: int = 3")
+        .Attr("use_diagonal: bool = true")
+        .Attr("diagonal_lower: float = -20.0")
+        .Attr("diagonal_upper: float = 0.0")
+        .Attr("rate_bias: float = 0.0")
+        .Attr("length_alpha: float = 0.0")
+        .Attr("insertion_bonus: float = 0.0")
+        .Attr("coverage_beta: float = 0.0")
+        .Attr("merge_mode: int = 0")
+        .Attr("num_groups: int = 1")
+        .Attr("diversity_penalty: float = 0.0")
+        .Attr("beam_threshold: float = -1.0")
+        .Attr("max_active: int = 0")
         .Output("prediction: int32")
         .Output("log_prob: float32")
         .Output("next_t: int32")
         .Output("next_u: int32")
         .Output("next_is_finished: bool")
         .Output("next_total_duration: int32")
-        .Output("beam_branch: int32");
+        .Output("beam_branch: int32")
+        .Output("num_valid: int32");
 
 namespace tf = tensorflow;
 
@@ -61,6 +116,32 @@ namespace ssnt {
             OP_REQUIRES_OK(ctx, ctx->GetAttr("zero_duration_id", &zero_duration_id_));
             OP_REQUIRES_OK(ctx, ctx->GetAttr("allow_skip", &allow_skip_));
             OP_REQUIRES_OK(ctx, ctx->GetAttr("test_mode", &test_mode_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("free_length", &free_length_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("length_prior_weight", &length_prior_weight_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("use_band", &use_band_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("lower_band_ratio", &lower_band_ratio_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("upper_band_ratio", &upper_band_ratio_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("lower_band_frames", &lower_band_frames_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("upper_band_frames", &upper_band_frames_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("use_overrun", &use_overrun_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("min_frames_per_token", &min_frames_per_token_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("use_diagonal", &use_diagonal_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("diagonal_lower", &diagonal_lower_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("diagonal_upper", &diagonal_upper_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("rate_bias", &rate_bias_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("length_alpha", &length_alpha_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("insertion_bonus", &insertion_bonus_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("coverage_beta", &coverage_beta_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("merge_mode", &merge_mode_));
+            // 0: NoMerge, 1: Max, 2: LogSumExp
+            OP_REQUIRES(ctx, merge_mode_ >= 0 && merge_mode_ <= 2,
+                        tf::errors::InvalidArgument("Unknown merge_mode: ", merge_mode_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("num_groups", &num_groups_));
+            OP_REQUIRES(ctx, num_groups_ >= 1 && num_groups_ <= beam_width_,
+                        tf::errors::InvalidArgument("num_groups must be between 1 and beam_width: ", num_groups_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("diversity_penalty", &diversity_penalty_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("beam_threshold", &beam_threshold_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("max_active", &max_active_));
         }
 
         void Compute(tf::OpKernelContext *ctx) override {
@@ -74,6 +155,11 @@ namespace ssnt {
             const tf::Tensor *u;
             const tf::Tensor *input_length;
             const tf::Tensor *output_length;
+            const tf::Tensor *speaking_rate;
+            const tf::Tensor *min_duration;
+            const tf::Tensor *max_duration;
+            const tf::Tensor *length_prior_mean;
+            const tf::Tensor *length_prior_stddev;
             OP_REQUIRES_OK(ctx, ctx->input("h", &h));
             OP_REQUIRES_OK(ctx, ctx->input("log_prob_history", &log_prob_history));
             OP_REQUIRES_OK(ctx, ctx->input("is_finished", &is_finished));
@@ -83,6 +169,11 @@ namespace ssnt {
             OP_REQUIRES_OK(ctx, ctx->input("u", &u));
             OP_REQUIRES_OK(ctx, ctx->input("input_length", &input_length));
             OP_REQUIRES_OK(ctx, ctx->input("output_length", &output_length));
+            OP_REQUIRES_OK(ctx, ctx->input("speaking_rate", &speaking_rate));
+            OP_REQUIRES_OK(ctx, ctx->input("min_duration", &min_duration));
+            OP_REQUIRES_OK(ctx, ctx->input("max_duration", &max_duration));
+            OP_REQUIRES_OK(ctx, ctx->input("length_prior_mean", &length_prior_mean));
+            OP_REQUIRES_OK(ctx, ctx->input("length_prior_stddev", &length_prior_stddev));
 
             OP_REQUIRES(ctx, h->shape().dims() == 3,
                         tf::errors::InvalidArgument("h is not a 3D-Tensor"));
@@ -102,6 +193,18 @@ namespace ssnt {
                         tf::errors::InvalidArgument("input_length is not 1D-Tensor"));
             OP_REQUIRES(ctx, output_length->shape().dims() == 1,
                         tf::errors::InvalidArgument("output_length is not 1D-Tensor"));
+            OP_REQUIRES(ctx, speaking_rate->shape().dims() == 1,
+                        tf::errors::InvalidArgument("speaking_rate is not 1D-Tensor"));
+            OP_REQUIRES(ctx, min_duration->shape().dims() == 2,
+                        tf::errors::InvalidArgument("min_duration is not 2D-Tensor"));
+            OP_REQUIRES(ctx, max_duration->shape().dims() == 2,
+                        tf::errors::InvalidArgument("max_duration is not 2D-Tensor"));
+            OP_REQUIRES(ctx, min_duration->shape() == max_duration->shape(),
+                        tf::errors::InvalidArgument("min_duration and max_duration have different shapes"));
+            OP_REQUIRES(ctx, length_prior_mean->shape().dims() == 1,
+                        tf::errors::InvalidArgument("length_prior_mean is not 1D-Tensor"));
+            OP_REQUIRES(ctx, length_prior_mean->shape() == length_prior_stddev->shape(),
+                        tf::errors::InvalidArgument("length_prior_mean and length_prior_stddev have different shapes"));
 
             // h: (B, W, D)
             OP_REQUIRES(ctx, h->shape().dim_size(1) == beam_width_,
@@ -141,6 +244,20 @@ namespace ssnt {
             auto u_t = u->tensor<int32_t, 2>();
             auto input_length_t = input_length->vec<int32_t>();
             auto output_length_t = output_length->vec<int32_t>();
+            auto speaking_rate_t = speaking_rate->vec<float>();
+            for (int b = 0; b < speaking_rate_t.size(); ++b) {
+                OP_REQUIRES(ctx, speaking_rate_t(b) > 0.0f,
+                            tf::errors::InvalidArgument("speaking_rate must be positive: ", speaking_rate_t(b)));
+            }
+            auto min_duration_t = min_duration->tensor<int32_t, 2>();
+            auto max_duration_t = max_duration->tensor<int32_t, 2>();
+            // Empty duration bounds mean no per-token constraint.
+            const bool has_duration_bounds = min_duration->NumElements() > 0;
+            const int max_t = min_duration->shape().dim_size(1);
+            auto length_prior_mean_t = length_prior_mean->vec<float>();
+            auto length_prior_stddev_t = length_prior_stddev->vec<float>();
+            // An empty length prior means no prior in free-length mode.
+            const bool has_length_prior = length_prior_mean->NumElements() > 0;
 
 
             tf::Tensor *prediction = nullptr;
@@ -176,6 +293,10 @@ namespace ssnt {
                                                      &beam_branch));
             auto beam_branch_t = beam_branch->tensor<int32_t, 2>();
 
+            tf::Tensor *num_valid = nullptr;
+            OP_REQUIRES_OK(ctx, ctx->allocate_output("num_valid", tf::TensorShape({batch_size}), &num_valid));
+            auto num_valid_t = num_valid->vec<int32_t>();
+
             ssnt_tts_v2_beam_search_decode(h_t.data(),
                                            log_prob_history_t.data(),
                                            is_finished_t.data(),
@@ -185,19 +306,47 @@ namespace ssnt {
                                            u_t.data(),
                                            input_length_t.data(),
                                            output_length_t.data(),
+                                           speaking_rate_t.data(),
+                                           has_duration_bounds ? min_duration_t.data() : nullptr,
+                                           has_duration_bounds ? max_duration_t.data() : nullptr,
+                                           max_t,
+                                           has_length_prior ? length_prior_mean_t.data() : nullptr,
+                                           has_length_prior ? length_prior_stddev_t.data() : nullptr,
                                            batch_size,
                                            beam_width_,
                                            duration_class_size_,
                                            zero_duration_id_,
                                            allow_skip_,
                                            test_mode_,
+                                           free_length_,
+                                           length_prior_weight_,
+                                           use_band_,
+                                           lower_band_ratio_,
+                                           upper_band_ratio_,
+                                           lower_band_frames_,
+                                           upper_band_frames_,
+                                           use_overrun_,
+                                           min_frames_per_token_,
+                                           use_diagonal_,
+                                           diagonal_lower_,
+                                           diagonal_upper_,
+                                           rate_bias_,
+                                           length_alpha_,
+                                           insertion_bonus_,
+                                           coverage_beta_,
+                                           merge_mode_,
+                                           num_groups_,
+                                           diversity_penalty_,
+                                           beam_threshold_,
+                                           max_active_,
                                            prediction_t.data(),
                                            log_prob_t.data(),
                                            next_t_t.data(),
                                            next_u_t.data(),
                                            next_is_finished_t.data(),
                                            next_total_duration_t.data(),
-                                           beam_branch_t.data());
+                                           beam_branch_t.data(),
+                                           num_valid_t.data());
 
         }
 
@@ -207,6 +356,27 @@ namespace ssnt {
         int zero_duration_id_;
         bool allow_skip_;
         bool test_mode_;
+        bool free_length_;
+        float length_prior_weight_;
+        bool use_band_;
+        float lower_band_ratio_;
+        float upper_band_ratio_;
+        float lower_band_frames_;
+        float upper_band_frames_;
+        bool use_overrun_;
+        int min_frames_per_token_;
+        bool use_diagonal_;
+        float diagonal_lower_;
+        float diagonal_upper_;
+        float rate_bias_;
+        float length_alpha_;
+        float insertion_bonus_;
+        float coverage_beta_;
+        int merge_mode_;
+        int num_groups_;
+        float diversity_penalty_;
+        float beam_threshold_;
+        int max_active_;
 
         void SetZeroDuration(tf::Tensor *t) {
             t->flat<int32_t>().setConstant(zero_duration_id_);
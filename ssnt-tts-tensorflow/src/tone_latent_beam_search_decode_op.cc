@@ -7,17 +7,34 @@ extern "C" void tone_latent_beam_search_decode(const float *h,
                                                const bool *is_finished,
                                                const int *t,
                                                const int *u,
+                                               const int *lm_context,
                                                const int *input_length,
+                                               const bool *allowed_tones,
+                                               const bool *tonal,
+                                               int max_t,
+                                               const int *previous_tone,
                                                int batch_size,
                                                int beam_width,
                                                int tone_class_size,
                                                int empty_tone_id,
+                                               const float *transition,
+                                               float length_alpha,
+                                               float insertion_bonus,
+                                               float coverage_beta,
+                                               int num_groups,
+                                               float diversity_penalty,
+                                               float beam_threshold,
+                                               int max_active,
+                                               const void *lm,
+                                               float lm_weight,
                                                int *prediction,
                                                float *log_prob,
                                                int *next_t,
                                                int *next_u,
                                                bool *next_is_finished,
-                                               int *beam_branch);
+                                               int *beam_branch,
+                                               int *next_lm_context,
+                                               int *num_valid);
 
 
 REGISTER_OP("ToneLatentBeamSearchDecode")
@@ -27,15 +44,27 @@ REGISTER_OP("ToneLatentBeamSearchDecode")
         .Input("t: int32")
         .Input("u: int32")
         .Input("input_length: int32")
+        .Input("allowed_tones: bool")
+        .Input("tonal: bool")
+        .Input("previous_tone: int32")
+        .Input("transition: float32")
         .Attr("beam_width: int")
         .Attr("tone_class_size: int")
         .Attr("empty_tone_id: int")
+        .Attr("length_alpha: float = 0.0")
+        .Attr("insertion_bonus: float = 0.0")
+        .Attr("coverage_beta: float = 0.0")
+        .Attr("num_groups: int = 1")
+        .Attr("diversity_penalty: float = 0.0")
+        .Attr("beam_threshold: float = -1.0")
+        .Attr("max_active: int = 0")
         .Output("prediction: int32")
         .Output("log_prob: float32")
         .Output("next_t: int32")
         .Output("next_u: int32")
         .Output("next_is_finished: bool")
-        .Output("beam_branch: int32");
+        .Output("beam_branch: int32")
+        .Output("num_valid: int32");
 
 namespace tf = tensorflow;
 
@@ -47,6 +76,15 @@ namespace ssnt {
             OP_REQUIRES_OK(ctx, ctx->GetAttr("beam_width", &beam_width_));
             OP_REQUIRES_OK(ctx, ctx->GetAttr("tone_class_size", &tone_class_size_));
             OP_REQUIRES_OK(ctx, ctx->GetAttr("empty_tone_id", &empty_tone_id_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("length_alpha", &length_alpha_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("insertion_bonus", &insertion_bonus_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("coverage_beta", &coverage_beta_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("num_groups", &num_groups_));
+            OP_REQUIRES(ctx, num_groups_ >= 1 && num_groups_ <= beam_width_,
+                        tf::errors::InvalidArgument("num_groups must be between 1 and beam_width: ", num_groups_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("diversity_penalty", &diversity_penalty_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("beam_threshold", &beam_threshold_));
+            OP_REQUIRES_OK(ctx, ctx->GetAttr("max_active", &max_active_));
         }
 
         void Compute(tf::OpKernelContext *ctx) override {
@@ -57,12 +95,20 @@ namespace ssnt {
             const tf::Tensor *t;
             const tf::Tensor *u;
             const tf::Tensor *input_length;
+            const tf::Tensor *allowed_tones;
+            const tf::Tensor *tonal;
+            const tf::Tensor *previous_tone;
+            const tf::Tensor *transition;
             OP_REQUIRES_OK(ctx, ctx->input("h", &h));
             OP_REQUIRES_OK(ctx, ctx->input("log_prob_history", &log_prob_history));
             OP_REQUIRES_OK(ctx, ctx->input("is_finished", &is_finished));
             OP_REQUIRES_OK(ctx, ctx->input("t", &t));
             OP_REQUIRES_OK(ctx, ctx->input("u", &u));
             OP_REQUIRES_OK(ctx, ctx->input("input_length", &input_length));
+            OP_REQUIRES_OK(ctx, ctx->input("allowed_tones", &allowed_tones));
+            OP_REQUIRES_OK(ctx, ctx->input("tonal", &tonal));
+            OP_REQUIRES_OK(ctx, ctx->input("previous_tone", &previous_tone));
+            OP_REQUIRES_OK(ctx, ctx->input("transition", &transition));
 
             OP_REQUIRES(ctx, h->shape().dims() == 3,
                         tf::errors::InvalidArgument("h is not a 3D-Tensor"));
@@ -76,6 +122,28 @@ namespace ssnt {
                         tf::errors::InvalidArgument("u is not 2D-Tensor"));
             OP_REQUIRES(ctx, input_length->shape().dims() == 1,
                         tf::errors::InvalidArgument("input_length is not 1D-Tensor"));
+            OP_REQUIRES(ctx, allowed_tones->shape().dims() == 3,
+                        tf::errors::InvalidArgument("allowed_tones is not 3D-Tensor"));
+            // allowed_tones: (B, T, C)
+            OP_REQUIRES(ctx, allowed_tones->shape().dim_size(2) == tone_class_size_,
+                        tf::errors::InvalidArgument("allowed_tones does not have tone class size: ", tone_class_size_));
+            OP_REQUIRES(ctx, tonal->shape().dims() == 2,
+                        tf::errors::InvalidArgument("tonal is not 2D-Tensor"));
+            OP_REQUIRES(ctx, allowed_tones->NumElements() == 0 || tonal->NumElements() == 0 ||
+                             allowed_tones->shape().dim_size(1) == tonal->shape().dim_size(1),
+                        tf::errors::InvalidArgument("allowed_tones and tonal have different lengths"));
+            OP_REQUIRES(ctx, previous_tone->shape().dims() == 2,
+                        tf::errors::InvalidArgument("previous_tone is not 2D-Tensor"));
+            OP_REQUIRES(ctx, transition->shape().dims() == 2,
+                        tf::errors::InvalidArgument("transition is not 2D-Tensor"));
+            // transition: (C, C)
+            OP_REQUIRES(ctx, transition->NumElements() == 0 ||
+                             (transition->shape().dim_size(0) == tone_class_size_ &&
+                              transition->shape().dim_size(1) == tone_class_size_),
+                        tf::errors::InvalidArgument("transition is not a square matrix of tone class size: ", tone_class_size_));
+            // previous_tone: (B, W)
+            OP_REQUIRES(ctx, transition->NumElements() == 0 || previous_tone->shape().dim_size(1) == beam_width_,
+                        tf::errors::InvalidArgument("previous_tone does not have beam width: ", beam_width_));
 
             // h: (B, W, D)
             OP_REQUIRES(ctx, h->shape().dim_size(1) == beam_width_,
@@ -110,6 +178,16 @@ namespace ssnt {
             auto t_t = t->tensor<int32_t, 2>();
             auto u_t = u->tensor<int32_t, 2>();
             auto input_length_t = input_length->vec<int32_t>();
+            auto allowed_tones_t = allowed_tones->tensor<bool, 3>();
+            auto tonal_t = tonal->tensor<bool, 2>();
+            // An empty mask allows every tone class, and an empty tonal mask treats the empty tone as an ordinary class.
+            const bool has_allowed_tones = allowed_tones->NumElements() > 0;
+            const bool has_tonal = tonal->NumElements() > 0;
+            const int max_t = has_allowed_tones ? allowed_tones->shape().dim_size(1) : tonal->shape().dim_size(1);
+            auto previous_tone_t = previous_tone->tensor<int32_t, 2>();
+            auto transition_t = transition->tensor<float, 2>();
+            // The previous tones are only read with a transition matrix.
+            const bool has_transition = transition->NumElements() > 0;
 
 
             tf::Tensor *prediction = nullptr;
@@ -140,22 +218,44 @@ namespace ssnt {
                                                      &beam_branch));
             auto beam_branch_t = beam_branch->tensor<int32_t, 2>();
 
+            tf::Tensor *num_valid = nullptr;
+            OP_REQUIRES_OK(ctx, ctx->allocate_output("num_valid", tf::TensorShape({batch_size}), &num_valid));
+            auto num_valid_t = num_valid->vec<int32_t>();
+
             tone_latent_beam_search_decode(h_t.data(),
                                            log_prob_history_t.data(),
                                            is_finished_t.data(),
                                            t_t.data(),
                                            u_t.data(),
+                                           nullptr,
                                            input_length_t.data(),
+                                           has_allowed_tones ? allowed_tones_t.data() : nullptr,
+                                           has_tonal ? tonal_t.data() : nullptr,
+                                           max_t,
+                                           has_transition ? previous_tone_t.data() : nullptr,
                                            batch_size,
                                            beam_width_,
                                            tone_class_size_,
                                            empty_tone_id_,
+                                           has_transition ? transition_t.data() : nullptr,
+                                           length_alpha_,
+                                           insertion_bonus_,
+                                           coverage_beta_,
+                                           num_groups_,
+                                           diversity_penalty_,
+                                           beam_threshold_,
+                                           max_active_,
+                                           // LM fusion is only available through the C API.
+                                           nullptr,
+                                           0.0f,
                                            prediction_t.data(),
                                            log_prob_t.data(),
                                            next_t_t.data(),
                                            next_u_t.data(),
                                            next_is_finished_t.data(),
-                                           beam_branch_t.data());
+                                           beam_branch_t.data(),
+                                           nullptr,
+                                           num_valid_t.data());
 
         }
 
@@ -163,6 +263,13 @@ namespace ssnt {
         int beam_width_;
         int tone_class_size_;
         int empty_tone_id_;
+        float length_alpha_;
+        float insertion_bonus_;
+        float coverage_beta_;
+        int num_groups_;
+        float diversity_penalty_;
+        float beam_threshold_;
+        int max_active_;
 
         void SetZeroDuration(tf::Tensor *t) {
             t->flat<int32_t>().setConstant(empty_tone_id_);
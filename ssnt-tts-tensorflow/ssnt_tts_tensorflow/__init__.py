@@ -4,20 +4,41 @@ import tensorflow as tf
 lib_file = imp.find_module('kernels', __path__)[1]
 _ssnt = tf.load_op_library(lib_file)
 
+# Combination of hypotheses reaching the same lattice state. 'none' keeps every path.
+_MERGE_MODES = {'none': 0, 'max': 1, 'logsumexp': 2}
 
-def beam_search_decode(h, log_prob_history, is_finished, t, u, max_t, beam_width):
-    prediction, log_prob, next_t, next_u, is_finished, beam_branch = _ssnt.ssnt_beam_search_decode(h,
+
+# None disables score-relative pruning, which the ops expect as a negative threshold.
+def _beam_threshold(beam_threshold):
+    return -1.0 if beam_threshold is None else float(beam_threshold)
+
+
+def beam_search_decode(h, log_prob_history, is_finished, t, u, max_t, beam_width,
+                       length_alpha=0.0, insertion_bonus=0.0, coverage_beta=0.0, merge_mode='none',
+                       num_groups=1, diversity_penalty=0.0, beam_threshold=None, max_active=0, return_num_valid=False):
+    prediction, log_prob, next_t, next_u, is_finished, beam_branch, num_valid = _ssnt.ssnt_beam_search_decode(h,
                                                                                                    log_prob_history,
                                                                                                    is_finished,
                                                                                                    t, u,
                                                                                                    max_t,
-                                                                                                   beam_width)
+                                                                                                   beam_width,
+                                                                                                   length_alpha=length_alpha,
+                                                                                                   insertion_bonus=insertion_bonus,
+                                                                                                   coverage_beta=coverage_beta,
+                                                                                                   merge_mode=_MERGE_MODES[merge_mode],
+                                                                                                   num_groups=num_groups,
+                                                                                                   diversity_penalty=diversity_penalty,
+                                                                                                   beam_threshold=_beam_threshold(beam_threshold),
+                                                                                                   max_active=max_active)
     prediction.set_shape(tf.TensorShape([beam_width]))
     log_prob.set_shape(tf.TensorShape([beam_width]))
     next_t.set_shape(tf.TensorShape([beam_width]))
     next_u.set_shape(tf.TensorShape([beam_width]))
     is_finished.set_shape(tf.TensorShape([beam_width]))
     beam_branch.set_shape(tf.TensorShape([beam_width]))
+    num_valid.set_shape(tf.TensorShape([]))
+    if return_num_valid:
+        return prediction, log_prob, next_t, next_u, is_finished, beam_branch, num_valid
     return prediction, log_prob, next_t, next_u, is_finished, beam_branch
 
 
@@ -30,6 +51,23 @@ def extract_best_beam_branch(best_final_branch, beam_branch, t_history, beam_wid
     return best_beam_branch, best_t_history
 
 
+def extract_best_path(best_final_branch, final_step, beam_branch, t_history, prediction, beam_width, pad_value=-1):
+    best_beam_branch, best_t_history, best_prediction = _ssnt.ssnt_extract_best_path(best_final_branch,
+                                                                                     final_step,
+                                                                                     beam_branch,
+                                                                                     t_history,
+                                                                                     prediction,
+                                                                                     beam_width,
+                                                                                     pad_value)
+    beam_branch_shape = beam_branch.get_shape()
+    batch_size = beam_branch_shape[0].value
+    max_u = beam_branch_shape[1].value
+    best_beam_branch.set_shape([batch_size, max_u])
+    best_t_history.set_shape([batch_size, max_u])
+    best_prediction.set_shape([batch_size, max_u])
+    return best_beam_branch, best_t_history, best_prediction
+
+
 def ssnt_tts_v2_beam_search_decode(h,
                                    log_prob_history,
                                    is_finished,
@@ -43,9 +81,48 @@ def ssnt_tts_v2_beam_search_decode(h,
                                    duration_class_size,
                                    zero_duration_id,
                                    allow_skip,
-                                   test_mode):
-    output_length = tf.zeros_like(input_length) if test_mode else output_length
-    prediction, log_prob, next_t, next_u, next_is_finished, next_total_duration, beam_branch = _ssnt.ssntv2_beam_search_decode(
+                                   test_mode,
+                                   use_band=True,
+                                   lower_band_ratio=0.05,
+                                   upper_band_ratio=0.1,
+                                   lower_band_frames=0.0,
+                                   upper_band_frames=0.0,
+                                   use_overrun=True,
+                                   min_frames_per_token=3,
+                                   use_diagonal=True,
+                                   diagonal_lower=-20.0,
+                                   diagonal_upper=0.0,
+                                   speaking_rate=None,
+                                   rate_bias=0.0,
+                                   min_duration=None,
+                                   max_duration=None,
+                                   free_length=False,
+                                   length_prior_mean=None,
+                                   length_prior_stddev=None,
+                                   length_prior_weight=1.0,
+                                   length_alpha=0.0,
+                                   insertion_bonus=0.0,
+                                   coverage_beta=0.0,
+                                   merge_mode='none',
+                                   num_groups=1,
+                                   diversity_penalty=0.0,
+                                   beam_threshold=None,
+                                   max_active=0,
+                                   return_num_valid=False):
+    output_length = tf.zeros_like(input_length) if test_mode or free_length else output_length
+    if length_prior_mean is None or length_prior_stddev is None:
+        length_prior_mean = tf.zeros([0], dtype=tf.float32)
+        length_prior_stddev = tf.zeros([0], dtype=tf.float32)
+    speaking_rate = tf.ones_like(input_length, dtype=tf.float32) if speaking_rate is None else speaking_rate
+    # Negative bounds are ignored, and empty bounds disable per-token constraints.
+    if min_duration is None and max_duration is None:
+        min_duration = tf.zeros([h.shape[0].value, 0], dtype=tf.int32)
+        max_duration = tf.zeros([h.shape[0].value, 0], dtype=tf.int32)
+    elif min_duration is None:
+        min_duration = -tf.ones_like(max_duration, dtype=tf.int32)
+    elif max_duration is None:
+        max_duration = -tf.ones_like(min_duration, dtype=tf.int32)
+    prediction, log_prob, next_t, next_u, next_is_finished, next_total_duration, beam_branch, num_valid = _ssnt.ssntv2_beam_search_decode(
         h,
         log_prob_history,
         is_finished,
@@ -55,11 +132,37 @@ def ssnt_tts_v2_beam_search_decode(h,
         u,
         tf.cast(input_length, dtype=tf.int32),
         tf.cast(output_length, dtype=tf.int32),
+        tf.cast(speaking_rate, dtype=tf.float32),
+        tf.cast(min_duration, dtype=tf.int32),
+        tf.cast(max_duration, dtype=tf.int32),
+        tf.cast(length_prior_mean, dtype=tf.float32),
+        tf.cast(length_prior_stddev, dtype=tf.float32),
         beam_width,
         duration_class_size,
         zero_duration_id,
         allow_skip,
-        test_mode)
+        test_mode,
+        free_length=free_length,
+        length_prior_weight=length_prior_weight,
+        use_band=use_band,
+        lower_band_ratio=lower_band_ratio,
+        upper_band_ratio=upper_band_ratio,
+        lower_band_frames=lower_band_frames,
+        upper_band_frames=upper_band_frames,
+        use_overrun=use_overrun,
+        min_frames_per_token=min_frames_per_token,
+        use_diagonal=use_diagonal,
+        diagonal_lower=diagonal_lower,
+        diagonal_upper=diagonal_upper,
+        rate_bias=rate_bias,
+        length_alpha=length_alpha,
+        insertion_bonus=insertion_bonus,
+        coverage_beta=coverage_beta,
+        merge_mode=_MERGE_MODES[merge_mode],
+        num_groups=num_groups,
+        diversity_penalty=diversity_penalty,
+        beam_threshold=_beam_threshold(beam_threshold),
+        max_active=max_active)
 
     batch_size = h.shape[0].value
     prediction.set_shape(tf.TensorShape([batch_size, beam_width]))
@@ -69,7 +172,10 @@ def ssnt_tts_v2_beam_search_decode(h,
     next_is_finished.set_shape(tf.TensorShape([batch_size, beam_width]))
     next_total_duration.set_shape(tf.TensorShape([batch_size, beam_width]))
     beam_branch.set_shape(tf.TensorShape([batch_size, beam_width]))
+    num_valid.set_shape(tf.TensorShape([batch_size]))
 
+    if return_num_valid:
+        return prediction, log_prob, next_t, next_u, next_is_finished, next_total_duration, beam_branch, num_valid
     return prediction, log_prob, next_t, next_u, next_is_finished, next_total_duration, beam_branch
 
 
@@ -104,17 +210,52 @@ def tone_latent_beam_search_decode(h,
                                    input_length,
                                    beam_width,
                                    tone_class_size,
-                                   empty_tone_id):
-    prediction, log_prob, next_t, next_u, next_is_finished, beam_branch = _ssnt.tone_latent_beam_search_decode(
+                                   empty_tone_id,
+                                   length_alpha=0.0,
+                                   insertion_bonus=0.0,
+                                   coverage_beta=0.0,
+                                   num_groups=1,
+                                   diversity_penalty=0.0,
+                                   beam_threshold=None,
+                                   max_active=0,
+                                   allowed_tones=None,
+                                   tonal=None,
+                                   previous_tone=None,
+                                   transition=None,
+                                   return_num_valid=False):
+    # An empty mask allows every tone class at every position.
+    if allowed_tones is None:
+        allowed_tones = tf.zeros([h.shape[0].value, 0, tone_class_size], dtype=tf.bool)
+    # Non-tonal positions are forced to empty_tone_id, and tonal ones never take it.
+    if tonal is None:
+        tonal = tf.zeros([h.shape[0].value, 0], dtype=tf.bool)
+    # previous_tone holds the last prediction of every beam, negative before the first one, and is only used with a transition matrix.
+    if transition is None:
+        transition = tf.zeros([0, 0], dtype=tf.float32)
+        previous_tone = tf.zeros([h.shape[0].value, 0], dtype=tf.int32)
+    elif previous_tone is None:
+        previous_tone = -tf.ones([h.shape[0].value, beam_width], dtype=tf.int32)
+    prediction, log_prob, next_t, next_u, next_is_finished, beam_branch, num_valid = _ssnt.tone_latent_beam_search_decode(
         h,
         log_prob_history,
         is_finished,
         t,
         u,
         tf.cast(input_length, dtype=tf.int32),
+        tf.cast(allowed_tones, dtype=tf.bool),
+        tf.cast(tonal, dtype=tf.bool),
+        tf.cast(previous_tone, dtype=tf.int32),
+        tf.cast(transition, dtype=tf.float32),
         beam_width,
         tone_class_size,
-        empty_tone_id)
+        empty_tone_id,
+        length_alpha=length_alpha,
+        insertion_bonus=insertion_bonus,
+        coverage_beta=coverage_beta,
+        num_groups=num_groups,
+        diversity_penalty=diversity_penalty,
+        beam_threshold=_beam_threshold(beam_threshold),
+        max_active=max_active)
 
     batch_size = h.shape[0].value
     prediction.set_shape(tf.TensorShape([batch_size, beam_width]))
@@ -123,7 +264,10 @@ def tone_latent_beam_search_decode(h,
     next_u.set_shape(tf.TensorShape([batch_size, beam_width]))
     next_is_finished.set_shape(tf.TensorShape([batch_size, beam_width]))
     beam_branch.set_shape(tf.TensorShape([batch_size, beam_width]))
+    num_valid.set_shape(tf.TensorShape([batch_size]))
 
+    if return_num_valid:
+        return prediction, log_prob, next_t, next_u, next_is_finished, beam_branch, num_valid
     return prediction, log_prob, next_t, next_u, next_is_finished, beam_branch
 
 
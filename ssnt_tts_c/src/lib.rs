@@ -1,14 +1,25 @@
 extern crate ssnt_tts;
 extern crate libc;
 
-use ssnt_tts::{SsntTts, SsntTtsCpu, util, v2, v2_util, tone_latent, edit_distance};
-use libc::c_float;
+use ssnt_tts::{SsntTts, SsntTtsCpu, util, v2, v2_util, tone_latent, tone_duration, edit_distance, alignment, forward_backward, v2_duration, n_best};
+use ssnt_tts::v2_duration::DurationSequence;
+use libc::{c_char, c_float, c_void};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::sync::Arc;
 use ssnt_tts::v2::SsntTtsV2;
 use ssnt_tts::tone_latent::{ToneLatent, ToneLatentCpu};
+use ssnt_tts::tone_duration::ToneDuration;
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::merge::MergeMode;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::sampling::SamplingPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+use ssnt_tts::ngram::{NGramModel, LmFusion};
 
 
 #[no_mangle]
-pub extern fn ssnt_tts_beam_search_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, t: *const i32, u: *const i32, max_t: i32, beam_width: i32, prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, beam_branch: *mut i32) -> () {
+pub extern fn ssnt_tts_beam_search_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, t: *const i32, u: *const i32, max_t: i32, beam_width: i32, length_alpha: c_float, insertion_bonus: c_float, coverage_beta: c_float, merge_mode: i32, num_groups: i32, diversity_penalty: c_float, beam_threshold: c_float, max_active: i32, prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, beam_branch: *mut i32, num_valid: *mut i32) -> () {
     // Restricted to single batch.
     let batch_size = 1;
     let n_transition_classes = 2;
@@ -78,8 +89,96 @@ pub extern fn ssnt_tts_beam_search_decode(h: *const c_float, log_prob_history: *
         std::slice::from_raw_parts_mut(beam_branch, beam_branch_len as usize)
     };
 
-    let ssnt_tts = SsntTtsCpu::new(batch_size, max_t as usize, 0 as usize);
-    ssnt_tts.beam_search_decode(h, log_prob_history, is_finished, t, u, beam_width, beam_width, prediction, log_probs, next_t, next_u, next_is_finished, beam_branch);
+    let num_valid = unsafe {
+        assert!(!num_valid.is_null());
+        let num_valid_len = batch_size;
+        std::slice::from_raw_parts_mut(num_valid, num_valid_len as usize)
+    };
+
+    let scoring = ScoringPolicy::new(length_alpha, insertion_bonus, coverage_beta);
+    // Unknown merge modes are rejected by the ops, and decode without merging otherwise.
+    let merge_mode = MergeMode::from_id(merge_mode).unwrap_or_default();
+    let ssnt_tts = SsntTtsCpu::new(batch_size, max_t as usize, 0 as usize, scoring, merge_mode, DiversityPolicy::from_attr(num_groups, beam_width, diversity_penalty).unwrap_or_default(), PruningPolicy::new(beam_threshold, max_active as usize));
+    ssnt_tts.beam_search_decode(h, log_prob_history, is_finished, t, u, beam_width, beam_width, prediction, log_probs, next_t, next_u, next_is_finished, beam_branch, num_valid, None);
+}
+
+
+#[no_mangle]
+pub extern fn ssnt_tts_decode(transition_log_prob: extern fn(*const i32, *const i32, i32, i32, *mut c_float, *mut c_void), user_data: *mut c_void, max_t: i32, max_u: i32, beam_width: i32, length_alpha: c_float, insertion_bonus: c_float, coverage_beta: c_float, merge_mode: i32, num_groups: i32, diversity_penalty: c_float, beam_threshold: c_float, max_active: i32, prediction: *mut i32, t_history: *mut i32, log_probs: *mut c_float, output_length: *mut i32, is_finished: *mut bool, num_valid: *mut i32) -> () {
+    // Restricted to single batch.
+    let batch_size = 1;
+    let n_transition_classes = 2;
+
+    let prediction = unsafe {
+        assert!(!prediction.is_null());
+        let prediction_len = batch_size * beam_width * max_u;
+        std::slice::from_raw_parts_mut(prediction, prediction_len as usize)
+    };
+
+    let t_history = unsafe {
+        assert!(!t_history.is_null());
+        let t_history_len = batch_size * beam_width * max_u;
+        std::slice::from_raw_parts_mut(t_history, t_history_len as usize)
+    };
+
+    let log_probs = unsafe {
+        assert!(!log_probs.is_null());
+        let log_probs_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(log_probs, log_probs_len as usize)
+    };
+
+    let output_length = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(output_length, output_length_len as usize)
+    };
+
+    let is_finished = unsafe {
+        assert!(!is_finished.is_null());
+        let is_finished_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(is_finished, is_finished_len as usize)
+    };
+
+    let num_valid = unsafe {
+        assert!(!num_valid.is_null());
+        let num_valid_len = batch_size;
+        std::slice::from_raw_parts_mut(num_valid, num_valid_len as usize)
+    };
+
+    // The callback writes (W, 2) Emit/Shift log-probs for the given parent branches, t and u.
+    let mut model = |beam_branch: &[usize], t: &[usize], u: usize| -> Vec<f32> {
+        let beam_branch: Vec<i32> = beam_branch.iter().map(|v| *v as i32).collect();
+        let t: Vec<i32> = t.iter().map(|v| *v as i32).collect();
+        let mut h: Vec<f32> = vec![0.0; (beam_width * n_transition_classes) as usize];
+        transition_log_prob(beam_branch.as_ptr(), t.as_ptr(), u as i32, beam_width, h.as_mut_ptr(), user_data);
+        h
+    };
+
+    let scoring = ScoringPolicy::new(length_alpha, insertion_bonus, coverage_beta);
+    // Unknown merge modes decode without merging.
+    let merge_mode = MergeMode::from_id(merge_mode).unwrap_or_default();
+    let ssnt_tts = SsntTtsCpu::new(batch_size, max_t as usize, max_u as usize, scoring, merge_mode, DiversityPolicy::from_attr(num_groups, beam_width, diversity_penalty).unwrap_or_default(), PruningPolicy::new(beam_threshold, max_active as usize));
+    let hypotheses = ssnt_tts.decode(&mut model, beam_width, None);
+
+    // Frames after the end of each hypothesis are padded with -1, and so are the rows after the last hypothesis.
+    prediction.iter_mut().for_each(|v| *v = -1);
+    t_history.iter_mut().for_each(|v| *v = -1);
+    log_probs.iter_mut().for_each(|v| *v = f32::NEG_INFINITY);
+    output_length.iter_mut().for_each(|v| *v = 0);
+    is_finished.iter_mut().for_each(|v| *v = false);
+    prediction.chunks_mut(max_u as usize)
+        .zip(t_history.chunks_mut(max_u as usize))
+        .zip(hypotheses.iter())
+        .enumerate()
+        .for_each(|(w, ((prediction, t_history), hypothesis))| {
+            let length = hypothesis.prediction.len();
+            prediction[..length].copy_from_slice(hypothesis.prediction.as_slice());
+            t_history[..length].copy_from_slice(hypothesis.t_history.as_slice());
+            log_probs[w] = hypothesis.log_prob;
+            output_length[w] = length as i32;
+            is_finished[w] = hypothesis.is_finished;
+        });
+    num_valid[0] = hypotheses.len() as i32;
 }
 
 
@@ -115,8 +214,229 @@ pub extern fn ssnt_extract_best_beam_branch(best_final_branch: i32, beam_branch:
     best_t_history.copy_from_slice(_best_t_history.as_slice());
 }
 
+
+#[no_mangle]
+pub extern fn ssnt_extract_best_path(best_final_branch: *const i32, final_step: *const i32, beam_branch: *const i32, t_history: *const i32, prediction: *const i32,
+                                     batch_size: i32, beam_width: i32, max_u: i32, pad_value: i32,
+                                     best_beam_branch: *mut i32, best_t_history: *mut i32, best_prediction: *mut i32) -> () {
+    let best_final_branch = unsafe {
+        assert!(!best_final_branch.is_null());
+        std::slice::from_raw_parts(best_final_branch, batch_size as usize)
+    };
+
+    let final_step = unsafe {
+        assert!(!final_step.is_null());
+        std::slice::from_raw_parts(final_step, batch_size as usize)
+    };
+
+    let beam_branch = unsafe {
+        assert!(!beam_branch.is_null());
+        let beam_branch_len = batch_size * max_u * beam_width;
+        std::slice::from_raw_parts(beam_branch, beam_branch_len as usize)
+    };
+
+    let t_history = unsafe {
+        assert!(!t_history.is_null());
+        let t_history_len = batch_size * max_u * beam_width;
+        std::slice::from_raw_parts(t_history, t_history_len as usize)
+    };
+
+    let prediction = unsafe {
+        assert!(!prediction.is_null());
+        let prediction_len = batch_size * max_u * beam_width;
+        std::slice::from_raw_parts(prediction, prediction_len as usize)
+    };
+
+    let best_beam_branch = unsafe {
+        assert!(!best_beam_branch.is_null());
+        let best_beam_branch_len = batch_size * max_u;
+        std::slice::from_raw_parts_mut(best_beam_branch, best_beam_branch_len as usize)
+    };
+
+    let best_t_history = unsafe {
+        assert!(!best_t_history.is_null());
+        let best_t_history_len = batch_size * max_u;
+        std::slice::from_raw_parts_mut(best_t_history, best_t_history_len as usize)
+    };
+
+    let best_prediction = unsafe {
+        assert!(!best_prediction.is_null());
+        let best_prediction_len = batch_size * max_u;
+        std::slice::from_raw_parts_mut(best_prediction, best_prediction_len as usize)
+    };
+
+    util::extract_best_path(best_final_branch, final_step, beam_branch, t_history, prediction, beam_width, max_u, pad_value,
+                            best_beam_branch, best_t_history, best_prediction);
+}
+
+#[no_mangle]
+pub extern fn ssnt_tts_v2_beam_search_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, total_duration: *const i32, duration_table: *const i32, t: *const i32, u: *const i32, input_length: *const i32, output_length: *const i32, speaking_rate: *const c_float, min_duration: *const i32, max_duration: *const i32, max_t: i32, length_prior_mean: *const c_float, length_prior_stddev: *const c_float, batch_size: i32, beam_width: i32, duration_class_size: i32, zero_duration_id: i32, allow_skip: bool, test_mode: bool, free_length: bool, length_prior_weight: c_float, use_band: bool, lower_band_ratio: c_float, upper_band_ratio: c_float, lower_band_frames: c_float, upper_band_frames: c_float, use_overrun: bool, min_frames_per_token: i32, use_diagonal: bool, diagonal_lower: c_float, diagonal_upper: c_float, rate_bias: c_float, length_alpha: c_float, insertion_bonus: c_float, coverage_beta: c_float, merge_mode: i32, num_groups: i32, diversity_penalty: c_float, beam_threshold: c_float, max_active: i32, prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, next_total_duration: *mut i32, beam_branch: *mut i32, num_valid: *mut i32) -> () {
+    let h = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * beam_width * duration_class_size;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let log_prob_history = unsafe {
+        assert!(!log_prob_history.is_null());
+        let log_prob_history_len = batch_size * beam_width;
+        std::slice::from_raw_parts(log_prob_history, log_prob_history_len as usize)
+    };
+
+    let is_finished = unsafe {
+        assert!(!is_finished.is_null());
+        let is_finished_len = batch_size * beam_width;
+        std::slice::from_raw_parts(is_finished, is_finished_len as usize)
+    };
+
+    let total_duration = unsafe {
+        assert!(!total_duration.is_null());
+        let total_duration_len = batch_size * beam_width;
+        std::slice::from_raw_parts(total_duration, total_duration_len as usize)
+    };
+
+    let duration_table = unsafe {
+        assert!(!duration_table.is_null());
+        let duration_table_len = duration_class_size;
+        std::slice::from_raw_parts(duration_table, duration_table_len as usize)
+    };
+
+    let t = unsafe {
+        assert!(!t.is_null());
+        let t_len = batch_size * beam_width;
+        std::slice::from_raw_parts(t, t_len as usize)
+    };
+
+    let u = unsafe {
+        assert!(!u.is_null());
+        let u_len = batch_size * beam_width;
+        std::slice::from_raw_parts(u, u_len as usize)
+    };
+
+    let input_length = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    let output_length = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size;
+        std::slice::from_raw_parts(output_length, output_length_len as usize)
+    };
+
+    let speaking_rate = unsafe {
+        assert!(!speaking_rate.is_null());
+        let speaking_rate_len = batch_size;
+        std::slice::from_raw_parts(speaking_rate, speaking_rate_len as usize)
+    };
+
+    // Per-token duration bounds are optional and passed as null pointers when absent.
+    let min_duration: Option<&[i32]> = if min_duration.is_null() {
+        None
+    } else {
+        let min_duration_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(min_duration, min_duration_len as usize) })
+    };
+
+    let max_duration: Option<&[i32]> = if max_duration.is_null() {
+        None
+    } else {
+        let max_duration_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(max_duration, max_duration_len as usize) })
+    };
+
+    let length_prior_mean: Option<&[f32]> = if length_prior_mean.is_null() {
+        None
+    } else {
+        let length_prior_mean_len = batch_size;
+        Some(unsafe { std::slice::from_raw_parts(length_prior_mean, length_prior_mean_len as usize) })
+    };
+
+    let length_prior_stddev: Option<&[f32]> = if length_prior_stddev.is_null() {
+        None
+    } else {
+        let length_prior_stddev_len = batch_size;
+        Some(unsafe { std::slice::from_raw_parts(length_prior_stddev, length_prior_stddev_len as usize) })
+    };
+
+    let prediction = unsafe {
+        assert!(!prediction.is_null());
+        let prediction_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(prediction, prediction_len as usize)
+    };
+
+    let log_probs = unsafe {
+        assert!(!log_probs.is_null());
+        let log_probs_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(log_probs, log_probs_len as usize)
+    };
+
+    let next_t = unsafe {
+        assert!(!next_t.is_null());
+        let next_t_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_t, next_t_len as usize)
+    };
+
+    let next_u = unsafe {
+        assert!(!next_u.is_null());
+        let next_u_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_u, next_u_len as usize)
+    };
+
+    let next_is_finished = unsafe {
+        assert!(!next_is_finished.is_null());
+        let next_is_finished_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_is_finished, next_is_finished_len as usize)
+    };
+
+    let next_total_duration = unsafe {
+        assert!(!next_total_duration.is_null());
+        let next_total_duration_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_total_duration, next_total_duration_len as usize)
+    };
+
+    let beam_branch = unsafe {
+        assert!(!beam_branch.is_null());
+        let beam_branch_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(beam_branch, beam_branch_len as usize)
+    };
+
+    let num_valid = unsafe {
+        assert!(!num_valid.is_null());
+        let num_valid_len = batch_size;
+        std::slice::from_raw_parts_mut(num_valid, num_valid_len as usize)
+    };
+
+    let scoring = ScoringPolicy::new(length_alpha, insertion_bonus, coverage_beta);
+
+    let constraints = v2::DurationConstraints {
+        use_band,
+        lower_band_ratio,
+        upper_band_ratio,
+        lower_band_frames,
+        upper_band_frames,
+        use_overrun,
+        min_frames_per_token: min_frames_per_token as usize,
+        use_diagonal,
+        diagonal_lower,
+        diagonal_upper,
+    };
+    let length_options = v2::LengthOptions {
+        free_length,
+        length_prior_weight,
+        rate_bias,
+    };
+
+    // Unknown merge modes are rejected by the ops, and decode without merging otherwise.
+    let merge_mode = MergeMode::from_id(merge_mode).unwrap_or_default();
+    let ssnt_tts = v2::SsntTtsV2Cpu::new(batch_size, duration_class_size as usize, zero_duration_id, allow_skip, test_mode, constraints, length_options, scoring, merge_mode, DiversityPolicy::from_attr(num_groups, beam_width, diversity_penalty).unwrap_or_default(), PruningPolicy::new(beam_threshold, max_active as usize));
+    ssnt_tts.beam_search_decode(h, log_prob_history, is_finished, total_duration, duration_table, t, u, input_length, output_length, speaking_rate, min_duration, max_duration, length_prior_mean, length_prior_stddev, batch_size, beam_width, beam_width, prediction, log_probs, next_t, next_u, next_is_finished, next_total_duration, beam_branch, num_valid, None);
+}
+
+
 #[no_mangle]
-pub extern fn ssnt_tts_v2_beam_search_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, total_duration: *const i32, duration_table: *const i32, t: *const i32, u: *const i32, input_length: *const i32, output_length: *const i32, batch_size: i32, beam_width: i32, duration_class_size: i32, zero_duration_id: i32, allow_skip: bool, test_mode: bool, prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, next_total_duration: *mut i32, beam_branch: *mut i32) -> () {
+pub extern fn ssnt_tts_v2_sample_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, total_duration: *const i32, duration_table: *const i32, t: *const i32, u: *const i32, input_length: *const i32, output_length: *const i32, speaking_rate: *const c_float, min_duration: *const i32, max_duration: *const i32, max_t: i32, length_prior_mean: *const c_float, length_prior_stddev: *const c_float, batch_size: i32, beam_width: i32, duration_class_size: i32, zero_duration_id: i32, allow_skip: bool, test_mode: bool, free_length: bool, length_prior_weight: c_float, use_band: bool, lower_band_ratio: c_float, upper_band_ratio: c_float, lower_band_frames: c_float, upper_band_frames: c_float, use_overrun: bool, min_frames_per_token: i32, use_diagonal: bool, diagonal_lower: c_float, diagonal_upper: c_float, rate_bias: c_float, temperature: c_float, top_k: i32, top_p: c_float, seed: u64, prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, next_total_duration: *mut i32, beam_branch: *mut i32) -> () {
     let h = unsafe {
         assert!(!h.is_null());
         let h_len = batch_size * beam_width * duration_class_size;
@@ -171,6 +491,41 @@ pub extern fn ssnt_tts_v2_beam_search_decode(h: *const c_float, log_prob_history
         std::slice::from_raw_parts(output_length, output_length_len as usize)
     };
 
+    let speaking_rate = unsafe {
+        assert!(!speaking_rate.is_null());
+        let speaking_rate_len = batch_size;
+        std::slice::from_raw_parts(speaking_rate, speaking_rate_len as usize)
+    };
+
+    // Per-token duration bounds are optional and passed as null pointers when absent.
+    let min_duration: Option<&[i32]> = if min_duration.is_null() {
+        None
+    } else {
+        let min_duration_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(min_duration, min_duration_len as usize) })
+    };
+
+    let max_duration: Option<&[i32]> = if max_duration.is_null() {
+        None
+    } else {
+        let max_duration_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(max_duration, max_duration_len as usize) })
+    };
+
+    let length_prior_mean: Option<&[f32]> = if length_prior_mean.is_null() {
+        None
+    } else {
+        let length_prior_mean_len = batch_size;
+        Some(unsafe { std::slice::from_raw_parts(length_prior_mean, length_prior_mean_len as usize) })
+    };
+
+    let length_prior_stddev: Option<&[f32]> = if length_prior_stddev.is_null() {
+        None
+    } else {
+        let length_prior_stddev_len = batch_size;
+        Some(unsafe { std::slice::from_raw_parts(length_prior_stddev, length_prior_stddev_len as usize) })
+    };
+
     let prediction = unsafe {
         assert!(!prediction.is_null());
         let prediction_len = batch_size * beam_width;
@@ -213,8 +568,27 @@ pub extern fn ssnt_tts_v2_beam_search_decode(h: *const c_float, log_prob_history
         std::slice::from_raw_parts_mut(beam_branch, beam_branch_len as usize)
     };
 
-    let ssnt_tts = v2::SsntTtsV2Cpu::new(batch_size, duration_class_size as usize, zero_duration_id, allow_skip, test_mode);
-    ssnt_tts.beam_search_decode(h, log_prob_history, is_finished, total_duration, duration_table, t, u, input_length, output_length, batch_size, beam_width, beam_width, prediction, log_probs, next_t, next_u, next_is_finished, next_total_duration, beam_branch);
+    let constraints = v2::DurationConstraints {
+        use_band,
+        lower_band_ratio,
+        upper_band_ratio,
+        lower_band_frames,
+        upper_band_frames,
+        use_overrun,
+        min_frames_per_token: min_frames_per_token as usize,
+        use_diagonal,
+        diagonal_lower,
+        diagonal_upper,
+    };
+    let length_options = v2::LengthOptions {
+        free_length,
+        length_prior_weight,
+        rate_bias,
+    };
+
+    let ssnt_tts = v2::SsntTtsV2Cpu::new(batch_size, duration_class_size as usize, zero_duration_id, allow_skip, test_mode, constraints, length_options, ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    let policy = SamplingPolicy::new(temperature, top_k as usize, top_p);
+    ssnt_tts.sample_decode(h, log_prob_history, is_finished, total_duration, duration_table, t, u, input_length, output_length, speaking_rate, min_duration, max_duration, length_prior_mean, length_prior_stddev, batch_size, beam_width, policy, seed, prediction, log_probs, next_t, next_u, next_is_finished, next_total_duration, beam_branch);
 }
 
 #[no_mangle]
@@ -265,7 +639,7 @@ pub extern fn ssnt_upsample_source_indexes(duration: *const i32, output_length:
 }
 
 #[no_mangle]
-pub extern fn tone_latent_beam_search_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, t: *const i32, u: *const i32, input_length: *const i32, batch_size: i32, beam_width: i32, tone_class_size: i32, empty_tone_id: i32, prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, beam_branch: *mut i32) -> () {
+pub extern fn tone_latent_beam_search_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, t: *const i32, u: *const i32, lm_context: *const i32, input_length: *const i32, allowed_tones: *const bool, tonal: *const bool, max_t: i32, previous_tone: *const i32, batch_size: i32, beam_width: i32, tone_class_size: i32, empty_tone_id: i32, transition: *const c_float, length_alpha: c_float, insertion_bonus: c_float, coverage_beta: c_float, num_groups: i32, diversity_penalty: c_float, beam_threshold: c_float, max_active: i32, lm: *const c_void, lm_weight: c_float, prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, beam_branch: *mut i32, next_lm_context: *mut i32, num_valid: *mut i32) -> () {
     let h = unsafe {
         assert!(!h.is_null());
         let h_len = batch_size * beam_width * tone_class_size;
@@ -302,6 +676,37 @@ pub extern fn tone_latent_beam_search_decode(h: *const c_float, log_prob_history
         std::slice::from_raw_parts(input_length, input_length_len as usize)
     };
 
+    // The (B, max_t, C) allowed-tone mask is optional and passed as a null pointer when absent.
+    let allowed_tones: Option<&[bool]> = if allowed_tones.is_null() {
+        None
+    } else {
+        let allowed_tones_len = batch_size * max_t * tone_class_size;
+        Some(unsafe { std::slice::from_raw_parts(allowed_tones, allowed_tones_len as usize) })
+    };
+
+    // Without the (B, max_t) tonal mask the empty tone is an ordinary class.
+    let tonal: Option<&[bool]> = if tonal.is_null() {
+        None
+    } else {
+        let tonal_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(tonal, tonal_len as usize) })
+    };
+
+    // The last tone of each beam is only needed with a transition matrix, and both are passed as null pointers when absent.
+    let previous_tone: Option<&[i32]> = if previous_tone.is_null() {
+        None
+    } else {
+        let previous_tone_len = batch_size * beam_width;
+        Some(unsafe { std::slice::from_raw_parts(previous_tone, previous_tone_len as usize) })
+    };
+
+    let transition: Option<Vec<f32>> = if transition.is_null() {
+        None
+    } else {
+        let transition_len = tone_class_size * tone_class_size;
+        Some(unsafe { std::slice::from_raw_parts(transition, transition_len as usize) }.to_vec())
+    };
+
     let prediction = unsafe {
         assert!(!prediction.is_null());
         let prediction_len = batch_size * beam_width;
@@ -338,44 +743,851 @@ pub extern fn tone_latent_beam_search_decode(h: *const c_float, log_prob_history
         std::slice::from_raw_parts_mut(beam_branch, beam_branch_len as usize)
     };
 
-    let tone_latent: ToneLatentCpu = tone_latent::ToneLatentCpu::new(batch_size, tone_class_size as usize, empty_tone_id);
-    tone_latent.beam_search_decode(h, log_prob_history, is_finished, t, u, input_length, batch_size, beam_width, beam_width, prediction, log_probs, next_t, next_u, next_is_finished, beam_branch);
+    let num_valid = unsafe {
+        assert!(!num_valid.is_null());
+        let num_valid_len = batch_size;
+        std::slice::from_raw_parts_mut(num_valid, num_valid_len as usize)
+    };
+
+    let fusion = lm_fusion(lm, lm_weight);
+    let lm_context_len = (batch_size * beam_width) as usize * fusion.as_ref().map_or(0, |f| f.context_size());
+    let lm_context: &[i32] = lm_context_slice(lm_context, lm_context_len);
+    let next_lm_context: &mut [i32] = lm_context_slice_mut(next_lm_context, lm_context_len);
+
+    let scoring = ScoringPolicy::new(length_alpha, insertion_bonus, coverage_beta);
+    let tone_latent: ToneLatentCpu = tone_latent::ToneLatentCpu::new(batch_size, tone_class_size as usize, empty_tone_id, scoring, DiversityPolicy::from_attr(num_groups, beam_width, diversity_penalty).unwrap_or_default(), PruningPolicy::new(beam_threshold, max_active as usize), fusion, transition);
+    tone_latent.beam_search_decode(h, log_prob_history, is_finished, t, u, lm_context, input_length, allowed_tones, tonal, previous_tone, batch_size, beam_width, beam_width, prediction, log_probs, next_t, next_u, next_is_finished, beam_branch, next_lm_context, num_valid, None);
 }
 
 
 #[no_mangle]
-pub extern fn tone_latent_levenshtein_edit_distance(a: *const i32, b: *const i32, a_lengths: *const i32, b_lengths: *const i32, batch_size: i32, max_length: i32, distance: *mut i32) -> () {
-    let a: &[i32] = unsafe {
-        assert!(!a.is_null());
-        let a_len = batch_size * max_length;
-        std::slice::from_raw_parts(a, a_len as usize)
+pub extern fn tone_latent_sample_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, t: *const i32, u: *const i32, lm_context: *const i32, input_length: *const i32, allowed_tones: *const bool, tonal: *const bool, max_t: i32, previous_tone: *const i32, batch_size: i32, beam_width: i32, tone_class_size: i32, empty_tone_id: i32, transition: *const c_float, temperature: c_float, top_k: i32, top_p: c_float, seed: u64, lm: *const c_void, lm_weight: c_float, prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, beam_branch: *mut i32, next_lm_context: *mut i32) -> () {
+    let h = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * beam_width * tone_class_size;
+        std::slice::from_raw_parts(h, h_len as usize)
     };
 
-    let b: &[i32] = unsafe {
-        assert!(!b.is_null());
-        let b_len = batch_size * max_length;
-        std::slice::from_raw_parts(b, b_len as usize)
+    let log_prob_history = unsafe {
+        assert!(!log_prob_history.is_null());
+        let log_prob_history_len = batch_size * beam_width;
+        std::slice::from_raw_parts(log_prob_history, log_prob_history_len as usize)
     };
 
-    let a_lengths: &[i32] = unsafe {
-        assert!(!a_lengths.is_null());
-        let a_lengths_len = batch_size;
-        std::slice::from_raw_parts(a_lengths, a_lengths_len as usize)
+    let is_finished = unsafe {
+        assert!(!is_finished.is_null());
+        let is_finished_len = batch_size * beam_width;
+        std::slice::from_raw_parts(is_finished, is_finished_len as usize)
     };
 
-    let b_lengths: &[i32] = unsafe {
-        assert!(!b_lengths.is_null());
-        let b_lengths_len = batch_size;
-        std::slice::from_raw_parts(b_lengths, b_lengths_len as usize)
+    let t = unsafe {
+        assert!(!t.is_null());
+        let t_len = batch_size * beam_width;
+        std::slice::from_raw_parts(t, t_len as usize)
     };
 
-    let distance: &mut [i32] = unsafe {
-        assert!(!distance.is_null());
-        let distance_len = batch_size;
-        std::slice::from_raw_parts_mut(distance, distance_len as usize)
+    let u = unsafe {
+        assert!(!u.is_null());
+        let u_len = batch_size * beam_width;
+        std::slice::from_raw_parts(u, u_len as usize)
     };
 
-    let led: Vec<i32> = edit_distance::levenshtein_edit_distance(a, b, a_lengths, b_lengths,
-                                                                 batch_size as usize, max_length as usize);
-    distance.copy_from_slice(&led);
-}
\ No newline at end of file
+    let input_length = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    // The (B, max_t, C) allowed-tone mask is optional and passed as a null pointer when absent.
+    let allowed_tones: Option<&[bool]> = if allowed_tones.is_null() {
+        None
+    } else {
+        let allowed_tones_len = batch_size * max_t * tone_class_size;
+        Some(unsafe { std::slice::from_raw_parts(allowed_tones, allowed_tones_len as usize) })
+    };
+
+    // Without the (B, max_t) tonal mask the empty tone is an ordinary class.
+    let tonal: Option<&[bool]> = if tonal.is_null() {
+        None
+    } else {
+        let tonal_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(tonal, tonal_len as usize) })
+    };
+
+    // The last tone of each beam is only needed with a transition matrix, and both are passed as null pointers when absent.
+    let previous_tone: Option<&[i32]> = if previous_tone.is_null() {
+        None
+    } else {
+        let previous_tone_len = batch_size * beam_width;
+        Some(unsafe { std::slice::from_raw_parts(previous_tone, previous_tone_len as usize) })
+    };
+
+    let transition: Option<Vec<f32>> = if transition.is_null() {
+        None
+    } else {
+        let transition_len = tone_class_size * tone_class_size;
+        Some(unsafe { std::slice::from_raw_parts(transition, transition_len as usize) }.to_vec())
+    };
+
+    let prediction = unsafe {
+        assert!(!prediction.is_null());
+        let prediction_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(prediction, prediction_len as usize)
+    };
+
+    let log_probs = unsafe {
+        assert!(!log_probs.is_null());
+        let log_probs_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(log_probs, log_probs_len as usize)
+    };
+
+    let next_t = unsafe {
+        assert!(!next_t.is_null());
+        let next_t_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_t, next_t_len as usize)
+    };
+
+    let next_u = unsafe {
+        assert!(!next_u.is_null());
+        let next_u_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_u, next_u_len as usize)
+    };
+
+    let next_is_finished = unsafe {
+        assert!(!next_is_finished.is_null());
+        let next_is_finished_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_is_finished, next_is_finished_len as usize)
+    };
+
+    let beam_branch = unsafe {
+        assert!(!beam_branch.is_null());
+        let beam_branch_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(beam_branch, beam_branch_len as usize)
+    };
+
+    let fusion = lm_fusion(lm, lm_weight);
+    let lm_context_len = (batch_size * beam_width) as usize * fusion.as_ref().map_or(0, |f| f.context_size());
+    let lm_context: &[i32] = lm_context_slice(lm_context, lm_context_len);
+    let next_lm_context: &mut [i32] = lm_context_slice_mut(next_lm_context, lm_context_len);
+
+    let policy = SamplingPolicy::new(temperature, top_k as usize, top_p);
+    let tone_latent: ToneLatentCpu = tone_latent::ToneLatentCpu::new(batch_size, tone_class_size as usize, empty_tone_id, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), fusion, transition);
+    tone_latent.sample_decode(h, log_prob_history, is_finished, t, u, lm_context, input_length, allowed_tones, tonal, previous_tone, batch_size, beam_width, policy, seed, prediction, log_probs, next_t, next_u, next_is_finished, beam_branch, next_lm_context);
+}
+
+
+#[no_mangle]
+pub extern fn tone_latent_marginal(h: *const c_float, input_length: *const i32, observed_tones: *const bool, tonal: *const bool, empty_tone_id: i32, batch_size: i32, max_t: i32, tone_class_size: i32, log_likelihood: *mut c_float, posterior: *mut c_float) -> () {
+    let h: &[f32] = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * max_t * tone_class_size;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let input_length: &[i32] = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    // The (B, max_t, C) observed-tone mask is optional and passed as a null pointer when no tone is observed.
+    let observed_tones: Option<&[bool]> = if observed_tones.is_null() {
+        None
+    } else {
+        let observed_tones_len = batch_size * max_t * tone_class_size;
+        Some(unsafe { std::slice::from_raw_parts(observed_tones, observed_tones_len as usize) })
+    };
+
+    // Without the (B, max_t) tonal mask the empty tone is an ordinary class.
+    let tonal: Option<&[bool]> = if tonal.is_null() {
+        None
+    } else {
+        let tonal_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(tonal, tonal_len as usize) })
+    };
+
+    let log_likelihood: &mut [f32] = unsafe {
+        assert!(!log_likelihood.is_null());
+        let log_likelihood_len = batch_size;
+        std::slice::from_raw_parts_mut(log_likelihood, log_likelihood_len as usize)
+    };
+
+    let posterior: &mut [f32] = unsafe {
+        assert!(!posterior.is_null());
+        let posterior_len = batch_size * max_t * tone_class_size;
+        std::slice::from_raw_parts_mut(posterior, posterior_len as usize)
+    };
+
+    tone_latent::tone_marginal(h, input_length, observed_tones, tonal, empty_tone_id, batch_size as usize, max_t as usize, tone_class_size as usize, log_likelihood, posterior);
+}
+
+
+#[no_mangle]
+pub extern fn tone_latent_viterbi_decode(h: *const c_float, input_length: *const i32, transition: *const c_float, allowed_tones: *const bool, tonal: *const bool, empty_tone_id: i32, batch_size: i32, max_t: i32, tone_class_size: i32, prediction: *mut i32, log_prob: *mut c_float) -> () {
+    let h: &[f32] = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * max_t * tone_class_size;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let input_length: &[i32] = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    // Without a transition matrix every position is decoded independently.
+    let transition: Option<&[f32]> = if transition.is_null() {
+        None
+    } else {
+        let transition_len = tone_class_size * tone_class_size;
+        Some(unsafe { std::slice::from_raw_parts(transition, transition_len as usize) })
+    };
+
+    let allowed_tones: Option<&[bool]> = if allowed_tones.is_null() {
+        None
+    } else {
+        let allowed_tones_len = batch_size * max_t * tone_class_size;
+        Some(unsafe { std::slice::from_raw_parts(allowed_tones, allowed_tones_len as usize) })
+    };
+
+    // Without the (B, max_t) tonal mask the empty tone is an ordinary class.
+    let tonal: Option<&[bool]> = if tonal.is_null() {
+        None
+    } else {
+        let tonal_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(tonal, tonal_len as usize) })
+    };
+
+    let prediction: &mut [i32] = unsafe {
+        assert!(!prediction.is_null());
+        let prediction_len = batch_size * max_t;
+        std::slice::from_raw_parts_mut(prediction, prediction_len as usize)
+    };
+
+    let log_prob: &mut [f32] = unsafe {
+        assert!(!log_prob.is_null());
+        let log_prob_len = batch_size;
+        std::slice::from_raw_parts_mut(log_prob, log_prob_len as usize)
+    };
+
+    tone_latent::tone_viterbi_decode(h, input_length, transition, allowed_tones, tonal, empty_tone_id, batch_size as usize, max_t as usize, tone_class_size as usize, prediction, log_prob);
+}
+
+
+#[no_mangle]
+pub extern fn tone_duration_beam_search_decode(h: *const c_float, log_prob_history: *const c_float, is_finished: *const bool, total_duration: *const i32, duration_table: *const i32, t: *const i32, u: *const i32, input_length: *const i32, output_length: *const i32, speaking_rate: *const c_float, min_duration: *const i32, max_duration: *const i32, allowed_tones: *const bool, tonal: *const bool, max_t: i32, batch_size: i32, beam_width: i32, tone_class_size: i32, empty_tone_id: i32, duration_class_size: i32, zero_duration_id: i32, allow_skip: bool, test_mode: bool, use_band: bool, lower_band_ratio: c_float, upper_band_ratio: c_float, lower_band_frames: c_float, upper_band_frames: c_float, use_overrun: bool, min_frames_per_token: i32, use_diagonal: bool, diagonal_lower: c_float, diagonal_upper: c_float, rate_bias: c_float, length_alpha: c_float, insertion_bonus: c_float, coverage_beta: c_float, num_groups: i32, diversity_penalty: c_float, beam_threshold: c_float, max_active: i32, tone_prediction: *mut i32, duration_prediction: *mut i32, log_probs: *mut c_float, next_t: *mut i32, next_u: *mut i32, next_is_finished: *mut bool, next_total_duration: *mut i32, beam_branch: *mut i32, num_valid: *mut i32) -> () {
+    let h = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * beam_width * tone_class_size * duration_class_size;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let log_prob_history = unsafe {
+        assert!(!log_prob_history.is_null());
+        let log_prob_history_len = batch_size * beam_width;
+        std::slice::from_raw_parts(log_prob_history, log_prob_history_len as usize)
+    };
+
+    let is_finished = unsafe {
+        assert!(!is_finished.is_null());
+        let is_finished_len = batch_size * beam_width;
+        std::slice::from_raw_parts(is_finished, is_finished_len as usize)
+    };
+
+    let total_duration = unsafe {
+        assert!(!total_duration.is_null());
+        let total_duration_len = batch_size * beam_width;
+        std::slice::from_raw_parts(total_duration, total_duration_len as usize)
+    };
+
+    let duration_table = unsafe {
+        assert!(!duration_table.is_null());
+        let duration_table_len = duration_class_size;
+        std::slice::from_raw_parts(duration_table, duration_table_len as usize)
+    };
+
+    let t = unsafe {
+        assert!(!t.is_null());
+        let t_len = batch_size * beam_width;
+        std::slice::from_raw_parts(t, t_len as usize)
+    };
+
+    let u = unsafe {
+        assert!(!u.is_null());
+        let u_len = batch_size * beam_width;
+        std::slice::from_raw_parts(u, u_len as usize)
+    };
+
+    let input_length = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    let output_length = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size;
+        std::slice::from_raw_parts(output_length, output_length_len as usize)
+    };
+
+    let speaking_rate = unsafe {
+        assert!(!speaking_rate.is_null());
+        let speaking_rate_len = batch_size;
+        std::slice::from_raw_parts(speaking_rate, speaking_rate_len as usize)
+    };
+
+    // Per-token duration bounds and tone masks are optional and passed as null pointers when absent.
+    let min_duration: Option<&[i32]> = if min_duration.is_null() {
+        None
+    } else {
+        let min_duration_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(min_duration, min_duration_len as usize) })
+    };
+
+    let max_duration: Option<&[i32]> = if max_duration.is_null() {
+        None
+    } else {
+        let max_duration_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(max_duration, max_duration_len as usize) })
+    };
+
+    let allowed_tones: Option<&[bool]> = if allowed_tones.is_null() {
+        None
+    } else {
+        let allowed_tones_len = batch_size * max_t * tone_class_size;
+        Some(unsafe { std::slice::from_raw_parts(allowed_tones, allowed_tones_len as usize) })
+    };
+
+    let tonal: Option<&[bool]> = if tonal.is_null() {
+        None
+    } else {
+        let tonal_len = batch_size * max_t;
+        Some(unsafe { std::slice::from_raw_parts(tonal, tonal_len as usize) })
+    };
+
+    let tone_prediction = unsafe {
+        assert!(!tone_prediction.is_null());
+        let tone_prediction_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(tone_prediction, tone_prediction_len as usize)
+    };
+
+    let duration_prediction = unsafe {
+        assert!(!duration_prediction.is_null());
+        let duration_prediction_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(duration_prediction, duration_prediction_len as usize)
+    };
+
+    let log_probs = unsafe {
+        assert!(!log_probs.is_null());
+        let log_probs_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(log_probs, log_probs_len as usize)
+    };
+
+    let next_t = unsafe {
+        assert!(!next_t.is_null());
+        let next_t_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_t, next_t_len as usize)
+    };
+
+    let next_u = unsafe {
+        assert!(!next_u.is_null());
+        let next_u_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_u, next_u_len as usize)
+    };
+
+    let next_is_finished = unsafe {
+        assert!(!next_is_finished.is_null());
+        let next_is_finished_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_is_finished, next_is_finished_len as usize)
+    };
+
+    let next_total_duration = unsafe {
+        assert!(!next_total_duration.is_null());
+        let next_total_duration_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(next_total_duration, next_total_duration_len as usize)
+    };
+
+    let beam_branch = unsafe {
+        assert!(!beam_branch.is_null());
+        let beam_branch_len = batch_size * beam_width;
+        std::slice::from_raw_parts_mut(beam_branch, beam_branch_len as usize)
+    };
+
+    let num_valid = unsafe {
+        assert!(!num_valid.is_null());
+        let num_valid_len = batch_size;
+        std::slice::from_raw_parts_mut(num_valid, num_valid_len as usize)
+    };
+
+    let scoring = ScoringPolicy::new(length_alpha, insertion_bonus, coverage_beta);
+
+    let constraints = v2::DurationConstraints {
+        use_band,
+        lower_band_ratio,
+        upper_band_ratio,
+        lower_band_frames,
+        upper_band_frames,
+        use_overrun,
+        min_frames_per_token: min_frames_per_token as usize,
+        use_diagonal,
+        diagonal_lower,
+        diagonal_upper,
+    };
+
+    let options = tone_duration::ToneDurationOptions {
+        tone_class_size: tone_class_size as usize,
+        empty_tone_id,
+        duration_class_size: duration_class_size as usize,
+        zero_duration_id,
+        allow_skip,
+        test_mode,
+        rate_bias,
+    };
+
+    let decoder = tone_duration::ToneDurationCpu::new(batch_size, options, constraints, scoring, DiversityPolicy::from_attr(num_groups, beam_width, diversity_penalty).unwrap_or_default(), PruningPolicy::new(beam_threshold, max_active as usize));
+    decoder.beam_search_decode(h, log_prob_history, is_finished, total_duration, duration_table, t, u, input_length, output_length, speaking_rate, min_duration, max_duration, allowed_tones, tonal, batch_size, beam_width, beam_width, tone_prediction, duration_prediction, log_probs, next_t, next_u, next_is_finished, next_total_duration, beam_branch, num_valid);
+}
+
+
+thread_local! {
+    // Why the last tone_latent_load_lm call on this thread failed. None if it succeeded.
+    static LM_LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+// Loads an ARPA n-gram model once so that decode calls can reference it by handle.
+// Returns null if the model cannot be loaded, and tone_latent_lm_last_error tells why.
+#[no_mangle]
+pub extern fn tone_latent_load_lm(path: *const c_char) -> *mut c_void {
+    let path = unsafe {
+        assert!(!path.is_null());
+        CStr::from_ptr(path)
+    };
+    let lm = match path.to_str() {
+        Ok(path) => NGramModel::load_arpa(path).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    match lm {
+        Ok(lm) => {
+            LM_LAST_ERROR.with(|error| *error.borrow_mut() = None);
+            Box::into_raw(Box::new(Arc::new(lm))) as *mut c_void
+        }
+        Err(e) => {
+            LM_LAST_ERROR.with(|error| *error.borrow_mut() = Some(CString::new(e).unwrap_or_default()));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+// Error message of the last tone_latent_load_lm call on the calling thread, or null if it succeeded.
+// The message is owned by the library and stays valid until the next tone_latent_load_lm call on the same thread.
+#[no_mangle]
+pub extern fn tone_latent_lm_last_error() -> *const c_char {
+    LM_LAST_ERROR.with(|error| error.borrow().as_ref().map_or(std::ptr::null(), |message| message.as_ptr()))
+}
+
+#[no_mangle]
+pub extern fn tone_latent_free_lm(lm: *mut c_void) -> () {
+    if !lm.is_null() {
+        unsafe { drop(Box::from_raw(lm as *mut Arc<NGramModel>)) };
+    }
+}
+
+// Number of preceding tones each beam carries in lm_context. 0 for a null handle.
+#[no_mangle]
+pub extern fn tone_latent_lm_context_size(lm: *const c_void) -> i32 {
+    lm_fusion(lm, 0.0).map_or(0, |fusion| fusion.context_size() as i32)
+}
+
+fn lm_fusion(lm: *const c_void, lm_weight: c_float) -> Option<LmFusion> {
+    if lm.is_null() {
+        None
+    } else {
+        let lm = unsafe { &*(lm as *const Arc<NGramModel>) };
+        Some(LmFusion::new(lm.clone(), lm_weight))
+    }
+}
+
+// LM contexts are empty and may be null pointers without an LM.
+fn lm_context_slice<'a>(lm_context: *const i32, lm_context_len: usize) -> &'a [i32] {
+    if lm_context_len == 0 {
+        return &[];
+    }
+    unsafe {
+        assert!(!lm_context.is_null());
+        std::slice::from_raw_parts(lm_context, lm_context_len)
+    }
+}
+
+fn lm_context_slice_mut<'a>(lm_context: *mut i32, lm_context_len: usize) -> &'a mut [i32] {
+    if lm_context_len == 0 {
+        return &mut [];
+    }
+    unsafe {
+        assert!(!lm_context.is_null());
+        std::slice::from_raw_parts_mut(lm_context, lm_context_len)
+    }
+}
+
+
+#[no_mangle]
+pub extern fn tone_latent_levenshtein_edit_distance(a: *const i32, b: *const i32, a_lengths: *const i32, b_lengths: *const i32, batch_size: i32, max_length: i32, distance: *mut i32) -> () {
+    let a: &[i32] = unsafe {
+        assert!(!a.is_null());
+        let a_len = batch_size * max_length;
+        std::slice::from_raw_parts(a, a_len as usize)
+    };
+
+    let b: &[i32] = unsafe {
+        assert!(!b.is_null());
+        let b_len = batch_size * max_length;
+        std::slice::from_raw_parts(b, b_len as usize)
+    };
+
+    let a_lengths: &[i32] = unsafe {
+        assert!(!a_lengths.is_null());
+        let a_lengths_len = batch_size;
+        std::slice::from_raw_parts(a_lengths, a_lengths_len as usize)
+    };
+
+    let b_lengths: &[i32] = unsafe {
+        assert!(!b_lengths.is_null());
+        let b_lengths_len = batch_size;
+        std::slice::from_raw_parts(b_lengths, b_lengths_len as usize)
+    };
+
+    let distance: &mut [i32] = unsafe {
+        assert!(!distance.is_null());
+        let distance_len = batch_size;
+        std::slice::from_raw_parts_mut(distance, distance_len as usize)
+    };
+
+    let led: Vec<i32> = edit_distance::levenshtein_edit_distance(a, b, a_lengths, b_lengths,
+                                                                 batch_size as usize, max_length as usize);
+    distance.copy_from_slice(&led);
+}
+
+
+#[no_mangle]
+pub extern fn ssnt_viterbi_alignment(h: *const c_float, input_length: *const i32, output_length: *const i32, batch_size: i32, max_t: i32, max_u: i32, t_history: *mut i32, duration: *mut i32, log_probs: *mut c_float) -> () {
+    let n_transition_classes = 2;
+    let h: &[f32] = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * max_u * max_t * n_transition_classes;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let input_length: &[i32] = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    let output_length: &[i32] = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size;
+        std::slice::from_raw_parts(output_length, output_length_len as usize)
+    };
+
+    let t_history: &mut [i32] = unsafe {
+        assert!(!t_history.is_null());
+        let t_history_len = batch_size * max_u;
+        std::slice::from_raw_parts_mut(t_history, t_history_len as usize)
+    };
+
+    let duration: &mut [i32] = unsafe {
+        assert!(!duration.is_null());
+        let duration_len = batch_size * max_t;
+        std::slice::from_raw_parts_mut(duration, duration_len as usize)
+    };
+
+    let log_probs: &mut [f32] = unsafe {
+        assert!(!log_probs.is_null());
+        let log_probs_len = batch_size;
+        std::slice::from_raw_parts_mut(log_probs, log_probs_len as usize)
+    };
+
+    alignment::viterbi_alignment(h, input_length, output_length, batch_size as usize, max_t as usize, max_u as usize, t_history, duration, log_probs);
+}
+
+
+#[no_mangle]
+pub extern fn ssnt_tts_loss(h: *const c_float, input_length: *const i32, output_length: *const i32, batch_size: i32, max_t: i32, max_u: i32, loss: *mut c_float, grad: *mut c_float) -> () {
+    let n_transition_classes = 2;
+    let h: &[f32] = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * max_u * max_t * n_transition_classes;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let input_length: &[i32] = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    let output_length: &[i32] = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size;
+        std::slice::from_raw_parts(output_length, output_length_len as usize)
+    };
+
+    let loss: &mut [f32] = unsafe {
+        assert!(!loss.is_null());
+        let loss_len = batch_size;
+        std::slice::from_raw_parts_mut(loss, loss_len as usize)
+    };
+
+    let grad: &mut [f32] = unsafe {
+        assert!(!grad.is_null());
+        let grad_len = batch_size * max_u * max_t * n_transition_classes;
+        std::slice::from_raw_parts_mut(grad, grad_len as usize)
+    };
+
+    forward_backward::ssnt_loss(h, input_length, output_length, batch_size as usize, max_t as usize, max_u as usize, loss, grad);
+}
+
+
+#[no_mangle]
+pub extern fn ssnt_tts_alignment_posterior(h: *const c_float, input_length: *const i32, output_length: *const i32, batch_size: i32, max_t: i32, max_u: i32, occupancy: *mut c_float, expected_duration: *mut c_float) -> () {
+    let n_transition_classes = 2;
+    let h: &[f32] = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * max_u * max_t * n_transition_classes;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let input_length: &[i32] = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    let output_length: &[i32] = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size;
+        std::slice::from_raw_parts(output_length, output_length_len as usize)
+    };
+
+    let occupancy: &mut [f32] = unsafe {
+        assert!(!occupancy.is_null());
+        let occupancy_len = batch_size * max_u * max_t;
+        std::slice::from_raw_parts_mut(occupancy, occupancy_len as usize)
+    };
+
+    let expected_duration: &mut [f32] = unsafe {
+        assert!(!expected_duration.is_null());
+        let expected_duration_len = batch_size * max_t;
+        std::slice::from_raw_parts_mut(expected_duration, expected_duration_len as usize)
+    };
+
+    forward_backward::alignment_posterior(h, input_length, output_length, batch_size as usize, max_t as usize, max_u as usize, occupancy, expected_duration);
+}
+
+
+#[no_mangle]
+pub extern fn ssnt_tts_v2_duration_log_likelihood(h: *const c_float, duration_table: *const i32, input_length: *const i32, output_length: *const i32, batch_size: i32, max_t: i32, duration_class_size: i32, zero_duration_id: i32, allow_skip: bool, log_likelihood: *mut c_float) -> () {
+    let h: &[f32] = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * max_t * duration_class_size;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let duration_table: &[i32] = unsafe {
+        assert!(!duration_table.is_null());
+        let duration_table_len = duration_class_size;
+        std::slice::from_raw_parts(duration_table, duration_table_len as usize)
+    };
+
+    let input_length: &[i32] = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    let output_length: &[i32] = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size;
+        std::slice::from_raw_parts(output_length, output_length_len as usize)
+    };
+
+    let log_likelihood: &mut [f32] = unsafe {
+        assert!(!log_likelihood.is_null());
+        let log_likelihood_len = batch_size;
+        std::slice::from_raw_parts_mut(log_likelihood, log_likelihood_len as usize)
+    };
+
+    let model = v2_duration::DurationSequenceCpu::new(duration_class_size as usize, zero_duration_id, allow_skip);
+    model.log_likelihood(h, duration_table, input_length, output_length, batch_size as usize, max_t as usize, log_likelihood);
+}
+
+
+#[no_mangle]
+pub extern fn ssnt_tts_v2_duration_sample(h: *const c_float, duration_table: *const i32, input_length: *const i32, output_length: *const i32, batch_size: i32, max_t: i32, duration_class_size: i32, zero_duration_id: i32, allow_skip: bool, seed: u64, prediction: *mut i32, log_probs: *mut c_float) -> () {
+    let h: &[f32] = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * max_t * duration_class_size;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let duration_table: &[i32] = unsafe {
+        assert!(!duration_table.is_null());
+        let duration_table_len = duration_class_size;
+        std::slice::from_raw_parts(duration_table, duration_table_len as usize)
+    };
+
+    let input_length: &[i32] = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    let output_length: &[i32] = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size;
+        std::slice::from_raw_parts(output_length, output_length_len as usize)
+    };
+
+    let prediction: &mut [i32] = unsafe {
+        assert!(!prediction.is_null());
+        let prediction_len = batch_size * max_t;
+        std::slice::from_raw_parts_mut(prediction, prediction_len as usize)
+    };
+
+    let log_probs: &mut [f32] = unsafe {
+        assert!(!log_probs.is_null());
+        let log_probs_len = batch_size;
+        std::slice::from_raw_parts_mut(log_probs, log_probs_len as usize)
+    };
+
+    let model = v2_duration::DurationSequenceCpu::new(duration_class_size as usize, zero_duration_id, allow_skip);
+    model.sample(h, duration_table, input_length, output_length, batch_size as usize, max_t as usize, seed, prediction, log_probs);
+}
+
+
+#[no_mangle]
+pub extern fn ssnt_tts_v2_duration_n_best_decode(h: *const c_float, duration_table: *const i32, input_length: *const i32, output_length: *const i32, batch_size: i32, max_t: i32, duration_class_size: i32, zero_duration_id: i32, allow_skip: bool, n_best: i32, prediction: *mut i32, log_probs: *mut c_float, num_valid: *mut i32) -> () {
+    let h: &[f32] = unsafe {
+        assert!(!h.is_null());
+        let h_len = batch_size * max_t * duration_class_size;
+        std::slice::from_raw_parts(h, h_len as usize)
+    };
+
+    let duration_table: &[i32] = unsafe {
+        assert!(!duration_table.is_null());
+        let duration_table_len = duration_class_size;
+        std::slice::from_raw_parts(duration_table, duration_table_len as usize)
+    };
+
+    let input_length: &[i32] = unsafe {
+        assert!(!input_length.is_null());
+        let input_length_len = batch_size;
+        std::slice::from_raw_parts(input_length, input_length_len as usize)
+    };
+
+    let output_length: &[i32] = unsafe {
+        assert!(!output_length.is_null());
+        let output_length_len = batch_size;
+        std::slice::from_raw_parts(output_length, output_length_len as usize)
+    };
+
+    let prediction: &mut [i32] = unsafe {
+        assert!(!prediction.is_null());
+        let prediction_len = batch_size * n_best * max_t;
+        std::slice::from_raw_parts_mut(prediction, prediction_len as usize)
+    };
+
+    let log_probs: &mut [f32] = unsafe {
+        assert!(!log_probs.is_null());
+        let log_probs_len = batch_size * n_best;
+        std::slice::from_raw_parts_mut(log_probs, log_probs_len as usize)
+    };
+
+    let num_valid: &mut [i32] = unsafe {
+        assert!(!num_valid.is_null());
+        let num_valid_len = batch_size;
+        std::slice::from_raw_parts_mut(num_valid, num_valid_len as usize)
+    };
+
+    let model = v2_duration::DurationSequenceCpu::new(duration_class_size as usize, zero_duration_id, allow_skip);
+    model.n_best_decode(h, duration_table, input_length, output_length, batch_size as usize, max_t as usize, n_best as usize, prediction, log_probs, num_valid);
+}
+
+
+#[no_mangle]
+pub extern fn ssnt_tts_n_best(prediction_history: *const i32, beam_branch_history: *const i32, t_history: *const i32, is_finished_history: *const bool, log_probs: *const c_float, total_duration: *const i32, batch_size: i32, beam_width: i32, n_steps: i32, n_best: i32, length_alpha: c_float, insertion_bonus: c_float, coverage_beta: c_float, best_prediction: *mut i32, best_t_history: *mut i32, best_total_duration: *mut i32, best_log_probs: *mut c_float, best_scores: *mut c_float, best_length: *mut i32, num_valid: *mut i32) -> () {
+    let history_len = batch_size * n_steps * beam_width;
+    let prediction_history: &[i32] = unsafe {
+        assert!(!prediction_history.is_null());
+        std::slice::from_raw_parts(prediction_history, history_len as usize)
+    };
+
+    let beam_branch_history: &[i32] = unsafe {
+        assert!(!beam_branch_history.is_null());
+        std::slice::from_raw_parts(beam_branch_history, history_len as usize)
+    };
+
+    let t_history: &[i32] = unsafe {
+        assert!(!t_history.is_null());
+        std::slice::from_raw_parts(t_history, history_len as usize)
+    };
+
+    let is_finished_history: &[bool] = unsafe {
+        assert!(!is_finished_history.is_null());
+        std::slice::from_raw_parts(is_finished_history, history_len as usize)
+    };
+
+    let log_probs: &[f32] = unsafe {
+        assert!(!log_probs.is_null());
+        let log_probs_len = batch_size * beam_width;
+        std::slice::from_raw_parts(log_probs, log_probs_len as usize)
+    };
+
+    // Total durations are only tracked by the v2 decoder and passed as a null pointer otherwise.
+    let total_duration: Option<&[i32]> = if total_duration.is_null() {
+        None
+    } else {
+        let total_duration_len = batch_size * beam_width;
+        Some(unsafe { std::slice::from_raw_parts(total_duration, total_duration_len as usize) })
+    };
+
+    let best_history_len = batch_size * n_best * n_steps;
+    let best_prediction: &mut [i32] = unsafe {
+        assert!(!best_prediction.is_null());
+        std::slice::from_raw_parts_mut(best_prediction, best_history_len as usize)
+    };
+
+    let best_t_history: &mut [i32] = unsafe {
+        assert!(!best_t_history.is_null());
+        std::slice::from_raw_parts_mut(best_t_history, best_history_len as usize)
+    };
+
+    let best_total_duration: &mut [i32] = unsafe {
+        assert!(!best_total_duration.is_null());
+        std::slice::from_raw_parts_mut(best_total_duration, (batch_size * n_best) as usize)
+    };
+
+    let best_log_probs: &mut [f32] = unsafe {
+        assert!(!best_log_probs.is_null());
+        std::slice::from_raw_parts_mut(best_log_probs, (batch_size * n_best) as usize)
+    };
+
+    let best_scores: &mut [f32] = unsafe {
+        assert!(!best_scores.is_null());
+        std::slice::from_raw_parts_mut(best_scores, (batch_size * n_best) as usize)
+    };
+
+    let best_length: &mut [i32] = unsafe {
+        assert!(!best_length.is_null());
+        std::slice::from_raw_parts_mut(best_length, (batch_size * n_best) as usize)
+    };
+
+    let num_valid: &mut [i32] = unsafe {
+        assert!(!num_valid.is_null());
+        std::slice::from_raw_parts_mut(num_valid, batch_size as usize)
+    };
+
+    let scoring = ScoringPolicy::new(length_alpha, insertion_bonus, coverage_beta);
+    n_best::n_best(prediction_history, beam_branch_history, t_history, is_finished_history, log_probs, total_duration, batch_size as usize, beam_width as usize, n_steps as usize, n_best as usize, &scoring,
+                   best_prediction, best_t_history, best_total_duration, best_log_probs, best_scores, best_length, num_valid);
+}
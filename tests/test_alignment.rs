@@ -0,0 +1,84 @@
+extern crate ssnt_tts;
+
+
+use ssnt_tts::alignment::{viterbi_alignment_kernel, viterbi_alignment};
+
+
+// (U, T, 2) probabilities given as (Emit, Shift) pairs.
+fn log_transition(input: &Vec<Vec<(f32, f32)>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().flat_map(|(e, s)| vec![e.ln(), s.ln()])
+    }).collect()
+}
+
+#[test]
+fn viterbi_alignment_kernel_test() {
+    let h = log_transition(&vec![
+        vec![(0.2, 0.8), (0.5, 0.5), (0.5, 0.5)],
+        vec![(0.5, 0.5), (0.9, 0.1), (0.5, 0.5)],
+        vec![(0.5, 0.5), (0.4, 0.6), (0.5, 0.5)],
+        vec![(0.5, 0.5), (0.5, 0.5), (0.7, 0.3)],
+        vec![(0.5, 0.5), (0.5, 0.5), (0.6, 0.4)],
+    ]);
+    let (t_history, duration, log_prob) = viterbi_alignment_kernel(h.as_slice(), 3, 5, 3).unwrap();
+    // The last input position finishes on its first Emit, so it gets a single frame.
+    assert_eq!(t_history, vec![0, 1, 1, 1, 2]);
+    assert_eq!(duration, vec![1, 3, 1]);
+    let expected = 0.8f32.ln() + 0.9f32.ln() + 0.4f32.ln() + 0.5f32.ln() + 0.6f32.ln();
+    assert!((log_prob - expected).abs() < 1e-5);
+
+    // Output shorter than input, and more than one frame for a single input position.
+    assert_eq!(viterbi_alignment_kernel(h.as_slice(), 3, 2, 3), None);
+    assert_eq!(viterbi_alignment_kernel(h.as_slice(), 1, 2, 3), None);
+}
+
+#[test]
+fn viterbi_alignment_batched_test() {
+    let batch_size = 2;
+    let max_t = 3;
+    let max_u = 4;
+    let h: Vec<f32> = vec![
+        log_transition(&vec![
+            vec![(0.5, 0.5), (0.5, 0.5), (0.5, 0.5)],
+            vec![(0.5, 0.5), (0.5, 0.5), (0.5, 0.5)],
+            vec![(0.5, 0.5), (0.5, 0.5), (0.5, 0.5)],
+            vec![(0.5, 0.5), (0.5, 0.5), (0.5, 0.5)],
+        ]),
+        log_transition(&vec![
+            vec![(0.9, 0.1), (0.5, 0.5), (0.5, 0.5)],
+            vec![(0.1, 0.9), (0.5, 0.5), (0.5, 0.5)],
+            vec![(0.5, 0.5), (0.5, 0.5), (0.5, 0.5)],
+            vec![(0.5, 0.5), (0.5, 0.5), (0.5, 0.5)],
+        ]),
+    ].into_iter().flatten().collect();
+    let input_length = vec![3, 2];
+    let output_length = vec![3, 3];
+    let mut t_history = vec![0; batch_size * max_u];
+    let mut duration = vec![0; batch_size * max_t];
+    let mut log_probs = vec![0.0; batch_size];
+    viterbi_alignment(h.as_slice(), input_length.as_slice(), output_length.as_slice(), batch_size, max_t, max_u,
+                      t_history.as_mut_slice(), duration.as_mut_slice(), log_probs.as_mut_slice());
+    assert_eq!(t_history, vec![0, 1, 2, -1,
+                               0, 0, 1, -1]);
+    assert_eq!(duration, vec![1, 1, 1,
+                              2, 1, 0]);
+}
+
+#[test]
+fn viterbi_alignment_impossible_test() {
+    let batch_size = 2;
+    let max_t = 2;
+    let max_u = 2;
+    let h = log_transition(&vec![vec![(0.5, 0.5), (0.5, 0.5)]; batch_size * max_u]);
+    // The first item is longer than max_u, and the second one has less frames than tokens.
+    let input_length = vec![2, 2];
+    let output_length = vec![3, 1];
+    let mut t_history = vec![0; batch_size * max_u];
+    let mut duration = vec![0; batch_size * max_t];
+    let mut log_probs = vec![0.0; batch_size];
+    viterbi_alignment(h.as_slice(), input_length.as_slice(), output_length.as_slice(), batch_size, max_t, max_u,
+                      t_history.as_mut_slice(), duration.as_mut_slice(), log_probs.as_mut_slice());
+    assert_eq!(log_probs, vec![f32::NEG_INFINITY; 2]);
+    assert_eq!(t_history, vec![-1; 4]);
+    assert_eq!(duration, vec![0; 4]);
+}
@@ -1,6 +1,10 @@
 extern crate ssnt_tts;
 
 use ssnt_tts::{SsntTts, SsntTtsCpu, BeamSearchDecodingTable, util};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::merge::MergeMode;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
 
 
 fn log(input: &Vec<Vec<f32>>) -> Vec<Vec<f32>> {
@@ -18,7 +22,7 @@ fn beam_search_decode_test() {
     let beam_width = 3;
     let max_beam_width = 3;
     let is_finished = vec![false, false, false];
-    let ssnt_tts_cpu = SsntTtsCpu::new(batch_size, T, U);
+    let ssnt_tts_cpu = SsntTtsCpu::new(batch_size, T, U, ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
 
     let log_prob_history: Vec<f32> = vec![0.0, 0.0, 0.0];
 
@@ -50,6 +54,62 @@ fn beam_search_decode_test() {
     println!("{:?}", result2);
 }
 
+#[test]
+fn decode_test() {
+    let T: usize = 3;
+    let max_u: usize = 10;
+    let beam_width = 2;
+    let ssnt_tts_cpu = SsntTtsCpu::new(1, T, max_u, ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+
+    // Shift is likely at every step, so the best path moves through the input one frame at a time and finishes by Emit.
+    let mut model = |_beam_branch: &[usize], t: &[usize], _u: usize| -> Vec<f32> {
+        log(&t.iter().map(|t| if *t == T - 1 { vec![0.9, 0.1] } else { vec![0.3, 0.7] }).collect()).into_iter().flatten().collect()
+    };
+    let hypotheses = ssnt_tts_cpu.decode(&mut model, beam_width, None);
+    println!("{:?}", hypotheses);
+
+    assert_eq!(hypotheses.len(), beam_width as usize);
+    let best = &hypotheses[0];
+    assert!(best.is_finished);
+    assert_eq!(best.t_history, vec![0, 1, 2]);
+    assert_eq!(best.prediction, vec![1, 1, 0]);
+    // Shift at the last input position is turned into a finishing Emit without its cost.
+    assert!((best.log_prob - 0.7f32.ln() * 2.0).abs() < 1e-5);
+    assert!(hypotheses[1].log_prob <= best.log_prob);
+}
+
+#[test]
+fn merge_test() {
+    let T: usize = 4;
+    let beam_width = 2;
+    let max_beam_width = 4;
+    let is_finished = vec![false, false];
+    let log_prob_history: Vec<f32> = vec![0.5f32.ln(), 0.5f32.ln()];
+    let input: Vec<f32> = log(&vec![  // beam
+                                      vec![0.6, 0.4],  // t = 0
+                                      vec![0.5, 0.5],  // t = 1
+    ]).into_iter().flatten().collect();
+    let start_t: Vec<usize> = vec![0, 1];
+    let u = vec![1, 1];
+    let table = BeamSearchDecodingTable::new(input.as_slice(), log_prob_history.as_slice(), is_finished.as_slice(), T, beam_width, max_beam_width);
+    let log_probs = |merge_mode: MergeMode| -> Vec<f32> {
+        let ssnt_tts_cpu = SsntTtsCpu::new(1, T, 0, ScoringPolicy::default(), merge_mode, DiversityPolicy::default(), PruningPolicy::default());
+        ssnt_tts_cpu.beam_search_kernel(&table, start_t.as_slice(), u.as_slice()).iter().map(|r| r.log_prob.exp()).collect()
+    };
+
+    // Shift from t = 0 and Emit at t = 1 both reach t = 1.
+    let assert_close = |actual: &[f32], expected: &[f32]| {
+        assert_eq!(actual.len(), expected.len());
+        actual.iter().zip(expected.iter()).for_each(|(a, e)| assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected));
+    };
+    assert_close(&log_probs(MergeMode::NoMerge), &[0.3, 0.25, 0.25, 0.2]);
+    assert_close(&log_probs(MergeMode::Max)[..3], &[0.3, 0.25, 0.25]);
+    assert_close(&log_probs(MergeMode::LogSumExp)[..3], &[0.45, 0.3, 0.25]);
+
+    assert_eq!(MergeMode::from_id(2), Some(MergeMode::LogSumExp));
+    assert_eq!(MergeMode::from_id(3), None);
+}
+
 #[test]
 fn extract_best_beam_branch_test() {
     let beam_width = 10;
@@ -128,4 +188,29 @@ fn extract_best_beam_branch_test() {
                                       0, 4, 0, 1, 0, 1, 0, 0, 0, 2,
                                       3, 5, 8, 3, 5, 5, 4, 3, 4, 5,
                                       4, 7, 7, 4, 6, 6, 7, 8, 9, 9]);
-}
\ No newline at end of file
+}
+
+#[test]
+fn extract_best_path_test() {
+    let beam_width = 2;
+    let max_u = 4;
+    // Both items share the same (U, W) histories.
+    let beam_branch: Vec<i32> = vec![0, 0, 1, 0, 0, 0, 0, 0].repeat(2);
+    let t_history: Vec<i32> = vec![0, 1, 2, 3, 4, 5, 6, 7].repeat(2);
+    let prediction: Vec<i32> = vec![5, 6, 7, 8, 9, 10, 11, 12].repeat(2);
+    let mut best_beam_branch = vec![0; 8];
+    let mut best_t_history = vec![0; 8];
+    let mut best_prediction = vec![0; 8];
+    // The first item finishes at the second step.
+    util::extract_best_path(&[1, 0], &[1, 3], beam_branch.as_slice(), t_history.as_slice(), prediction.as_slice(), beam_width, max_u, -1,
+                            best_beam_branch.as_mut_slice(), best_t_history.as_mut_slice(), best_prediction.as_mut_slice());
+    assert_eq!(best_beam_branch, vec![0, 1, -1, -1, 1, 0, 0, 0]);
+    assert_eq!(best_t_history, vec![0, 3, -1, -1, 1, 2, 4, 6]);
+    assert_eq!(best_prediction, vec![5, 8, -1, -1, 6, 7, 9, 11]);
+
+    // The full length matches the unbounded backtracking.
+    let (branch, ts, _) = util::extract_best_path_kernel(1, max_u - 1, &beam_branch[..8], &t_history[..8], &prediction[..8], beam_width, max_u, -1);
+    assert_eq!((branch, ts), util::extract_best_beam_branch_kernel(1, &beam_branch[..8], &t_history[..8], beam_width, max_u));
+    let (branch, _, _) = util::extract_best_path_kernel(0, -1, &beam_branch[..8], &t_history[..8], &prediction[..8], beam_width, max_u, 0);
+    assert_eq!(branch, vec![0; 4]);
+}
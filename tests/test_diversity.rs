@@ -0,0 +1,79 @@
+extern crate ssnt_tts;
+
+use ssnt_tts::tone_latent::{ToneLatent, ToneLatentCpu};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+
+
+fn log(input: &Vec<Vec<f32>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().map(|item| item.ln())
+    }).collect()
+}
+
+fn first_step(tone_latent: &ToneLatentCpu) -> (Vec<i32>, Vec<i32>) {
+    let h = log(&vec![  // beam
+                        vec![0.6, 0.3, 0.1],  // 0
+                        vec![0.5, 0.4, 0.1],  // 1
+    ]);
+    let (prediction, beam_branch, _) = step(tone_latent, h.as_slice(), &[0.0, 0.9f32.ln()]);
+    (prediction, beam_branch)
+}
+
+// Runs a single step from the start of the input and returns the predictions, the parent beams and num_valid.
+fn step(tone_latent: &ToneLatentCpu, h: &[f32], log_prob_history: &[f32]) -> (Vec<i32>, Vec<i32>, i32) {
+    let beam_width = log_prob_history.len();
+    let mut prediction = vec![0; beam_width];
+    let mut log_probs = vec![0.0; beam_width];
+    let mut next_t = vec![0; beam_width];
+    let mut next_u = vec![0; beam_width];
+    let mut next_is_finished = vec![false; beam_width];
+    let mut beam_branch = vec![0; beam_width];
+    let mut num_valid = vec![0];
+    tone_latent.beam_search_decode(h, log_prob_history, &vec![false; beam_width], &vec![0; beam_width], &vec![0; beam_width], &[], &[4], None, None, None,
+                                   1, beam_width as i32, beam_width as i32,
+                                   prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(), next_is_finished.as_mut_slice(), beam_branch.as_mut_slice(), &mut [], num_valid.as_mut_slice(), None);
+    (prediction, beam_branch, num_valid[0])
+}
+
+#[test]
+fn diverse_beam_search_test() {
+    // Both beams prefer tone 0 without diversity.
+    let plain = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), None, None);
+    assert_eq!(first_step(&plain), (vec![0, 0], vec![0, 1]));
+
+    // The second group extends its own beam and is pushed away from the tone chosen by the first group.
+    let diverse = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::new(2, 10.0), PruningPolicy::default(), None, None);
+    assert_eq!(first_step(&diverse), (vec![0, 1], vec![0, 1]));
+
+    // Without a penalty, groups only restrict each beam to its own parent.
+    let grouped = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::new(2, 0.0), PruningPolicy::default(), None, None);
+    assert_eq!(first_step(&grouped), (vec![0, 0], vec![0, 1]));
+}
+
+#[test]
+fn diverse_beam_search_from_identical_beams_test() {
+    // Every search starts from identical beams, whose expansions are only duplicates within a group.
+    let h = log(&vec![vec![0.6, 0.3, 0.1]; 4]);
+    let diverse = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::new(2, 10.0), PruningPolicy::default(), None, None);
+    assert_eq!(step(&diverse, &h[..6], &[0.0, 0.0]), (vec![0, 1], vec![0, 1], 2));
+    // The second group of two beams turns to the tone that the first group did not choose.
+    assert_eq!(step(&diverse, h.as_slice(), &[0.0; 4]), (vec![0, 1, 2, 0], vec![0, 0, 2, 2], 4));
+
+    // max_active applies to every group, so a later group is not dropped as a whole.
+    let pruned = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::new(2, 10.0), PruningPolicy::new(-1.0, 1), None, None);
+    let (prediction, beam_branch, num_valid) = step(&pruned, h.as_slice(), &[0.0; 4]);
+    assert_eq!(prediction, vec![0, 0, 1, 1]);
+    assert_eq!(beam_branch, vec![0, 0, 2, 2]);
+    assert_eq!(num_valid, 2);
+}
+
+#[test]
+fn from_attr_test() {
+    assert_eq!(DiversityPolicy::from_attr(2, 4, 0.5), Some(DiversityPolicy::new(2, 0.5)));
+    assert_eq!(DiversityPolicy::from_attr(4, 4, 0.5), Some(DiversityPolicy::new(4, 0.5)));
+    assert_eq!(DiversityPolicy::from_attr(0, 4, 0.5), None);
+    assert_eq!(DiversityPolicy::from_attr(-1, 4, 0.5), None);
+    assert_eq!(DiversityPolicy::from_attr(5, 4, 0.5), None);
+}
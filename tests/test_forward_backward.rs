@@ -0,0 +1,173 @@
+extern crate ssnt_tts;
+
+
+use ssnt_tts::alignment::viterbi_alignment_kernel;
+use ssnt_tts::forward_backward::{forward_backward_kernel, ssnt_loss_grad_kernel, ssnt_loss, alignment_posterior_kernel, alignment_posterior};
+
+
+fn log_transition(input: &Vec<Vec<(f32, f32)>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().flat_map(|(e, s)| vec![e.ln(), s.ln()])
+    }).collect()
+}
+
+fn transition_table() -> Vec<f32> {
+    log_transition(&vec![
+        vec![(0.2, 0.8), (0.5, 0.5), (0.5, 0.5)],
+        vec![(0.6, 0.4), (0.9, 0.1), (0.3, 0.7)],
+        vec![(0.5, 0.5), (0.4, 0.6), (0.2, 0.8)],
+        vec![(0.7, 0.3), (0.5, 0.5), (0.7, 0.3)],
+        vec![(0.5, 0.5), (0.1, 0.9), (0.6, 0.4)],
+    ])
+}
+
+// Sums the probabilities of every monotonic path by enumeration. Emit at the last input position finishes the path.
+fn brute_force_log_likelihood(h: &[f32], input_length: usize, output_length: usize, max_t: usize) -> f32 {
+    let n_paths = 1 << (output_length - 1);
+    let total: f32 = (0..n_paths).filter_map(|shifts: usize| {
+        let mut t = 0;
+        let mut log_prob = 0.0;
+        for u in 0..output_length - 1 {
+            let transition = (shifts >> u) & 1;
+            if t == input_length - 1 && transition == 0 {
+                return None;
+            }
+            log_prob += h[(u * max_t + t) * 2 + transition];
+            t += transition;
+            if t >= input_length {
+                return None;
+            }
+        }
+        if t != input_length - 1 {
+            return None;
+        }
+        Some((log_prob + h[((output_length - 1) * max_t + t) * 2]).exp())
+    }).sum();
+    total.ln()
+}
+
+#[test]
+fn forward_backward_log_likelihood_test() {
+    let h = transition_table();
+    let table = forward_backward_kernel(h.as_slice(), 3, 5, 3);
+    let expected = brute_force_log_likelihood(h.as_slice(), 3, 5, 3);
+    assert!((table.log_likelihood - expected).abs() < 1e-5, "{} != {}", table.log_likelihood, expected);
+    let forward = table.alpha[4 * 3 + 2] + h[(4 * 3 + 2) * 2];
+    assert!((forward - table.log_likelihood).abs() < 1e-5);
+}
+
+#[test]
+fn ssnt_loss_grad_test() {
+    let h = transition_table();
+    let table = forward_backward_kernel(h.as_slice(), 3, 5, 3);
+    let grad = ssnt_loss_grad_kernel(h.as_slice(), &table, 3);
+    let epsilon = 1e-2;
+    h.iter().enumerate().for_each(|(i, _)| {
+        let mut perturbed = h.clone();
+        perturbed[i] += epsilon;
+        let plus = -brute_force_log_likelihood(perturbed.as_slice(), 3, 5, 3);
+        perturbed[i] -= 2.0 * epsilon;
+        let minus = -brute_force_log_likelihood(perturbed.as_slice(), 3, 5, 3);
+        let numerical = (plus - minus) / (2.0 * epsilon);
+        assert!((grad[i] - numerical).abs() < 1e-3, "index {}: {} != {}", i, grad[i], numerical);
+    });
+}
+
+#[test]
+fn ssnt_loss_batched_test() {
+    let batch_size = 2;
+    let max_t = 3;
+    let max_u = 5;
+    let h: Vec<f32> = vec![transition_table(), transition_table()].into_iter().flatten().collect();
+    let input_length = vec![3, 2];
+    let output_length = vec![5, 1];
+    let mut loss = vec![0.0; batch_size];
+    let mut grad = vec![0.0; h.len()];
+    ssnt_loss(h.as_slice(), input_length.as_slice(), output_length.as_slice(), batch_size, max_t, max_u, loss.as_mut_slice(), grad.as_mut_slice());
+    let expected = -brute_force_log_likelihood(h.as_slice(), 3, 5, 3);
+    assert!((loss[0] - expected).abs() < 1e-5);
+    // A single frame cannot cover two input tokens.
+    assert_eq!(loss[1], std::f32::INFINITY);
+    assert!(grad[h.len() / 2..].iter().all(|g| *g == 0.0));
+}
+
+#[test]
+fn alignment_posterior_kernel_test() {
+    let h = transition_table();
+    let table = forward_backward_kernel(h.as_slice(), 3, 5, 3);
+    let (gamma, expected_duration) = alignment_posterior_kernel(&table);
+    // Every frame is aligned to exactly one input position.
+    gamma.chunks(3).for_each(|row| {
+        assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+    });
+    assert!((gamma[0] - 1.0).abs() < 1e-5);
+    assert!((gamma[4 * 3 + 2] - 1.0).abs() < 1e-5);
+    assert!((expected_duration.iter().sum::<f32>() - 5.0).abs() < 1e-4);
+    // Only t = 0 is reachable at the first frame and only t = input_length - 1 at the last one.
+    assert!(gamma[1..3].iter().all(|g| *g == 0.0));
+}
+
+#[test]
+fn alignment_posterior_batched_test() {
+    let batch_size = 2;
+    let max_t = 3;
+    let max_u = 5;
+    let h: Vec<f32> = vec![transition_table(), transition_table()].into_iter().flatten().collect();
+    let input_length = vec![3, 2];
+    let output_length = vec![5, 3];
+    let mut occupancy = vec![-1.0; batch_size * max_u * max_t];
+    let mut expected_duration = vec![-1.0; batch_size * max_t];
+    alignment_posterior(h.as_slice(), input_length.as_slice(), output_length.as_slice(), batch_size, max_t, max_u, occupancy.as_mut_slice(), expected_duration.as_mut_slice());
+    let second = &occupancy[max_u * max_t..];
+    assert!(second[3 * max_t..].iter().all(|v| *v == 0.0));
+    assert!(second.chunks(max_t).all(|row| row[2] == 0.0));
+    assert_eq!(expected_duration[max_t + 2], 0.0);
+    assert!((expected_duration[max_t..].iter().sum::<f32>() - 3.0).abs() < 1e-4);
+}
+
+#[test]
+fn alignment_posterior_viterbi_agreement_test() {
+    // Peaked around the alignment [0, 0, 1, 1, 1, 2].
+    let h = log_transition(&vec![
+        vec![(0.95, 0.05), (0.5, 0.5), (0.5, 0.5)],
+        vec![(0.05, 0.95), (0.5, 0.5), (0.5, 0.5)],
+        vec![(0.5, 0.5), (0.95, 0.05), (0.5, 0.5)],
+        vec![(0.5, 0.5), (0.95, 0.05), (0.5, 0.5)],
+        vec![(0.5, 0.5), (0.05, 0.95), (0.5, 0.5)],
+        vec![(0.5, 0.5), (0.5, 0.5), (0.95, 0.05)],
+    ]);
+    let table = forward_backward_kernel(h.as_slice(), 3, 6, 3);
+    let (gamma, expected_duration) = alignment_posterior_kernel(&table);
+    let mut argmax_duration = vec![0; 3];
+    gamma.chunks(3).for_each(|row| {
+        let t = (0..3).fold(0, |best, t| if row[t] > row[best] { t } else { best });
+        argmax_duration[t] += 1;
+    });
+    let (_, viterbi_duration, _) = viterbi_alignment_kernel(h.as_slice(), 3, 6, 3).unwrap();
+    assert_eq!(argmax_duration, viterbi_duration);
+    assert_eq!(argmax_duration, vec![2, 3, 1]);
+    // The last input position only ever gets the last frame.
+    assert!((expected_duration[2] - 1.0).abs() < 1e-5);
+}
+
+#[test]
+fn invalid_length_test() {
+    let batch_size = 4;
+    let max_t = 3;
+    let max_u = 5;
+    let h: Vec<f32> = (0..batch_size).flat_map(|_| transition_table()).collect();
+    // Empty input, input longer than max_t, empty output and output longer than max_u.
+    let input_length = vec![0, 4, 3, 3];
+    let output_length = vec![5, 5, 0, 6];
+    let mut loss = vec![0.0; batch_size];
+    let mut grad = vec![1.0; h.len()];
+    ssnt_loss(h.as_slice(), input_length.as_slice(), output_length.as_slice(), batch_size, max_t, max_u, loss.as_mut_slice(), grad.as_mut_slice());
+    assert!(loss.iter().all(|l| *l == std::f32::INFINITY));
+    assert!(grad.iter().all(|g| *g == 0.0));
+
+    let mut occupancy = vec![1.0; batch_size * max_u * max_t];
+    let mut expected_duration = vec![1.0; batch_size * max_t];
+    alignment_posterior(h.as_slice(), input_length.as_slice(), output_length.as_slice(), batch_size, max_t, max_u, occupancy.as_mut_slice(), expected_duration.as_mut_slice());
+    assert!(occupancy.iter().all(|v| *v == 0.0));
+    assert!(expected_duration.iter().all(|v| *v == 0.0));
+}
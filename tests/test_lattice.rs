@@ -0,0 +1,112 @@
+extern crate ssnt_tts;
+
+use ssnt_tts::{SsntTts, SsntTtsCpu};
+use ssnt_tts::tone_latent::{ToneLatent, ToneLatentCpu};
+use ssnt_tts::lattice::{Lattice, LatticeNode};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::merge::MergeMode;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+
+
+fn log(input: &Vec<Vec<f32>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().map(|item| item.ln())
+    }).collect()
+}
+
+#[test]
+fn lattice_test() {
+    let node = |prediction: i32, log_prob: f32, is_finished: bool| LatticeNode::new(prediction, log_prob, log_prob, 0, 0, 0, is_finished);
+    let mut lattice = Lattice::new();
+    lattice.push_step(vec![(0, node(0, -1.0, false)), (0, node(1, -2.0, false)), (0, node(2, -3.0, false))], &[0, 1]);
+    // Beam 0 finishes, and beam 1 keeps going.
+    lattice.push_step(vec![(0, node(0, -1.5, true)), (1, node(0, -2.5, false)), (1, node(1, -4.0, true))], &[0, 1]);
+    // The finished beam keeps its node.
+    lattice.push_step(vec![(0, node(0, -1.5, true)), (1, node(0, -3.0, true))], &[0, 1]);
+    assert_eq!(lattice.n_steps(), 3);
+    assert_eq!(lattice.nodes.len(), 7);
+    assert_eq!(lattice.nodes[6].parent, Some(4));
+
+    let paths = lattice.n_best(5);
+    assert_eq!(paths.iter().map(|p| p.prediction.clone()).collect::<Vec<_>>(), vec![vec![0, 0], vec![1, 0, 0], vec![1, 1]]);
+    assert_eq!(paths[1].nodes, vec![1, 4, 6]);
+    assert_eq!(lattice.best_path(), Some(paths[0].clone()));
+
+    // Only the paths within 1.5 of the best one are kept, and parents are renumbered.
+    lattice.prune(1.5);
+    assert_eq!(lattice.nodes.iter().map(|n| n.prediction).collect::<Vec<_>>(), vec![0, 1, 0, 0, 0]);
+    assert_eq!(lattice.nodes.iter().map(|n| n.parent).collect::<Vec<_>>(), vec![None, None, Some(0), Some(1), Some(3)]);
+    assert_eq!(lattice.n_best(5).len(), 2);
+}
+
+#[test]
+fn decode_lattice_test() {
+    let T: usize = 3;
+    let beam_width = 2;
+    let ssnt_tts_cpu = SsntTtsCpu::new(1, T, 10, ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    let mut model = |_beam_branch: &[usize], t: &[usize], _u: usize| -> Vec<f32> {
+        log(&t.iter().map(|t| if *t == T - 1 { vec![0.9, 0.1] } else { vec![0.3, 0.7] }).collect())
+    };
+    let mut lattice = Lattice::new();
+    let hypotheses = ssnt_tts_cpu.decode(&mut model, beam_width, Some(&mut lattice));
+
+    let best = lattice.best_path().unwrap();
+    assert_eq!(best.prediction, hypotheses[0].prediction);
+    assert_eq!(best.t_history, hypotheses[0].t_history);
+    assert_eq!(best.log_prob, hypotheses[0].log_prob);
+
+    // The lattice also holds the hypotheses that fell out of the beam.
+    assert!(lattice.nodes.len() > lattice.n_steps() * beam_width as usize);
+    assert_eq!(lattice.n_best(10).len(), 1);
+
+    // The decoder keeps no lattice of its own, so decoding again records the same graph.
+    let mut again = Lattice::new();
+    ssnt_tts_cpu.decode(&mut model, beam_width, Some(&mut again));
+    assert_eq!(again, lattice);
+
+    lattice.prune(0.0);
+    assert_eq!(lattice.nodes.len(), best.prediction.len());
+    assert_eq!(lattice.best_path().unwrap().prediction, best.prediction);
+}
+
+#[test]
+fn tone_latent_lattice_test() {
+    let beam_width = 2;
+    let tone_latent = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), None, None);
+    let steps = vec![
+        log(&vec![vec![0.6, 0.3, 0.1], vec![0.6, 0.3, 0.1]]),
+        log(&vec![vec![0.5, 0.4, 0.1], vec![0.9, 0.05, 0.05]]),
+        log(&vec![vec![1.0, 1.0, 1.0], vec![1.0, 1.0, 1.0]]),
+    ];
+    let mut log_probs = vec![0.0; beam_width];
+    let mut is_finished = vec![false; beam_width];
+    let mut t = vec![0; beam_width];
+    let mut u = vec![0; beam_width];
+    let mut lattices = vec![Lattice::new()];
+    for h in steps.iter() {
+        let mut prediction = vec![0; beam_width];
+        let mut next_log_probs = vec![0.0; beam_width];
+        let mut next_t = vec![0; beam_width];
+        let mut next_u = vec![0; beam_width];
+        let mut next_is_finished = vec![false; beam_width];
+        let mut beam_branch = vec![0; beam_width];
+        tone_latent.beam_search_decode(h.as_slice(), log_probs.as_slice(), is_finished.as_slice(), t.as_slice(), u.as_slice(), &[], &[2], None, None, None,
+                                       1, beam_width as i32, beam_width as i32,
+                                       prediction.as_mut_slice(), next_log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(), next_is_finished.as_mut_slice(), beam_branch.as_mut_slice(), &mut [], &mut [0], Some(lattices.as_mut_slice()));
+        log_probs = next_log_probs;
+        is_finished = next_is_finished;
+        t = next_t;
+        u = next_u;
+    }
+
+    // Three expansions of the first step and six of the second, which all finish on the last token.
+    // The finished beams keep their nodes in the third step.
+    let lattice = lattices.pop().unwrap();
+    assert_eq!(lattice.n_steps(), 3);
+    assert_eq!(lattice.nodes.len(), 9);
+    let paths = lattice.n_best(3);
+    assert_eq!(paths.iter().map(|p| p.prediction.clone()).collect::<Vec<_>>(), vec![vec![0, 0], vec![1, 0], vec![0, 1]]);
+    assert_eq!(paths[0].t_history, vec![0, 1]);
+    assert!((paths[0].log_prob - 0.3f32.ln()).abs() < 1e-5);
+}
@@ -0,0 +1,92 @@
+extern crate ssnt_tts;
+
+use std::f32;
+use ssnt_tts::n_best::{n_best, n_best_kernel};
+use ssnt_tts::scoring::ScoringPolicy;
+
+
+// Three steps of width 3. Beam 0 finishes at step 1, and beams 1 and 2 end with the same prediction sequence.
+fn histories() -> (Vec<i32>, Vec<i32>, Vec<i32>, Vec<bool>) {
+    let prediction = vec![
+        1, 2, 3,
+        4, 5, 5,
+        0, 6, 6,
+    ];
+    let beam_branch = vec![
+        0, 0, 0,
+        0, 1, 1,
+        0, 1, 2,
+    ];
+    let t_history = vec![
+        0, 0, 0,
+        1, 1, 1,
+        1, 2, 2,
+    ];
+    let is_finished = vec![
+        false, false, false,
+        true, false, false,
+        true, true, true,
+    ];
+    (prediction, beam_branch, t_history, is_finished)
+}
+
+#[test]
+fn n_best_kernel_test() {
+    let (prediction, beam_branch, t_history, is_finished) = histories();
+    let log_probs = vec![-1.0, -2.0, -1.5];
+    let hypotheses = n_best_kernel(&prediction, &beam_branch, &t_history, &is_finished, &log_probs, None, 3, 3, 3, &ScoringPolicy::default());
+    assert_eq!(hypotheses.len(), 2);
+    assert_eq!(hypotheses[0].final_branch, 0);
+    assert_eq!(hypotheses[0].prediction, vec![1, 4]);
+    assert_eq!(hypotheses[0].t_history, vec![0, 1]);
+    assert_eq!(hypotheses[0].log_prob, -1.0);
+    assert_eq!(hypotheses[1].final_branch, 2);
+    assert_eq!(hypotheses[1].prediction, vec![2, 5, 6]);
+    assert_eq!(hypotheses[1].t_history, vec![0, 1, 2]);
+    assert_eq!(hypotheses[1].log_prob, -1.5);
+
+    // The insertion bonus favours the longer hypothesis.
+    let hypotheses = n_best_kernel(&prediction, &beam_branch, &t_history, &is_finished, &log_probs, None, 3, 3, 1, &ScoringPolicy::new(0.0, 1.0, 0.0));
+    assert_eq!(hypotheses.len(), 1);
+    assert_eq!(hypotheses[0].prediction, vec![2, 5, 6]);
+    assert_eq!(hypotheses[0].score, 1.5);
+}
+
+#[test]
+fn batched_n_best_test() {
+    let (prediction, beam_branch, t_history, is_finished) = histories();
+    // Nothing has finished in the second batch item.
+    let prediction: Vec<i32> = prediction.iter().chain(prediction.iter()).cloned().collect();
+    let beam_branch: Vec<i32> = beam_branch.iter().chain(beam_branch.iter()).cloned().collect();
+    let t_history: Vec<i32> = t_history.iter().chain(t_history.iter()).cloned().collect();
+    let is_finished: Vec<bool> = is_finished.iter().cloned().chain(vec![false; 9].into_iter()).collect();
+    let log_probs = vec![-1.0, -2.0, -1.5, -1.0, -2.0, -1.5];
+    let total_duration = vec![3, 4, 5, 3, 4, 5];
+    let n = 2;
+    let mut best_prediction = vec![0; 2 * n * 3];
+    let mut best_t_history = vec![0; 2 * n * 3];
+    let mut best_total_duration = vec![0; 2 * n];
+    let mut best_log_probs = vec![0.0; 2 * n];
+    let mut best_scores = vec![0.0; 2 * n];
+    let mut best_length = vec![0; 2 * n];
+    let mut num_valid = vec![0; 2];
+    n_best(&prediction, &beam_branch, &t_history, &is_finished, &log_probs, Some(&total_duration), 2, 3, 3, n, &ScoringPolicy::default(),
+           &mut best_prediction, &mut best_t_history, &mut best_total_duration, &mut best_log_probs, &mut best_scores, &mut best_length, &mut num_valid);
+    assert_eq!(best_prediction, vec![
+        1, 4, -1,
+        2, 5, 6,
+        -1, -1, -1,
+        -1, -1, -1,
+    ]);
+    assert_eq!(best_t_history, vec![
+        0, 1, -1,
+        0, 1, 2,
+        -1, -1, -1,
+        -1, -1, -1,
+    ]);
+    assert_eq!(best_total_duration, vec![3, 5, 0, 0]);
+    assert_eq!(best_log_probs, vec![-1.0, -1.5, f32::NEG_INFINITY, f32::NEG_INFINITY]);
+    assert_eq!(best_scores, vec![-1.0, -1.5, f32::NEG_INFINITY, f32::NEG_INFINITY]);
+    assert_eq!(best_length, vec![2, 3, 0, 0]);
+    assert_eq!(num_valid, vec![2, 0]);
+}
@@ -0,0 +1,135 @@
+extern crate ssnt_tts;
+
+use std::f32;
+use std::sync::Arc;
+use ssnt_tts::ngram::{NGramModel, LmFusion, ArpaError, BOS_ID};
+use ssnt_tts::tone_latent::{ToneLatent, ToneLatentCpu};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+use ssnt_tts::n_best::NBestHypothesis;
+use ssnt_tts::lattice::{Lattice, LatticeNode};
+use ssnt_tts::rescoring::{rescore_n_best, rescore_lattice};
+
+
+const ARPA: &str = "
+\\data\\
+ngram 1=4
+ngram 2=3
+
+\\1-grams:
+-1.0 <s> -0.5
+-0.5 </s>
+-0.3 0 -0.2
+-0.6 1 -0.1
+
+\\2-grams:
+-0.1 <s> 0
+-0.2 0 1
+-0.4 1 </s>
+
+\\end\\
+";
+
+fn assert_close(actual: f32, expected: f32) {
+    assert!((actual - expected).abs() < 1e-5, "{} != {}", actual, expected);
+}
+
+#[test]
+fn arpa_test() {
+    let lm = NGramModel::from_arpa(ARPA).unwrap();
+    assert_eq!(lm.order(), 2);
+    assert_close(lm.log_prob(&[BOS_ID], 0), -0.1 * f32::consts::LN_10);
+    // Unlisted bigrams back off to the unigram.
+    assert_close(lm.log_prob(&[0], 0), -0.5 * f32::consts::LN_10);
+    // Only the last token of a longer context is used by a bigram model.
+    assert_close(lm.log_prob(&[1, 1, 0], 1), -0.2 * f32::consts::LN_10);
+    assert_eq!(lm.log_prob(&[], 5), f32::NEG_INFINITY);
+    assert_close(lm.sequence_log_prob(&[0, 1]), -0.7 * f32::consts::LN_10);
+
+    match NGramModel::from_arpa("\\data\\\nngram 1=1\n\\1-grams:\n-0.1 a\n") {
+        Err(ArpaError::InvalidLine { line_number, .. }) => assert_eq!(line_number, 4),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(NGramModel::from_arpa("\\1-grams:\n-0.1 0\n").is_err());
+}
+
+#[test]
+fn rescore_n_best_test() {
+    let lm = NGramModel::from_arpa(ARPA).unwrap();
+    let hypothesis = |prediction: Vec<i32>, score: f32| NBestHypothesis {
+        final_branch: 0,
+        prediction,
+        t_history: vec![],
+        total_duration: 0,
+        log_prob: score,
+        score,
+    };
+    let hypotheses = vec![hypothesis(vec![1, 0], -1.0), hypothesis(vec![0, 1], -1.2)];
+    let unchanged = rescore_n_best(hypotheses.clone(), &lm, 0.0);
+    assert_eq!(unchanged, hypotheses);
+
+    // The LM prefers [0, 1] strongly enough to overturn the acoustic order.
+    let rescored = rescore_n_best(hypotheses, &lm, 0.1);
+    assert_eq!(rescored[0].prediction, vec![0, 1]);
+    assert_close(rescored[0].score, -1.2 - 0.07 * f32::consts::LN_10);
+    assert_close(rescored[1].score, -1.0 - 0.22 * f32::consts::LN_10);
+    assert_eq!(rescored[0].log_prob, -1.2);
+}
+
+#[test]
+fn rescore_lattice_test() {
+    let lm = NGramModel::from_arpa(ARPA).unwrap();
+    let node = |prediction: i32, log_prob: f32, is_finished: bool| LatticeNode::new(prediction, log_prob, log_prob, 0, 0, 0, is_finished);
+    let mut lattice = Lattice::new();
+    lattice.push_step(vec![(0, node(1, -0.5, false)), (0, node(0, -0.7, false))], &[0, 1]);
+    lattice.push_step(vec![(0, node(0, -1.0, true)), (1, node(1, -1.2, true))], &[0, 1]);
+    let paths = lattice.n_best(2);
+    assert_eq!(paths[0].prediction, vec![1, 0]);
+
+    // Every path is rescored as a whole sequence.
+    rescore_lattice(&mut lattice, &lm, 0.1);
+    let rescored = lattice.n_best(2);
+    assert_eq!(rescored[0].prediction, vec![0, 1]);
+    rescored.iter().for_each(|path| {
+        let original = paths.iter().find(|p| p.prediction == path.prediction).unwrap();
+        assert_close(path.score, original.score + 0.1 * lm.sequence_log_prob(path.prediction.as_slice()));
+    });
+}
+
+#[test]
+fn shallow_fusion_test() {
+    let lm = Arc::new(NGramModel::from_arpa(ARPA).unwrap());
+    let tone_latent = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), Some(LmFusion::new(lm, 1.0)), None);
+    assert_eq!(tone_latent.lm_context_size(), 1);
+    let w = 2;
+    let h: Vec<f32> = vec![0.4f32.ln(), 0.4f32.ln(), 0.2f32.ln(), 0.4f32.ln(), 0.4f32.ln(), 0.2f32.ln()];
+    let step = |log_prob_history: &[f32], t: &[i32], u: &[i32], lm_context: &[i32]| {
+        let mut prediction = vec![0; w];
+        let mut log_probs = vec![0.0; w];
+        let mut next_t = vec![0; w];
+        let mut next_u = vec![0; w];
+        let mut next_is_finished = vec![false; w];
+        let mut beam_branch = vec![0; w];
+        let mut next_lm_context = vec![0; w];
+        tone_latent.beam_search_decode(h.as_slice(), log_prob_history, &vec![false; w], t, u, lm_context, &[3], None, None, None, 1, w as i32, w as i32,
+                                       prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                       next_is_finished.as_mut_slice(), beam_branch.as_mut_slice(), next_lm_context.as_mut_slice(), &mut [0], None);
+        (prediction, log_probs, next_t, next_u, beam_branch, next_lm_context)
+    };
+
+    // Tones 0 and 1 are equally likely, and the LM breaks the tie. Tone 2 is unknown to the LM.
+    let (prediction, log_probs, next_t, next_u, beam_branch, lm_context) = step(&[0.0, 0.0], &[0, 0], &[0, 0], &[BOS_ID, BOS_ID]);
+    assert_eq!(prediction, vec![0, 1]);
+    assert_eq!(lm_context, vec![0, 1]);
+    assert_close(log_probs[0], 0.4f32.ln() - 0.1 * f32::consts::LN_10);
+    assert_close(log_probs[1], 0.4f32.ln() - 1.1 * f32::consts::LN_10);
+    assert_eq!(beam_branch, vec![0, 0]);
+
+    // The context of each beam selects the bigram.
+    let (prediction, log_probs, _, _, beam_branch, lm_context) = step(log_probs.as_slice(), next_t.as_slice(), next_u.as_slice(), lm_context.as_slice());
+    assert_eq!(prediction, vec![1, 0]);
+    assert_eq!(beam_branch, vec![0, 0]);
+    assert_eq!(lm_context, vec![1, 0]);
+    assert_close(log_probs[0], 2.0 * 0.4f32.ln() - 0.3 * f32::consts::LN_10);
+}
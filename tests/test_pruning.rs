@@ -0,0 +1,56 @@
+extern crate ssnt_tts;
+
+use ssnt_tts::{SsntTts, SsntTtsCpu};
+use ssnt_tts::tone_latent::{ToneLatent, ToneLatentCpu};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::merge::MergeMode;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+
+
+fn log(input: &Vec<Vec<f32>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().map(|item| item.ln())
+    }).collect()
+}
+
+#[test]
+fn beam_threshold_test() {
+    let w = 3;
+    let h = log(&vec![
+        vec![0.7, 0.25, 0.05],
+        vec![0.7, 0.25, 0.05],
+        vec![0.7, 0.25, 0.05],
+    ]);
+    let first_step = |pruning: PruningPolicy| -> (Vec<i32>, i32) {
+        let tone_latent = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), pruning, None, None);
+        let mut prediction = vec![0; w];
+        let mut log_probs = vec![0.0; w];
+        let mut next_t = vec![0; w];
+        let mut next_u = vec![0; w];
+        let mut next_is_finished = vec![false; w];
+        let mut beam_branch = vec![0; w];
+        let mut num_valid = vec![0; 1];
+        tone_latent.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &vec![0; w], &vec![0; w], &[], &[3], None, None, None, 1, w as i32, w as i32,
+                                       prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                       next_is_finished.as_mut_slice(), beam_branch.as_mut_slice(), &mut [], num_valid.as_mut_slice(), None);
+        (prediction, num_valid[0])
+    };
+
+    assert_eq!(first_step(PruningPolicy::default()), (vec![0, 1, 2], 3));
+    // ln(0.7 / 0.05) exceeds the threshold, so the last tone is replaced by padding.
+    assert_eq!(first_step(PruningPolicy::new(2.0, 0)), (vec![0, 1, 0], 2));
+    assert_eq!(first_step(PruningPolicy::new(-1.0, 1)), (vec![0, 0, 0], 1));
+}
+
+#[test]
+fn decode_without_padding_test() {
+    let T: usize = 3;
+    let mut model = |_beam_branch: &[usize], t: &[usize], _u: usize| -> Vec<f32> {
+        log(&t.iter().map(|t| if *t == T - 1 { vec![0.9, 0.1] } else { vec![0.3, 0.7] }).collect())
+    };
+    let ssnt_tts = SsntTtsCpu::new(1, T, 10, ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::new(-1.0, 1));
+    let hypotheses = ssnt_tts.decode(&mut model, 3, None);
+    assert_eq!(hypotheses.len(), 1);
+    assert_eq!(hypotheses[0].t_history, vec![0, 1, 2]);
+}
@@ -0,0 +1,118 @@
+extern crate ssnt_tts;
+
+use std::f32;
+use ssnt_tts::v2::{SsntTtsV2, SsntTtsV2Cpu, DurationConstraints, LengthOptions};
+use ssnt_tts::tone_latent::{ToneLatent, ToneLatentCpu};
+use ssnt_tts::sampling::{SamplingPolicy, beam_rng};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::merge::MergeMode;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+
+
+fn log(input: &Vec<Vec<f32>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().map(|item| item.ln())
+    }).collect()
+}
+
+#[test]
+fn sampling_filter_test() {
+    let log_probs = log(&vec![vec![0.15, 0.5, 0.05, 0.3]]);
+    let kept = |policy: SamplingPolicy| -> Vec<bool> {
+        policy.filter(log_probs.as_slice()).iter().map(|w| *w > f32::NEG_INFINITY).collect()
+    };
+    assert_eq!(kept(SamplingPolicy::default()), vec![true, true, true, true]);
+    assert_eq!(kept(SamplingPolicy::new(1.0, 2, 1.0)), vec![false, true, false, true]);
+    assert_eq!(kept(SamplingPolicy::new(1.0, 0, 0.9)), vec![true, true, false, true]);
+    assert_eq!(kept(SamplingPolicy::new(0.0, 0, 1.0)), vec![false, true, false, false]);
+
+    // Temperature sharpens the distribution.
+    let cold = SamplingPolicy::new(0.5, 0, 1.0).filter(log_probs.as_slice());
+    assert!((cold[1] - cold[3] - 2.0 * (0.5f32 / 0.3).ln()).abs() < 1e-5);
+}
+
+#[test]
+fn tone_latent_sample_decode_test() {
+    let tone_latent = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), None, None);
+    let w = 4;
+    let h = log(&vec![
+        vec![0.2, 0.7, 0.1],
+        vec![0.6, 0.3, 0.1],
+        vec![0.2, 0.7, 0.1],
+        vec![0.6, 0.3, 0.1],
+    ]);
+    let sample = |policy: SamplingPolicy, seed: u64| -> (Vec<i32>, Vec<f32>, Vec<i32>) {
+        let mut prediction = vec![0; w];
+        let mut log_probs = vec![0.0; w];
+        let mut next_t = vec![0; w];
+        let mut next_u = vec![0; w];
+        let mut next_is_finished = vec![false; w];
+        let mut beam_branch = vec![0; w];
+        tone_latent.sample_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &vec![0; w], &vec![0; w], &[], &[3], None, None, None, 1, w as i32, policy, seed,
+                                  prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                  next_is_finished.as_mut_slice(), beam_branch.as_mut_slice(), &mut []);
+        (prediction, log_probs, beam_branch)
+    };
+
+    // Greedy sampling picks the best class of each beam, and beams stay in place.
+    let (prediction, log_probs, beam_branch) = sample(SamplingPolicy::new(0.0, 0, 1.0), 0);
+    assert_eq!(prediction, vec![1, 0, 1, 0]);
+    assert_eq!(beam_branch, vec![0, 1, 2, 3]);
+    assert!((log_probs[0] - 0.7f32.ln()).abs() < 1e-6);
+
+    // The same seed reproduces the same draws.
+    assert_eq!(sample(SamplingPolicy::default(), 7), sample(SamplingPolicy::default(), 7));
+    let draws: Vec<Vec<i32>> = (0..1000).map(|seed| sample(SamplingPolicy::default(), seed).0).collect();
+    assert!(draws.iter().any(|p| *p != draws[0]));
+
+    // Draws across seeds and beams follow the distribution of each beam.
+    let frequency = |w: usize, class: i32| draws.iter().filter(|p| p[w] == class).count() as f32 / draws.len() as f32;
+    assert!((frequency(0, 1) - 0.7).abs() < 0.05);
+    assert!((frequency(2, 1) - 0.7).abs() < 0.05);
+    assert!((frequency(1, 0) - 0.6).abs() < 0.05);
+    assert!((frequency(3, 2) - 0.1).abs() < 0.05);
+}
+
+#[test]
+fn beam_rng_test() {
+    let first = |b: usize, w: usize, u: usize| beam_rng(3, b, w, u).next_u64();
+    // Streams neither collide for large indexes nor for permuted ones.
+    assert_ne!(first(0, 1, 0), first(0, 0, 1 << 20));
+    assert_ne!(first(1, 0, 0), first(0, 0, 1 << 40));
+    assert_ne!(first(1, 2, 0), first(2, 1, 0));
+    assert_ne!(first(0, 1, 2), first(0, 2, 1));
+    assert_eq!(first(1, 2, 3), first(1, 2, 3));
+}
+
+#[test]
+fn v2_sample_decode_test() {
+    let ssnt_tts = SsntTtsV2Cpu::new(1, 4, 0, true, true, DurationConstraints::default(), LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    let w = 4;
+    let h = log(&vec![
+        vec![0.4, 0.3, 0.2, 0.1],
+        vec![0.4, 0.3, 0.2, 0.1],
+        vec![0.4, 0.3, 0.2, 0.1],
+        vec![0.4, 0.3, 0.2, 0.1],
+    ]);
+    // Token 0 is fixed to 2 frames, so every draw has to respect the bounds.
+    let min_duration = vec![2, 1, -1];
+    let max_duration = vec![2, 3, -1];
+    (0..20).for_each(|seed| {
+        let mut prediction = vec![0; w];
+        let mut log_probs = vec![0.0; w];
+        let mut next_t = vec![0; w];
+        let mut next_u = vec![0; w];
+        let mut next_is_finished = vec![false; w];
+        let mut next_total_duration = vec![0; w];
+        let mut beam_branch = vec![0; w];
+        ssnt_tts.sample_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &vec![0; w], &[0, 1, 2, 3],
+                               &vec![0; w], &vec![0; w], &[3], &[0], &[1.0], Some(min_duration.as_slice()), Some(max_duration.as_slice()), None, None, 1, w as i32,
+                               SamplingPolicy::default(), seed,
+                               prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                               next_is_finished.as_mut_slice(), next_total_duration.as_mut_slice(), beam_branch.as_mut_slice());
+        assert_eq!(prediction, vec![2; w]);
+        assert_eq!(next_total_duration, vec![2; w]);
+        assert!(log_probs.iter().all(|p| (p - 0.2f32.ln()).abs() < 1e-6));
+    });
+}
@@ -0,0 +1,49 @@
+extern crate ssnt_tts;
+
+
+use ssnt_tts::{SsntTts, SsntTtsCpu};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::merge::MergeMode;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+
+
+#[test]
+fn scoring_policy_test() {
+    let policy = ScoringPolicy::default();
+    assert_eq!(policy.score(-3.0, 10, 0.5), -3.0);
+
+    let normalized = ScoringPolicy::new(1.0, 0.0, 0.0);
+    assert_eq!(normalized.length_penalty(1), 1.0);
+    assert!((normalized.score(-3.0, 7, 1.0) - -1.5).abs() < 1e-6);
+
+    let bonus = ScoringPolicy::new(0.0, 0.5, 0.0);
+    assert_eq!(bonus.score(-3.0, 4, 1.0), -1.0);
+
+    let coverage = ScoringPolicy::new(0.0, 0.0, 1.0);
+    assert_eq!(coverage.score(-3.0, 4, 1.0), -3.0);
+    assert!((coverage.score(-3.0, 4, 0.5) - (-3.0 + 0.5f32.ln())).abs() < 1e-6);
+    // Coverage above 1 is not rewarded.
+    assert_eq!(coverage.score(-3.0, 4, 2.0), -3.0);
+}
+
+#[test]
+fn decode_with_insertion_bonus_test() {
+    let T: usize = 2;
+    let max_u: usize = 6;
+    let beam_width = 4;
+    // Emit is likely at the first input position, so longer outputs cost little per step.
+    let mut model = |_beam_branch: &[usize], t: &[usize], _u: usize| -> Vec<f32> {
+        t.iter().flat_map(|t| if *t == 0 { vec![0.9f32.ln(), 0.1f32.ln()] } else { vec![0.5f32.ln(), 0.5f32.ln()] }).collect()
+    };
+
+    let plain = SsntTtsCpu::new(1, T, max_u, ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    let shortest = plain.decode(&mut model, beam_width, None);
+    let rewarded = SsntTtsCpu::new(1, T, max_u, ScoringPolicy::new(0.0, 1.0, 0.0), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    let longest = rewarded.decode(&mut model, beam_width, None);
+
+    assert_eq!(shortest[0].prediction, vec![1, 0]);
+    assert!(longest[0].is_finished);
+    assert!(longest[0].prediction.len() > shortest[0].prediction.len());
+    assert!(longest[0].log_prob < shortest[0].log_prob);
+}
@@ -0,0 +1,164 @@
+extern crate ssnt_tts;
+
+use ssnt_tts::tone_duration::{ToneDuration, ToneDurationCpu, ToneDurationOptions};
+use ssnt_tts::v2::DurationConstraints;
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+
+
+fn log(input: &Vec<Vec<f32>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().map(|item| item.ln())
+    }).collect()
+}
+
+// 2 tone classes, of which 1 is empty, and durations of 0, 1 and 2 frames.
+const OPTIONS: ToneDurationOptions = ToneDurationOptions {
+    tone_class_size: 2,
+    empty_tone_id: 1,
+    duration_class_size: 3,
+    zero_duration_id: 0,
+    allow_skip: false,
+    test_mode: false,
+    rate_bias: 0.0,
+};
+
+struct Step {
+    tone: Vec<i32>,
+    duration: Vec<i32>,
+    log_probs: Vec<f32>,
+    next_t: Vec<i32>,
+    is_finished: Vec<bool>,
+    total_duration: Vec<i32>,
+    num_valid: i32,
+}
+
+// Runs a single step with OPTIONS.
+fn step(h: &[f32], t: i32, total_duration: i32, input_length: i32, output_length: i32, tonal: Option<&[bool]>) -> Step {
+    let constraints = DurationConstraints {
+        use_band: false,
+        use_overrun: false,
+        use_diagonal: false,
+        ..DurationConstraints::default()
+    };
+    let decoder = ToneDurationCpu::new(1, OPTIONS, constraints, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default());
+    step_with(&decoder, 2, h, t, total_duration, input_length, output_length, tonal)
+}
+
+fn step_with(decoder: &ToneDurationCpu, w: usize, h: &[f32], t: i32, total_duration: i32, input_length: i32, output_length: i32, tonal: Option<&[bool]>) -> Step {
+    let mut num_valid = vec![0];
+    let mut tone = vec![0; w];
+    let mut duration = vec![0; w];
+    let mut log_probs = vec![0.0; w];
+    let mut next_t = vec![0; w];
+    let mut next_u = vec![0; w];
+    let mut is_finished = vec![false; w];
+    let mut next_total_duration = vec![0; w];
+    let mut beam_branch = vec![0; w];
+    decoder.beam_search_decode(h, &vec![0.0; w], &vec![false; w], &vec![total_duration; w], &[0, 1, 2], &vec![t; w], &vec![t; w],
+                               &[input_length], &[output_length], &[1.0], None, None, None, tonal, 1, w as i32, w as i32,
+                               tone.as_mut_slice(), duration.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                               is_finished.as_mut_slice(), next_total_duration.as_mut_slice(), beam_branch.as_mut_slice(), num_valid.as_mut_slice());
+    Step {
+        tone,
+        duration,
+        log_probs,
+        next_t,
+        is_finished,
+        total_duration: next_total_duration,
+        num_valid: num_valid[0],
+    }
+}
+
+#[test]
+fn joint_decode_test() {
+    // (W, C, D) joint log-probs. Tone 0 is far more likely on its own, but only with a duration of 1 frame.
+    let row = vec![vec![0.05, 0.6, 0.02], vec![0.03, 0.2, 0.1]];
+    let h = log(&vec![row[0].clone(), row[1].clone(), row[0].clone(), row[1].clone()]);
+
+    // The first of two tokens, where every nonzero duration is allowed.
+    let first = step(h.as_slice(), 0, 0, 2, 3, None);
+    assert_eq!(first.tone, vec![0, 1]);
+    assert_eq!(first.duration, vec![1, 1]);
+    assert_eq!(first.next_t, vec![1, 1]);
+    assert_eq!(first.total_duration, vec![1, 1]);
+    assert_eq!(first.is_finished, vec![false, false]);
+    assert!((first.log_probs[0] - 0.6f32.ln()).abs() < 1e-5);
+
+    // The last token has to take the remaining 2 frames, which favours tone 1.
+    let last = step(h.as_slice(), 1, 1, 2, 3, None);
+    assert_eq!(last.tone, vec![1, 0]);
+    assert_eq!(last.duration, vec![2, 2]);
+    assert_eq!(last.total_duration, vec![3, 3]);
+    assert_eq!(last.is_finished, vec![true, true]);
+
+    // A non-tonal token is forced to the empty tone.
+    let last = step(h.as_slice(), 1, 2, 2, 3, Some(&[true, false]));
+    assert_eq!(last.tone, vec![1, 1]);
+    assert_eq!(last.duration, vec![1, 1]);
+    assert!((last.log_probs[0] - 0.2f32.ln()).abs() < 1e-5);
+}
+
+#[test]
+fn diagonal_before_padding_test() {
+    let row = vec![vec![0.05, 0.6, 0.1], vec![0.03, 0.1, 0.12]];
+    let h = log(&vec![row[0].clone(), row[1].clone(), row[0].clone(), row[1].clone(), row[0].clone(), row[1].clone()]);
+    let constraints = DurationConstraints {
+        use_band: false,
+        use_overrun: false,
+        diagonal_lower: 0.0,
+        ..DurationConstraints::default()
+    };
+    // Only the best hypothesis survives pruning, and the diagonal candidate of 2 frames is added as a real one in front of the padding.
+    let decoder = ToneDurationCpu::new(1, OPTIONS, constraints, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::new(-1.0, 1));
+    let first = step_with(&decoder, 3, h.as_slice(), 0, 0, 2, 4, None);
+    assert_eq!(first.tone, vec![0, 1, 0]);
+    assert_eq!(first.duration, vec![1, 2, 1]);
+    assert_eq!(first.num_valid, 2);
+    assert!((first.log_probs[1] - 0.12f32.ln()).abs() < 1e-5);
+}
+
+#[test]
+fn dead_end_test() {
+    let row = vec![vec![0.05, 0.6, 0.02], vec![0.03, 0.2, 0.1]];
+    let h = log(&vec![row[0].clone(), row[1].clone(), row[0].clone(), row[1].clone()]);
+
+    // The last token cannot fill the remaining 4 frames, so every beam ends without a valid hypothesis.
+    let last = step(h.as_slice(), 1, 1, 2, 5, None);
+    assert_eq!(last.num_valid, 0);
+    assert_eq!(last.is_finished, vec![true, true]);
+    assert!(last.log_probs.iter().all(|p| *p == std::f32::NEG_INFINITY));
+}
+
+#[test]
+fn mismatch_dead_end_test() {
+    let row = vec![vec![0.05, 0.6, 0.02], vec![0.03, 0.2, 0.1]];
+    let h = log(&vec![row[0].clone(), row[1].clone(), row[0].clone(), row[1].clone()]);
+    let constraints = DurationConstraints {
+        use_band: false,
+        use_overrun: false,
+        use_diagonal: false,
+        ..DurationConstraints::default()
+    };
+    let decoder = ToneDurationCpu::new(1, OPTIONS, constraints, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default());
+    let w = 2;
+    let mut tone = vec![0; w];
+    let mut log_probs = vec![0.0; w];
+    let mut is_finished = vec![false; w];
+    let mut beam_branch = vec![0; w];
+    let mut num_valid = vec![0];
+    // Only tone 0 is allowed at the last token. Beam 0 still has 4 of 5 frames to fill, which no duration class does,
+    // while beam 1 finishes with 2 frames.
+    let allowed_tones = [true, true, true, false];
+    decoder.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &[1, 3], &[0, 1, 2], &vec![1; w], &vec![1; w],
+                               &[2], &[5], &[1.0], None, None, Some(&allowed_tones), None, 1, w as i32, w as i32,
+                               tone.as_mut_slice(), vec![0; w].as_mut_slice(), log_probs.as_mut_slice(), vec![0; w].as_mut_slice(), vec![0; w].as_mut_slice(),
+                               is_finished.as_mut_slice(), vec![0; w].as_mut_slice(), beam_branch.as_mut_slice(), num_valid.as_mut_slice());
+    assert_eq!(num_valid, vec![1]);
+    assert_eq!(tone[0], 0);
+    assert_eq!(beam_branch, vec![1, 0]);
+    assert_eq!(is_finished, vec![true, true]);
+    assert!((log_probs[0] - 0.02f32.ln()).abs() < 1e-5);
+    assert_eq!(log_probs[1], std::f32::NEG_INFINITY);
+}
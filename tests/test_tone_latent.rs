@@ -0,0 +1,197 @@
+extern crate ssnt_tts;
+
+use ssnt_tts::tone_latent::{ToneLatent, ToneLatentCpu, tone_marginal, tone_viterbi_decode};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+
+
+fn log(input: &Vec<Vec<f32>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().map(|item| item.ln())
+    }).collect()
+}
+
+#[test]
+fn allowed_tones_test() {
+    let tone_latent = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), None, None);
+    let w = 2;
+    let h = log(&vec![vec![0.6, 0.3, 0.1], vec![0.6, 0.3, 0.1]]);
+    // Tone 0 is not allowed at the first position, and nothing is allowed at the second one.
+    let allowed_tones = vec![false, true, true, false, false, false];
+    let step = |t: &[i32], allowed_tones: Option<&[bool]>| {
+        let mut prediction = vec![0; w];
+        let mut log_probs = vec![0.0; w];
+        let mut next_t = vec![0; w];
+        let mut next_u = vec![0; w];
+        let mut next_is_finished = vec![false; w];
+        let mut beam_branch = vec![0; w];
+        tone_latent.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], t, t, &[], &[2], allowed_tones, None, None, 1, w as i32, w as i32,
+                                       prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                       next_is_finished.as_mut_slice(), beam_branch.as_mut_slice(), &mut [], &mut [0], None);
+        prediction
+    };
+
+    assert_eq!(step(&[0, 0], None), vec![0, 1]);
+    assert_eq!(step(&[0, 0], Some(allowed_tones.as_slice())), vec![1, 2]);
+    // A position without any allowed tone falls back to every tone.
+    assert_eq!(step(&[1, 1], Some(allowed_tones.as_slice())), vec![0, 1]);
+}
+
+#[test]
+fn tone_marginal_test() {
+    let max_t = 3;
+    // The second item is one position shorter.
+    let h = log(&vec![
+        vec![0.6, 0.3, 0.1], vec![0.2, 0.5, 0.3], vec![0.5, 0.5, 0.5],
+        vec![0.6, 0.3, 0.1], vec![0.2, 0.5, 0.3], vec![0.5, 0.5, 0.5],
+    ]);
+    let mut log_likelihood = vec![0.0; 2];
+    let mut posterior = vec![0.0; h.len()];
+    tone_marginal(h.as_slice(), &[3, 2], None, None, 2, 2, max_t, 3, log_likelihood.as_mut_slice(), posterior.as_mut_slice());
+    assert!((log_likelihood[0] - 1.5f32.ln()).abs() < 1e-5);
+    assert!(log_likelihood[1].abs() < 1e-5);
+    assert!((posterior[6] - 1.0 / 3.0).abs() < 1e-5);
+    assert_eq!(&posterior[15..], &[0.0, 0.0, 0.0]);
+
+    // Tone 1 is observed at the first position of both items, and tones 0 or 2 at the second position of the second one.
+    let observed_tones = vec![
+        false, true, false, false, false, false, false, false, false,
+        false, true, false, true, false, true, false, false, false,
+    ];
+    tone_marginal(h.as_slice(), &[3, 2], Some(observed_tones.as_slice()), None, 2, 2, max_t, 3, log_likelihood.as_mut_slice(), posterior.as_mut_slice());
+    assert!((log_likelihood[0] - (0.3f32 * 1.5).ln()).abs() < 1e-5);
+    assert!((log_likelihood[1] - (0.3f32 * 0.5).ln()).abs() < 1e-5);
+    assert_eq!(&posterior[..3], &[0.0, 1.0, 0.0]);
+    assert!((posterior[12] - 0.4).abs() < 1e-5);
+    assert_eq!(posterior[13], 0.0);
+    assert!((posterior[14] - 0.6).abs() < 1e-5);
+}
+
+#[test]
+fn transition_test() {
+    let transition = log(&vec![vec![0.1, 0.8, 0.1], vec![0.4, 0.3, 0.3], vec![0.4, 0.3, 0.3]]);
+    let w = 2;
+    let h = log(&vec![vec![0.5, 0.4, 0.1], vec![0.5, 0.4, 0.1]]);
+    let step = |tone_latent: &ToneLatentCpu, previous_tone: Option<&[i32]>| {
+        let mut prediction = vec![0; w];
+        let mut log_probs = vec![0.0; w];
+        let mut next_t = vec![0; w];
+        let mut next_u = vec![0; w];
+        let mut next_is_finished = vec![false; w];
+        let mut beam_branch = vec![0; w];
+        tone_latent.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &[1, 1], &[1, 1], &[], &[2], None, None, previous_tone, 1, w as i32, w as i32,
+                                       prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                       next_is_finished.as_mut_slice(), beam_branch.as_mut_slice(), &mut [], &mut [0], None);
+        (prediction, log_probs)
+    };
+    let plain = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), None, None);
+    assert_eq!(step(&plain, Some(&[0, 0])).0, vec![0, 1]);
+
+    // Repeating tone 0 is unlikely, so tone 1 overtakes it.
+    let markov = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), None, Some(transition.clone()));
+    let (prediction, log_probs) = step(&markov, Some(&[0, 0]));
+    assert_eq!(prediction, vec![1, 0]);
+    assert!((log_probs[0] - 0.32f32.ln()).abs() < 1e-5);
+    // Without a previous tone there is nothing to transition from.
+    assert_eq!(step(&markov, Some(&[-1, -1])).0, vec![0, 1]);
+    assert_eq!(step(&markov, None).0, vec![0, 1]);
+}
+
+#[test]
+fn viterbi_decode_test() {
+    let tone_class_size = 2;
+    let max_t = 3;
+    let transition = log(&vec![vec![0.2, 0.8], vec![0.7, 0.3]]);
+    let h = log(&vec![vec![0.6, 0.4], vec![0.7, 0.3], vec![0.5, 0.5]]);
+    // Every sequence is enumerated for the expected best one.
+    let (expected, expected_log_prob) = (0..1 << max_t).map(|bits: usize| {
+        let tones: Vec<usize> = (0..max_t).map(|t| (bits >> t) & 1).collect();
+        let log_prob: f32 = tones.iter().enumerate().map(|(t, c)| {
+            let transition_log_prob = if t == 0 { 0.0 } else { transition[tones[t - 1] * tone_class_size + c] };
+            h[t * tone_class_size + c] + transition_log_prob
+        }).sum();
+        (tones.iter().map(|c| *c as i32).collect::<Vec<i32>>(), log_prob)
+    }).fold((vec![], f32::NEG_INFINITY), |best, v| if v.1 > best.1 { v } else { best });
+
+    let mut prediction = vec![0; max_t];
+    let mut log_prob = vec![0.0];
+    tone_viterbi_decode(h.as_slice(), &[3], Some(transition.as_slice()), None, None, 2, 1, max_t, tone_class_size, prediction.as_mut_slice(), log_prob.as_mut_slice());
+    assert_eq!(prediction, expected);
+    assert!((log_prob[0] - expected_log_prob).abs() < 1e-5);
+
+    // Positions beyond the input length are padded.
+    tone_viterbi_decode(h.as_slice(), &[2], None, None, None, 2, 1, max_t, tone_class_size, prediction.as_mut_slice(), log_prob.as_mut_slice());
+    assert_eq!(prediction, vec![0, 0, -1]);
+    assert!((log_prob[0] - 0.42f32.ln()).abs() < 1e-5);
+}
+
+#[test]
+fn tonal_test() {
+    let tone_latent = ToneLatentCpu::new(1, 3, 2, ScoringPolicy::default(), DiversityPolicy::default(), PruningPolicy::default(), None, None);
+    let w = 2;
+    // The empty tone is the most likely class everywhere.
+    let h = log(&vec![vec![0.3, 0.2, 0.5], vec![0.3, 0.2, 0.5]]);
+    // The second of three positions is non-tonal, and the first one only allows the empty tone.
+    let tonal = vec![true, false, true];
+    let allowed_tones = vec![false, false, true, true, true, true, true, true, true];
+    let step = |t: &[i32], allowed_tones: Option<&[bool]>, tonal: Option<&[bool]>| {
+        let mut prediction = vec![0; w];
+        let mut log_probs = vec![0.0; w];
+        let mut next_t = vec![0; w];
+        let mut next_u = vec![0; w];
+        let mut next_is_finished = vec![false; w];
+        let mut beam_branch = vec![0; w];
+        tone_latent.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], t, t, &[], &[3], allowed_tones, tonal, None, 1, w as i32, w as i32,
+                                       prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                       next_is_finished.as_mut_slice(), beam_branch.as_mut_slice(), &mut [], &mut [0], None);
+        (prediction, next_is_finished, next_t)
+    };
+
+    assert_eq!(step(&[0, 0], None, None).0, vec![2, 0]);
+    // A tonal position never takes the empty tone, even if the allowed tones say so.
+    assert_eq!(step(&[0, 0], Some(allowed_tones.as_slice()), Some(tonal.as_slice())).0, vec![0, 1]);
+    // A non-tonal position is forced to the empty tone.
+    let (prediction, is_finished, next_t) = step(&[1, 1], None, Some(tonal.as_slice()));
+    assert_eq!(prediction, vec![2, 2]);
+    assert_eq!(is_finished, vec![false, false]);
+    assert_eq!(next_t, vec![2, 2]);
+    // Hypotheses finish with the tone of the last position.
+    let (prediction, is_finished, next_t) = step(&[2, 2], None, Some(tonal.as_slice()));
+    assert_eq!(prediction, vec![0, 1]);
+    assert_eq!(is_finished, vec![true, true]);
+    assert_eq!(next_t, vec![2, 2]);
+}
+
+#[test]
+fn tonal_viterbi_marginal_test() {
+    let max_t = 3;
+    let h = log(&vec![vec![0.3, 0.2, 0.5], vec![0.3, 0.2, 0.5], vec![0.3, 0.2, 0.5]]);
+    // The second position is non-tonal, and the first one only allows the empty tone, which the tonal mask overrides.
+    let tonal = vec![true, false, true];
+    let allowed_tones = vec![false, false, true, true, true, true, true, true, true];
+    let mut prediction = vec![0; max_t];
+    let mut log_prob = vec![0.0];
+    tone_viterbi_decode(h.as_slice(), &[3], None, Some(allowed_tones.as_slice()), Some(tonal.as_slice()), 2, 1, max_t, 3, prediction.as_mut_slice(), log_prob.as_mut_slice());
+    assert_eq!(prediction, vec![0, 2, 0]);
+    assert!((log_prob[0] - (0.3f32 * 0.5 * 0.3).ln()).abs() < 1e-5);
+
+    let mut log_likelihood = vec![0.0];
+    let mut posterior = vec![0.0; h.len()];
+    tone_marginal(h.as_slice(), &[3], Some(allowed_tones.as_slice()), Some(tonal.as_slice()), 2, 1, max_t, 3, log_likelihood.as_mut_slice(), posterior.as_mut_slice());
+    assert!((log_likelihood[0] - (0.5f32 * 0.5 * 0.5).ln()).abs() < 1e-5);
+    assert!((posterior[0] - 0.6).abs() < 1e-5);
+    assert_eq!(posterior[2], 0.0);
+    assert_eq!(&posterior[3..6], &[0.0, 0.0, 1.0]);
+
+    // An empty tone outside of the tone classes has no probability of its own, and no transition leads into or out of it.
+    let h = log(&vec![vec![0.3, 0.7], vec![0.3, 0.7], vec![0.3, 0.7]]);
+    let transition = log(&vec![vec![0.1, 0.9], vec![0.9, 0.1]]);
+    tone_viterbi_decode(h.as_slice(), &[3], Some(transition.as_slice()), None, Some(tonal.as_slice()), 2, 1, max_t, 2, prediction.as_mut_slice(), log_prob.as_mut_slice());
+    assert_eq!(prediction, vec![1, 2, 1]);
+    assert!((log_prob[0] - 0.49f32.ln()).abs() < 1e-5);
+    let mut posterior = vec![0.0; h.len()];
+    tone_marginal(h.as_slice(), &[3], None, Some(tonal.as_slice()), 2, 1, max_t, 2, log_likelihood.as_mut_slice(), posterior.as_mut_slice());
+    assert!(log_likelihood[0].abs() < 1e-5);
+    assert_eq!(&posterior[2..4], &[0.0, 0.0]);
+}
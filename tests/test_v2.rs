@@ -0,0 +1,228 @@
+extern crate ssnt_tts;
+
+
+use ssnt_tts::v2::{SsntTtsV2, SsntTtsV2Cpu, DurationConstraints, LengthOptions, LengthPrior};
+use ssnt_tts::scoring::ScoringPolicy;
+use ssnt_tts::merge::MergeMode;
+use ssnt_tts::diversity::DiversityPolicy;
+use ssnt_tts::pruning::PruningPolicy;
+
+
+fn log(input: &Vec<Vec<f32>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().map(|item| item.ln())
+    }).collect()
+}
+
+// Runs a single step of the v2 beam search from the initial state and returns the predictions.
+fn first_step(ssnt_tts: &SsntTtsV2Cpu, h: &[f32], input_length: i32, output_length: i32, beam_width: i32) -> Vec<i32> {
+    first_step_with_rate(ssnt_tts, h, input_length, output_length, 1.0, beam_width)
+}
+
+fn first_step_with_rate(ssnt_tts: &SsntTtsV2Cpu, h: &[f32], input_length: i32, output_length: i32, speaking_rate: f32, beam_width: i32) -> Vec<i32> {
+    let w = beam_width as usize;
+    let duration_table = vec![0, 1, 2, 3];
+    let mut prediction = vec![0; w];
+    let mut log_probs = vec![0.0; w];
+    let mut next_t = vec![0; w];
+    let mut next_u = vec![0; w];
+    let mut next_is_finished = vec![false; w];
+    let mut next_total_duration = vec![0; w];
+    let mut beam_branch = vec![0; w];
+    ssnt_tts.beam_search_decode(h, &vec![0.0; w], &vec![false; w], &vec![0; w], duration_table.as_slice(),
+                                &vec![0; w], &vec![0; w], &[input_length], &[output_length], &[speaking_rate], None, None, None, None, 1, beam_width, beam_width,
+                                prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                next_is_finished.as_mut_slice(), next_total_duration.as_mut_slice(), beam_branch.as_mut_slice(), &mut [0], None);
+    prediction
+}
+
+#[test]
+fn duration_constraints_test() {
+    let h = log(&vec![
+        vec![0.05, 0.1, 0.15, 0.7],
+        vec![0.05, 0.1, 0.15, 0.7],
+    ]);
+
+    // The diagonal at t = 0 is 2 frames, and the default band only allows 1 or 2 frames.
+    // The last slot is taken by the diagonal candidate.
+    let default = SsntTtsV2Cpu::new(1, 4, 0, false, false, DurationConstraints::default(), LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    assert_eq!(first_step(&default, h.as_slice(), 2, 4, 2), vec![2, 2]);
+    let without_diagonal = SsntTtsV2Cpu::new(1, 4, 0, false, false, DurationConstraints {
+        use_diagonal: false,
+        ..DurationConstraints::default()
+    }, LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    assert_eq!(first_step(&without_diagonal, h.as_slice(), 2, 4, 2), vec![2, 1]);
+
+    let without_band = SsntTtsV2Cpu::new(1, 4, 0, false, false, DurationConstraints {
+        use_band: false,
+        use_diagonal: false,
+        ..DurationConstraints::default()
+    }, LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    assert_eq!(first_step(&without_band, h.as_slice(), 2, 4, 2), vec![3, 2]);
+
+    let absolute_band = SsntTtsV2Cpu::new(1, 4, 0, false, false, DurationConstraints {
+        lower_band_ratio: 0.0,
+        upper_band_ratio: 0.0,
+        lower_band_frames: 0.0,
+        upper_band_frames: 1.0,
+        use_diagonal: false,
+        ..DurationConstraints::default()
+    }, LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    assert_eq!(first_step(&absolute_band, h.as_slice(), 2, 4, 2), vec![3, 2]);
+}
+
+#[test]
+fn diagonal_before_padding_test() {
+    let h = log(&vec![
+        vec![0.05, 0.7, 0.15, 0.1],
+        vec![0.05, 0.7, 0.15, 0.1],
+        vec![0.05, 0.7, 0.15, 0.1],
+    ]);
+    // Only the best hypothesis survives pruning, and the diagonal candidate of 2 frames is added as a real one in front of the padding.
+    let ssnt_tts = SsntTtsV2Cpu::new(1, 4, 0, false, false, DurationConstraints {
+        diagonal_lower: 0.0,
+        ..DurationConstraints::default()
+    }, LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::new(-1.0, 1));
+    let w = 3;
+    let mut prediction = vec![0; w];
+    let mut log_probs = vec![0.0; w];
+    let mut num_valid = vec![0];
+    ssnt_tts.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &vec![0; w], &[0, 1, 2, 3],
+                                &vec![0; w], &vec![0; w], &[2], &[4], &[1.0], None, None, None, None, 1, w as i32, w as i32,
+                                prediction.as_mut_slice(), log_probs.as_mut_slice(), vec![0; w].as_mut_slice(), vec![0; w].as_mut_slice(),
+                                vec![false; w].as_mut_slice(), vec![0; w].as_mut_slice(), vec![0; w].as_mut_slice(), num_valid.as_mut_slice(), None);
+    assert_eq!(prediction, vec![1, 2, 1]);
+    assert_eq!(num_valid, vec![2]);
+    assert!((log_probs[1] - 0.15f32.ln()).abs() < 1e-5);
+}
+
+#[test]
+fn dead_end_test() {
+    let h = log(&vec![
+        vec![0.1, 0.2, 0.6, 0.1],
+        vec![0.1, 0.2, 0.6, 0.1],
+    ]);
+    let ssnt_tts = SsntTtsV2Cpu::new(1, 4, 0, false, false, DurationConstraints::default(), LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    let w = 2;
+    let mut log_probs = vec![0.0; w];
+    let mut is_finished = vec![false; w];
+    let mut num_valid = vec![0];
+    // The only token cannot take all 10 frames with at most 3 per class, so every beam ends without a valid hypothesis.
+    ssnt_tts.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &vec![0; w], &[0, 1, 2, 3],
+                                &vec![0; w], &vec![0; w], &[1], &[10], &[1.0], None, None, None, None, 1, w as i32, w as i32,
+                                vec![0; w].as_mut_slice(), log_probs.as_mut_slice(), vec![0; w].as_mut_slice(), vec![0; w].as_mut_slice(),
+                                is_finished.as_mut_slice(), vec![0; w].as_mut_slice(), vec![0; w].as_mut_slice(), num_valid.as_mut_slice(), None);
+    assert_eq!(num_valid, vec![0]);
+    assert_eq!(is_finished, vec![true, true]);
+    assert!(log_probs.iter().all(|p| *p == std::f32::NEG_INFINITY));
+}
+
+#[test]
+fn speaking_rate_test() {
+    let h = log(&vec![
+        vec![0.1, 0.2, 0.6, 0.1],
+        vec![0.1, 0.2, 0.6, 0.1],
+    ]);
+    let constraints = DurationConstraints {
+        use_diagonal: false,
+        ..DurationConstraints::default()
+    };
+    assert_eq!(SsntTtsV2Cpu::scaled_output_length(8, 1.25), 6);
+    assert_eq!(SsntTtsV2Cpu::scaled_output_length(4, 0.8), 5);
+
+    // Slower speech moves the diagonal at t = 0 from 2 to 3 frames.
+    let ssnt_tts = SsntTtsV2Cpu::new(1, 4, 0, false, false, constraints, LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    assert_eq!(first_step_with_rate(&ssnt_tts, h.as_slice(), 2, 4, 1.0, 2), vec![2, 1]);
+    assert_eq!(first_step_with_rate(&ssnt_tts, h.as_slice(), 2, 4, 0.67, 2), vec![2, 3]);
+
+    // Without length constraints the bias alone prefers shorter classes for faster speech.
+    let biased = SsntTtsV2Cpu::new(1, 4, 0, false, true, constraints, LengthOptions { rate_bias: 10.0, ..LengthOptions::default() }, ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    assert_eq!(first_step_with_rate(&biased, h.as_slice(), 2, 4, 1.0, 2), vec![2, 1]);
+    assert_eq!(first_step_with_rate(&biased, h.as_slice(), 2, 4, 1.3, 2)[0], 1);
+    assert_eq!(first_step_with_rate(&biased, h.as_slice(), 2, 4, 0.8, 2)[0], 3);
+
+    // The bias only ranks the hypotheses, and the log-probs stay those of the model.
+    let w = 2;
+    let mut prediction = vec![0; w];
+    let mut log_probs = vec![0.0; w];
+    biased.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &vec![0; w], &[0, 1, 2, 3],
+                              &vec![0; w], &vec![0; w], &[2], &[4], &[1.3], None, None, None, None, 1, w as i32, w as i32,
+                              prediction.as_mut_slice(), log_probs.as_mut_slice(), &mut vec![0; w], &mut vec![0; w],
+                              &mut vec![false; w], &mut vec![0; w], &mut vec![0; w], &mut [0], None);
+    assert_eq!(prediction[0], 1);
+    assert!((log_probs[0] - 0.2f32.ln()).abs() < 1e-5);
+}
+
+#[test]
+fn token_duration_bounds_test() {
+    let ssnt_tts = SsntTtsV2Cpu::new(1, 4, 0, true, true, DurationConstraints::default(), LengthOptions::default(), ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    let beam_width = 4;
+    let h = log(&vec![
+        vec![0.4, 0.3, 0.2, 0.1],
+        vec![0.4, 0.3, 0.2, 0.1],
+        vec![0.4, 0.3, 0.2, 0.1],
+        vec![0.4, 0.3, 0.2, 0.1],
+    ]);
+    // Token 0 is fixed to 2 frames, and token 1 needs at least 1 frame and at most 3.
+    let min_duration = vec![2, 1, -1];
+    let max_duration = vec![2, 3, -1];
+    let w = beam_width as usize;
+    let mut prediction = vec![0; w];
+    let mut log_probs = vec![0.0; w];
+    let mut next_t = vec![0; w];
+    let mut next_u = vec![0; w];
+    let mut next_is_finished = vec![false; w];
+    let mut next_total_duration = vec![0; w];
+    let mut beam_branch = vec![0; w];
+    ssnt_tts.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &vec![0; w], &[0, 1, 2, 3],
+                                &vec![0; w], &vec![0; w], &[3], &[0], &[1.0], Some(min_duration.as_slice()), Some(max_duration.as_slice()), None, None, 1, beam_width, beam_width,
+                                prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                next_is_finished.as_mut_slice(), next_total_duration.as_mut_slice(), beam_branch.as_mut_slice(), &mut [0], None);
+    assert!(prediction.iter().all(|p| *p == 2));
+
+    let t = next_t.clone();
+    let total_duration = next_total_duration.clone();
+    ssnt_tts.beam_search_decode(h.as_slice(), log_probs.clone().as_slice(), &vec![false; w], total_duration.as_slice(), &[0, 1, 2, 3],
+                                t.as_slice(), &vec![1; w], &[3], &[0], &[1.0], Some(min_duration.as_slice()), Some(max_duration.as_slice()), None, None, 1, beam_width, beam_width,
+                                prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                next_is_finished.as_mut_slice(), next_total_duration.as_mut_slice(), beam_branch.as_mut_slice(), &mut [0], None);
+    assert_eq!(prediction, vec![1, 2, 3, 1]);
+}
+
+#[test]
+fn free_length_test() {
+    let prior = LengthPrior { mean: 5.0, stddev: 1.0, weight: 1.0 };
+    assert!(prior.log_prob(5) > prior.log_prob(4));
+    assert_eq!(prior.log_prob(4), prior.log_prob(6));
+
+    // The last token with a total duration of 2 so far. Without a prior the most likely class wins.
+    let h = log(&vec![
+        vec![0.1, 0.5, 0.1, 0.3],
+        vec![0.1, 0.5, 0.1, 0.3],
+    ]);
+    let w = 2;
+    let decode = |ssnt_tts: &SsntTtsV2Cpu, mean: Option<&[f32]>, stddev: Option<&[f32]>| -> (Vec<i32>, Vec<bool>, Vec<f32>) {
+        let mut prediction = vec![0; w];
+        let mut log_probs = vec![0.0; w];
+        let mut next_t = vec![0; w];
+        let mut next_u = vec![0; w];
+        let mut next_is_finished = vec![false; w];
+        let mut next_total_duration = vec![0; w];
+        let mut beam_branch = vec![0; w];
+        ssnt_tts.beam_search_decode(h.as_slice(), &vec![0.0; w], &vec![false; w], &vec![2; w], &[0, 1, 2, 3],
+                                    &vec![1; w], &vec![1; w], &[2], &[0], &[1.0], None, None, mean, stddev, 1, w as i32, w as i32,
+                                    prediction.as_mut_slice(), log_probs.as_mut_slice(), next_t.as_mut_slice(), next_u.as_mut_slice(),
+                                    next_is_finished.as_mut_slice(), next_total_duration.as_mut_slice(), beam_branch.as_mut_slice(), &mut [0], None);
+        (prediction, next_is_finished, log_probs)
+    };
+    let free = SsntTtsV2Cpu::new(1, 4, 0, false, false, DurationConstraints::default(), LengthOptions { free_length: true, ..LengthOptions::default() }, ScoringPolicy::default(), MergeMode::NoMerge, DiversityPolicy::default(), PruningPolicy::default());
+    let (prediction, is_finished, _) = decode(&free, None, None);
+    assert_eq!(prediction, vec![1, 3]);
+    assert!(is_finished.iter().all(|f| *f));
+
+    // A prior around 5 frames favours 3 more frames.
+    // The prior only ranks the hypotheses, and the log-probs stay those of the model.
+    let (prediction, _, log_probs) = decode(&free, Some(&[5.0]), Some(&[0.5]));
+    assert_eq!(prediction[0], 3);
+    assert!((log_probs[0] - 0.3f32.ln()).abs() < 1e-5);
+}
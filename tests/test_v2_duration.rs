@@ -0,0 +1,121 @@
+extern crate ssnt_tts;
+
+
+use ssnt_tts::v2_duration::{DurationSequence, DurationSequenceCpu, DurationDecodeError};
+use ssnt_tts::util::Rng;
+
+
+fn log(input: &Vec<Vec<f32>>) -> Vec<f32> {
+    input.iter().flat_map(|row| {
+        row.iter().map(|item| item.ln())
+    }).collect()
+}
+
+fn duration_probs() -> Vec<f32> {
+    log(&vec![
+        vec![0.1, 0.6, 0.3],
+        vec![0.2, 0.3, 0.5],
+        vec![0.3, 0.3, 0.4],
+    ])
+}
+
+#[test]
+fn duration_log_likelihood_test() {
+    let duration_table = vec![0, 1, 2];
+    let h = duration_probs();
+    let model = DurationSequenceCpu::new(3, 0, true);
+    let mut log_likelihood = vec![0.0; 2];
+    let h_batch: Vec<f32> = vec![h.clone(), h.clone()].into_iter().flatten().collect();
+    model.log_likelihood(h_batch.as_slice(), duration_table.as_slice(), &[3, 3], &[4, 7], 2, 3, log_likelihood.as_mut_slice());
+
+    // Enumerates every sequence and keeps those with total duration 4.
+    let probs: Vec<f32> = h.iter().map(|v| v.exp()).collect();
+    let mut expected = 0.0;
+    for a in 0..3 {
+        for b in 0..3 {
+            for c in 0..3 {
+                if a + b + c == 4 {
+                    expected += probs[a] * probs[3 + b] * probs[6 + c];
+                }
+            }
+        }
+    }
+    assert!((log_likelihood[0] - expected.ln()).abs() < 1e-5);
+    // Total duration 7 is not reachable with three tokens.
+    assert_eq!(log_likelihood[1], std::f32::NEG_INFINITY);
+}
+
+#[test]
+fn duration_sample_test() {
+    let duration_table = vec![0, 1, 2];
+    let h = duration_probs();
+    let model = DurationSequenceCpu::new(3, 0, false);
+    let alpha = model.forward_kernel(h.as_slice(), duration_table.as_slice(), 3, 4);
+    let mut rng = Rng::new(1234);
+    for _ in 0..100 {
+        let (prediction, log_prob) = model.sample_kernel(h.as_slice(), duration_table.as_slice(), alpha.as_slice(), 3, 4, &mut rng).unwrap();
+        let total: i32 = prediction.iter().map(|i| duration_table[*i as usize]).sum();
+        assert_eq!(total, 4);
+        // zero_duration_id is never drawn without allow_skip.
+        assert!(prediction.iter().all(|i| *i != 0));
+        let expected: f32 = prediction.iter().enumerate().map(|(t, i)| h[t * 3 + *i as usize]).sum();
+        assert!((log_prob - expected).abs() < 1e-5);
+    }
+
+    let mut first = vec![0; 6];
+    let mut second = vec![0; 6];
+    let mut log_probs = vec![0.0; 2];
+    let h_batch: Vec<f32> = vec![h.clone(), h.clone()].into_iter().flatten().collect();
+    model.sample(h_batch.as_slice(), duration_table.as_slice(), &[3, 2], &[4, 4], 2, 3, 42, first.as_mut_slice(), log_probs.as_mut_slice());
+    model.sample(h_batch.as_slice(), duration_table.as_slice(), &[3, 2], &[4, 4], 2, 3, 42, second.as_mut_slice(), log_probs.as_mut_slice());
+    assert_eq!(first, second);
+    assert_eq!(&first[3..], &[2, 2, 0]);
+}
+
+#[test]
+fn duration_sample_seed_test() {
+    let duration_table = vec![0, 1, 2];
+    let h = duration_probs();
+    let model = DurationSequenceCpu::new(3, 0, false);
+    let h_batch: Vec<f32> = vec![h.clone(), h.clone()].into_iter().flatten().collect();
+    let mut log_probs = vec![0.0; 2];
+    let samples: Vec<Vec<i32>> = (0..51).map(|seed| {
+        let mut prediction = vec![0; 6];
+        model.sample(h_batch.as_slice(), duration_table.as_slice(), &[3, 3], &[4, 4], 2, 3, seed, prediction.as_mut_slice(), log_probs.as_mut_slice());
+        prediction
+    }).collect();
+    // The second item of one seed does not follow the first item of the next seed.
+    let n_same = (0..50).filter(|&seed| samples[seed][3..] == samples[seed + 1][..3]).count();
+    assert!(n_same < 40, "{} of 50 samples repeat across seeds", n_same);
+}
+
+#[test]
+fn duration_n_best_test() {
+    let duration_table = vec![0, 1, 2];
+    let h = duration_probs();
+    let model = DurationSequenceCpu::new(3, 0, true);
+
+    let (best, log_prob) = model.best_path_kernel(h.as_slice(), duration_table.as_slice(), 3, 4).unwrap();
+    assert_eq!(best, vec![1, 2, 1]);
+    assert!((log_prob - (0.6f32 * 0.5 * 0.3).ln()).abs() < 1e-5);
+
+    // Every sequence of total duration 4, ordered by probability.
+    let paths = model.n_best_kernel(h.as_slice(), duration_table.as_slice(), 3, 4, 10).unwrap();
+    assert_eq!(paths.len(), 6);
+    assert_eq!(paths[0].0, best);
+    assert!(paths.windows(2).all(|w| w[0].1 >= w[1].1));
+    assert!(paths.iter().all(|(p, _)| p.iter().map(|i| duration_table[*i as usize]).sum::<i32>() == 4));
+
+    assert_eq!(model.best_path_kernel(h.as_slice(), duration_table.as_slice(), 3, 7),
+               Err(DurationDecodeError::NoCompatibleSequence { input_length: 3, output_length: 7 }));
+
+    let mut prediction = vec![0; 2 * 2 * 3];
+    let mut log_probs = vec![0.0; 2 * 2];
+    let mut num_valid = vec![0; 2];
+    let h_batch: Vec<f32> = vec![h.clone(), h.clone()].into_iter().flatten().collect();
+    model.n_best_decode(h_batch.as_slice(), duration_table.as_slice(), &[3, 3], &[4, 7], 2, 3, 2,
+                        prediction.as_mut_slice(), log_probs.as_mut_slice(), num_valid.as_mut_slice());
+    assert_eq!(num_valid, vec![2, 0]);
+    assert_eq!(&prediction[..3], &[1, 2, 1]);
+    assert!(log_probs[2..].iter().all(|v| *v == std::f32::NEG_INFINITY));
+}